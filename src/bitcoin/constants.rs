@@ -3,17 +3,8 @@
 
 use std::time::Duration;
 
-// pub const SUPPLY_ALERTS: &[f64] = &[
-//     19_200_000.0,
-//     19_300_000.0,
-//     19_400_000.0,
-//     19_500_000.0,
-//     19_600_000.0,
-//     19_700_000.0,
-//     19_800_000.0,
-//     19_900_000.0,
-//     20_000_000.0,
-// ];
-
 pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(60);
-pub const BLOCK_HEIGHT_ROUND_MAGNITUDE: u32 = 4; // Notify blocks divisible by 10^4
+pub const MAX_REORG_DEPTH: u64 = 1000; // Block hashes kept to detect reorgs
+pub const SUPPLY_RECONCILE_INTERVAL: u64 = 2016; // Reconcile supply with `gettxoutsetinfo` every 2016 blocks
+pub const TX_OUT_SET_INFO_TIMEOUT: Duration = Duration::from_secs(240);
+pub const SCAN_TX_OUT_SET_TIMEOUT: Duration = Duration::from_secs(600);
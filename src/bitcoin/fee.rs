@@ -0,0 +1,116 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use bitcoin::Amount;
+use nostr_sdk::Result;
+
+use super::constants::DEFAULT_RPC_TIMEOUT;
+use super::rpc::RpcClient;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Enter,
+    Exit,
+}
+
+/// Threshold with a dead band, to avoid alerts flapping around the threshold
+#[derive(Debug, Clone, Copy)]
+pub struct Hysteresis {
+    threshold: f64,
+    margin: f64,
+    below: bool,
+}
+
+impl Hysteresis {
+    /// Enter when the value drops below `threshold`, exit when it rises over `threshold + margin`
+    pub fn below(threshold: f64, margin: f64) -> Self {
+        Self {
+            threshold,
+            margin,
+            below: true,
+        }
+    }
+
+    /// Enter when the value rises over `threshold`, exit when it drops below `threshold - margin`
+    pub fn above(threshold: f64, margin: f64) -> Self {
+        Self {
+            threshold,
+            margin,
+            below: false,
+        }
+    }
+
+    pub fn update(&self, active: bool, value: f64) -> Option<Transition> {
+        match (active, self.below) {
+            (false, true) if value < self.threshold => Some(Transition::Enter),
+            (true, true) if value >= self.threshold + self.margin => Some(Transition::Exit),
+            (false, false) if value > self.threshold => Some(Transition::Enter),
+            (true, false) if value <= self.threshold - self.margin => Some(Transition::Exit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MempoolFees {
+    /// Next block fee estimate (sat/vB)
+    pub next_block: Option<f64>,
+    /// Minimum fee rate to enter the mempool (sat/vB)
+    pub mempool_min_fee: f64,
+    /// Minimum relay fee rate (sat/vB)
+    pub min_relay_fee: f64,
+}
+
+impl MempoolFees {
+    /// The mempool is full and is evicting the lowest fee rate transactions
+    pub fn is_purging(&self) -> bool {
+        self.mempool_min_fee > self.min_relay_fee
+    }
+}
+
+/// Convert a BTC/kvB fee rate to sat/vB
+#[inline]
+pub fn to_sat_per_vb(fee_rate: Amount) -> f64 {
+    fee_rate.to_sat() as f64 / 1000.0
+}
+
+pub async fn mempool_fees(rpc: &RpcClient) -> Result<MempoolFees> {
+    let estimate = rpc.estimate_smart_fee(1, DEFAULT_RPC_TIMEOUT).await?;
+    let mempool_info = rpc.get_mempool_info(DEFAULT_RPC_TIMEOUT).await?;
+
+    Ok(MempoolFees {
+        next_block: estimate.fee_rate.map(to_sat_per_vb),
+        mempool_min_fee: to_sat_per_vb(mempool_info.mempool_min_fee),
+        min_relay_fee: to_sat_per_vb(mempool_info.min_relay_tx_fee),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis_below() {
+        let h = Hysteresis::below(5.0, 1.0);
+        assert_eq!(h.update(false, 6.0), None);
+        assert_eq!(h.update(false, 4.0), Some(Transition::Enter));
+        assert_eq!(h.update(true, 3.0), None);
+        assert_eq!(h.update(true, 5.5), None);
+        assert_eq!(h.update(true, 6.0), Some(Transition::Exit));
+    }
+
+    #[test]
+    fn test_hysteresis_above() {
+        let h = Hysteresis::above(100.0, 20.0);
+        assert_eq!(h.update(false, 90.0), None);
+        assert_eq!(h.update(false, 101.0), Some(Transition::Enter));
+        assert_eq!(h.update(true, 85.0), None);
+        assert_eq!(h.update(true, 80.0), Some(Transition::Exit));
+    }
+
+    #[test]
+    fn test_fee_rate_conversion() {
+        assert_eq!(to_sat_per_vb(Amount::from_sat(1000)), 1.0);
+        assert_eq!(to_sat_per_vb(Amount::from_btc(0.00012).unwrap()), 12.0);
+    }
+}
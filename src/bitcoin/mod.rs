@@ -1,25 +1,35 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::sync::{watch, Notify};
 use tokio::time;
 
 mod constants;
+mod fee;
 mod processor;
 mod rpc;
+mod rule;
+mod supply;
+mod zmq;
 
 use self::constants::DEFAULT_RPC_TIMEOUT;
 use self::processor::Processor;
 pub use self::rpc::RpcClient;
+pub use self::supply::HALVING_INTERVAL;
 use crate::config::Config;
-use crate::db::{BitcoinStore, NotificationStore};
+use crate::db::{BitcoinStore, NotificationStore, SubscriptionStore};
+use crate::primitives::ChainState;
 
 pub async fn run(
     config: Config,
     rpc: RpcClient,
     bitcoin_store: BitcoinStore,
     notification_store: NotificationStore,
+    subscription_store: SubscriptionStore,
+    chain_state: watch::Sender<Option<ChainState>>,
 ) {
     loop {
         let blockchain_info = match rpc.get_blockchain_info(DEFAULT_RPC_TIMEOUT).await {
@@ -40,8 +50,9 @@ pub async fn run(
             }
         };
 
-        if network_info.version < 22_00_00 {
-            tracing::error!("This application requires Bitcoin Core 22.0+");
+        // `gettxspendingprevout`, used by the transaction tracker, was added in 24.0
+        if network_info.version < 24_00_00 {
+            tracing::error!("This application requires Bitcoin Core 24.0+");
             panic!("Bitcoin Core version incompatible");
         }
 
@@ -69,7 +80,21 @@ pub async fn run(
         time::sleep(Duration::from_secs(60)).await;
     }
 
-    Processor::new(config, rpc, bitcoin_store, notification_store)
-        .run()
-        .await;
+    let block_notify: Arc<Notify> = Arc::new(Notify::new());
+
+    if let Some(zmq) = config.bitcoin.zmq.clone() {
+        tokio::spawn(zmq::run(zmq, block_notify.clone()));
+    }
+
+    Processor::new(
+        config,
+        rpc,
+        bitcoin_store,
+        notification_store,
+        subscription_store,
+        block_notify,
+        chain_state,
+    )
+    .run()
+    .await;
 }
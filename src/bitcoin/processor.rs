@@ -1,25 +1,148 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use bitcoin::network::Network;
-use bitcoincore_rpc::json::GetMiningInfoResult;
+use bitcoin::{Amount, Block, BlockHash, OutPoint, Txid};
+use bitcoincore_rpc::json::ScanTxOutRequest;
 use nostr_sdk::Result;
+use tokio::sync::{watch, Notify};
 use tokio::time;
 
-use super::constants::{BLOCK_HEIGHT_ROUND_MAGNITUDE, DEFAULT_RPC_TIMEOUT};
+use super::constants::{
+    DEFAULT_RPC_TIMEOUT, MAX_REORG_DEPTH, SCAN_TX_OUT_SET_TIMEOUT, SUPPLY_RECONCILE_INTERVAL,
+    TX_OUT_SET_INFO_TIMEOUT,
+};
+use super::fee::{self, Hysteresis, MempoolFees, Transition};
 use super::rpc::RpcClient;
+use super::rule::{BlockContext, Registry};
+use super::supply;
+use crate::config::model::{Nostr, Route};
 use crate::config::Config;
-use crate::db::{BitcoinStore, NotificationStore};
-use crate::primitives::Target;
+use crate::db::{
+    BitcoinStore, NotificationStore, Recipient, StateUpdates, SubscriptionStore, TrackedTx,
+    WatchedUtxo,
+};
+use crate::locale::Locale;
+use crate::primitives::{Alert, AlertKind, ChainState, Phrase, Target, Var};
+use crate::template::Templates;
 use crate::util;
 
 pub struct Processor {
     config: Config,
     rpc: RpcClient,
+    rules: Registry,
     bitcoin_store: BitcoinStore,
     notification_store: NotificationStore,
+    subscription_store: SubscriptionStore,
+    block_notify: Arc<Notify>,
+    chain_state: watch::Sender<Option<ChainState>>,
+}
+
+/// Alert of a fee rate crossing a threshold
+fn fee_rate_alert(kind: AlertKind, block_height: u64, fee_rate: f64, threshold: f64) -> Alert {
+    let key: &str = match kind {
+        AlertKind::HighFee => "median_fee_rate",
+        _ => "next_block_fee_rate",
+    };
+    Alert::new(kind, block_height)
+        .value(key, fee_rate)
+        .value("threshold", threshold)
+        .var("fee_rate", Var::Decimal(fee_rate, 1))
+        .var("threshold", Var::Decimal(threshold, 1))
+}
+
+/// Target instance the alerts can be queued for
+#[derive(Clone, Copy)]
+struct Destination<'a> {
+    target: Target,
+    instance: &'a str,
+    enabled: bool,
+    /// Render the HTML template, instead of reusing the plain text one
+    html: bool,
+    locale: Locale,
+}
+
+impl Destination<'_> {
+    /// Destination of a target with a single instance
+    fn single(target: Target, enabled: bool, html: bool, locale: Locale) -> Self {
+        Self {
+            target,
+            instance: target.as_str(),
+            enabled,
+            html,
+            locale,
+        }
+    }
+}
+
+/// All the configured destinations, enabled or not
+fn destinations(config: &Config) -> Vec<Destination<'_>> {
+    let mut destinations: Vec<Destination> = Vec::new();
+
+    for ntfy in config.ntfy.iter() {
+        destinations.push(Destination {
+            target: Target::Ntfy,
+            instance: &ntfy.instance,
+            enabled: ntfy.enabled,
+            html: false,
+            locale: ntfy.locale,
+        });
+    }
+
+    for nostr in config.nostr.iter() {
+        destinations.push(Destination {
+            target: Target::Nostr,
+            instance: &nostr.instance,
+            enabled: nostr.enabled,
+            html: false,
+            locale: nostr.locale,
+        });
+    }
+
+    destinations.extend([
+        Destination::single(
+            Target::Matrix,
+            config.matrix.enabled,
+            true,
+            config.matrix.locale,
+        ),
+        Destination::single(
+            Target::Discord,
+            config.discord.enabled,
+            false,
+            config.discord.locale,
+        ),
+        Destination::single(
+            Target::Slack,
+            config.slack.enabled,
+            false,
+            config.slack.locale,
+        ),
+        Destination::single(
+            Target::Email,
+            config.email.enabled,
+            true,
+            config.email.locale,
+        ),
+        Destination::single(Target::Mqtt, config.mqtt.enabled, false, config.mqtt.locale),
+        Destination::single(
+            Target::Telegram,
+            config.telegram.enabled,
+            true,
+            config.telegram.locale,
+        ),
+        Destination::single(
+            Target::Webhook,
+            config.webhook.enabled,
+            true,
+            config.webhook.locale,
+        ),
+    ]);
+
+    destinations
 }
 
 impl Processor {
@@ -28,18 +151,48 @@ impl Processor {
         rpc: RpcClient,
         bitcoin_store: BitcoinStore,
         notification_store: NotificationStore,
+        subscription_store: SubscriptionStore,
+        block_notify: Arc<Notify>,
+        chain_state: watch::Sender<Option<ChainState>>,
     ) -> Self {
         Self {
+            rules: Registry::from_config(&config),
             config,
             rpc,
             bitcoin_store,
             notification_store,
+            subscription_store,
+            block_notify,
+            chain_state,
+        }
+    }
+
+    /// Wait for a ZMQ block notification, falling back to polling every 60 secs
+    ///
+    /// Return `true` if woken up by a notification.
+    async fn wait_for_new_block(&self) -> bool {
+        match time::timeout(Duration::from_secs(60), self.block_notify.notified()).await {
+            Ok(_) => {
+                tracing::debug!("Woken up by new block notification");
+                true
+            }
+            Err(_) => false,
         }
     }
 
     pub async fn run(&self) {
         tracing::info!("Bitcoin Processor started");
 
+        for txid in self.config.tracker.txids.iter() {
+            if let Err(e) = self.notification_store.track_tx(txid) {
+                tracing::error!("Impossible to track {txid}: {e}");
+            }
+        }
+
+        if let Err(e) = self.seed_watched_utxos().await {
+            tracing::error!("Impossible to scan the UTXO set for the watched outputs: {e}");
+        }
+
         let mut delay = 30; // Delay seconds
 
         loop {
@@ -58,6 +211,13 @@ impl Processor {
             let last_processed_block: u64 = match self.bitcoin_store.get_last_processed_block() {
                 Ok(value) => value,
                 Err(_) => {
+                    if let Ok(hash) = self
+                        .rpc
+                        .get_block_hash(block_height, DEFAULT_RPC_TIMEOUT)
+                        .await
+                    {
+                        let _ = self.bitcoin_store.set_block_hash(block_height, &hash);
+                    }
                     let _ = self.bitcoin_store.set_last_processed_block(block_height);
                     block_height
                 }
@@ -65,16 +225,40 @@ impl Processor {
 
             tracing::debug!("Last processed block is {}", last_processed_block);
 
+            let last_processed_block: u64 =
+                match self.reorg(last_processed_block, block_height).await {
+                    Ok(Some(fork_height)) => {
+                        let _ = self.bitcoin_store.set_last_processed_block(fork_height);
+                        fork_height
+                    }
+                    Ok(None) => last_processed_block,
+                    Err(e) => {
+                        tracing::error!("Check reorg: {e}");
+                        time::sleep(Duration::from_secs(60)).await;
+                        continue;
+                    }
+                };
+
             if block_height <= last_processed_block {
                 tracing::debug!("Wait for new block");
-                time::sleep(Duration::from_secs(60)).await;
+
+                if !self.wait_for_new_block().await {
+                    if let Err(e) = self.mempool_fees().await {
+                        tracing::error!("Check mempool fees: {e}");
+                    }
+
+                    if let Err(e) = self.tracked_txs_mempool().await {
+                        tracing::error!("Check tracked transactions: {e}");
+                    }
+                }
+
                 continue;
             }
 
             let next_block_to_process: u64 = last_processed_block + 1;
             let start = Instant::now();
             match self.process_block(next_block_to_process).await {
-                Ok(_) => {
+                Ok((hash, updates)) => {
                     delay = 30;
 
                     let elapsed_time = start.elapsed().as_millis();
@@ -83,9 +267,17 @@ impl Processor {
                         next_block_to_process,
                         elapsed_time
                     );
-                    let _ = self
-                        .bitcoin_store
-                        .set_last_processed_block(next_block_to_process);
+                    if let Err(e) = self.bitcoin_store.set_processed_block(
+                        next_block_to_process,
+                        &hash,
+                        &updates,
+                    ) {
+                        tracing::error!("Save processed block: {e}");
+                    }
+
+                    if let Some(height) = next_block_to_process.checked_sub(MAX_REORG_DEPTH) {
+                        let _ = self.bitcoin_store.delete_block_hash(height);
+                    }
                 }
                 Err(e) => {
                     if delay > 3600 {
@@ -103,221 +295,821 @@ impl Processor {
         }
     }
 
-    async fn process_block(&self, block_height: u64) -> Result<()> {
+    /// Compare the stored block hashes with the node's active chain.
+    ///
+    /// Return the height of the fork point if processed blocks were reorganized out.
+    async fn reorg(&self, last_processed_block: u64, block_height: u64) -> Result<Option<u64>> {
+        let old_tip: BlockHash = match self.bitcoin_store.get_block_hash(last_processed_block) {
+            Ok(hash) => hash,
+            Err(_) => return Ok(None),
+        };
+
+        let mut fork_height: u64 = last_processed_block;
+
+        while let Ok(stored_hash) = self.bitcoin_store.get_block_hash(fork_height) {
+            if fork_height <= block_height {
+                let hash: BlockHash = self
+                    .rpc
+                    .get_block_hash(fork_height, DEFAULT_RPC_TIMEOUT)
+                    .await?;
+
+                if hash == stored_hash {
+                    break;
+                }
+            }
+
+            if fork_height == 0 || last_processed_block - fork_height >= MAX_REORG_DEPTH {
+                break;
+            }
+
+            fork_height -= 1;
+        }
+
+        if fork_height == last_processed_block {
+            return Ok(None);
+        }
+
+        let depth: u64 = last_processed_block - fork_height;
+        let new_tip: BlockHash = self
+            .rpc
+            .get_block_hash(block_height, DEFAULT_RPC_TIMEOUT)
+            .await?;
+
+        tracing::warn!(
+            "Reorg of depth {depth} detected: old tip {old_tip} ({last_processed_block}), new tip {new_tip} ({block_height})"
+        );
+
+        self.queue_notification(
+            Alert::new(AlertKind::Reorg, block_height)
+                .value("depth", depth)
+                .value("old_tip", old_tip.to_string())
+                .value("old_height", last_processed_block)
+                .value("new_tip", new_tip.to_string())
+                .value("new_height", block_height)
+                .var("depth", depth)
+                .var("old_tip", old_tip.to_string())
+                .var("old_height", last_processed_block)
+                .var("new_tip", new_tip.to_string())
+                .var("new_height", block_height),
+        )?;
+
+        // Rewind processing state to the fork point
+        for height in (fork_height + 1)..=last_processed_block {
+            self.bitcoin_store.delete_block_hash(height)?;
+        }
+        self.bitcoin_store.rewind_watched_utxos(fork_height)?;
+
+        for (txid, mut tx) in self.notification_store.get_tracked_txs()?.into_iter() {
+            if tx.block_height.is_some_and(|height| height > fork_height) {
+                tx.block_height = None;
+                tx.confirmations = 0;
+                tx.done = false;
+                self.notification_store.set_tracked_tx(&txid, &tx)?;
+            }
+        }
+
+        Ok(Some(fork_height))
+    }
+
+    /// Process a block, returning its hash and the rule state to save along with it
+    async fn process_block(&self, block_height: u64) -> Result<(BlockHash, StateUpdates)> {
+        let hash: BlockHash = self
+            .rpc
+            .get_block_hash(block_height, DEFAULT_RPC_TIMEOUT)
+            .await?;
         let mining_info = self.rpc.get_mining_info(DEFAULT_RPC_TIMEOUT).await?;
 
-        self.halving(block_height)?;
-        self.difficulty_adjustment(block_height, &mining_info)?;
-        //self.supply(block_height).await?;
-        self.hashrate(&mining_info)?;
-        self.block(block_height)?;
-        Ok(())
+        let tracked_txs: HashMap<Txid, TrackedTx> = self
+            .notification_store
+            .get_tracked_txs()?
+            .into_iter()
+            .filter(|(_, tx)| !tx.done)
+            .collect();
+
+        let block: Option<Block> = if !self.config.watch.is_empty() || !tracked_txs.is_empty() {
+            Some(self.rpc.get_block(hash, DEFAULT_RPC_TIMEOUT).await?)
+        } else {
+            None
+        };
+
+        let ctx: BlockContext = BlockContext {
+            block_height,
+            mining_info: &mining_info,
+        };
+
+        let mut updates: StateUpdates = StateUpdates::default();
+        for alert in self
+            .rules
+            .check(&ctx, &self.bitcoin_store, &mut updates)?
+            .into_iter()
+        {
+            self.queue_notification(alert)?;
+        }
+
+        self.supply(block_height).await?;
+        self.block_fees(block_height).await?;
+
+        if let Some(block) = &block {
+            self.watch(block_height, block).await?;
+            self.tracked_txs(block_height, block, tracked_txs)?;
+        }
+
+        // Fee estimates are updated by the mempool checks
+        let (next_block_fee_rate, mempool_min_fee) = self
+            .chain_state
+            .borrow()
+            .map(|state| (state.next_block_fee_rate, state.mempool_min_fee))
+            .unwrap_or_default();
+
+        self.chain_state.send_replace(Some(ChainState {
+            block_height,
+            difficulty: mining_info.difficulty,
+            hashrate: mining_info.network_hash_ps,
+            blocks_to_halving: supply::HALVING_INTERVAL - block_height % supply::HALVING_INTERVAL,
+            next_block_fee_rate,
+            mempool_min_fee,
+        }));
+
+        Ok((hash, updates))
     }
 
-    fn halving(&self, block_height: u64) -> Result<()> {
-        if block_height % 210_000 == 0 {
-            let halving: u64 = block_height / 210_000;
-
-            if halving <= 32 {
-                // Calc block reward
-                let block_reward: f64 = 50.0 / f64::powf(2.0, (block_height / 210_000) as f64);
-
-                // Calc epoch
-                let epoch: u64 = halving + 1;
-
-                // Send halving notification
-                let plain_text: String = format!(
-                    "⛏️ The Halving is here! Welcome to the {epoch}{} epoch! ⛏️",
-                    match epoch {
-                        1 | 21 | 31 => "st",
-                        2 | 22 | 32 => "nd",
-                        3 | 23 | 33 => "rd",
-                        _ => "th",
+    async fn supply(&self, block_height: u64) -> Result<()> {
+        if !self.config.supply.enabled {
+            return Ok(());
+        }
+
+        let last_supply_height: Option<u64> = self.bitcoin_store.get_last_supply_height().ok();
+        let last_supply: Option<Amount> = self.bitcoin_store.get_last_supply().ok();
+
+        let (previous, current): (Amount, Amount) = match (last_supply_height, last_supply) {
+            // Block already accounted (retry after a failure)
+            (Some(height), Some(_)) if height == block_height => return Ok(()),
+            (Some(height), Some(last_supply)) if height + 1 == block_height => {
+                let current: Amount = if block_height % SUPPLY_RECONCILE_INTERVAL == 0 {
+                    match self.reconcile_supply(block_height).await? {
+                        Some(supply) => supply,
+                        None => last_supply + supply::block_subsidy(block_height),
                     }
-                );
-                self.queue_notification(plain_text)?;
+                } else {
+                    last_supply + supply::block_subsidy(block_height)
+                };
+                (last_supply, current)
+            }
+            // First run or processing state rewound: start a new tally
+            (_, last_supply) => {
+                let current: Amount = match self.reconcile_supply(block_height).await? {
+                    Some(supply) => supply,
+                    None => supply::scheduled_supply(block_height),
+                };
+                (last_supply.unwrap_or(current), current)
+            }
+        };
+
+        tracing::debug!("Total supply: {} BTC", current.to_btc());
 
-                // Send block reward notification
-                let plain_text: String = format!("⛏️ New block reward: {block_reward:.2} BTC ⛏️");
-                self.queue_notification(plain_text)?;
-            } else {
-                tracing::warn!("Halving > 32 ({halving})");
+        let last_milestone: Amount = self
+            .bitcoin_store
+            .get_last_supply_milestone()
+            .unwrap_or(Amount::ZERO);
+
+        for milestone in
+            supply::crossed_milestones(&self.config.supply.milestones, previous, current)
+        {
+            if milestone <= last_milestone {
+                continue;
             }
-        } else {
-            let missing_blocks: u64 = (block_height / 210_000 + 1) * 210_000 - block_height;
-
-            if missing_blocks <= 144 * 7 // Less that a week left, notify every block
-                || (missing_blocks <= 4320 && missing_blocks % 6 == 0) // Less than 1 month left, notify every hour
-                || missing_blocks == 4320 // One month left
-                || (missing_blocks <= 8640 && missing_blocks % 144 == 0) // Less than 2 months left, notify every day
-                || missing_blocks == 8640 // 2 months left
-                || (missing_blocks <= 51840 && missing_blocks % 432 == 0) // Less than one year left, notify every 3 days
-                || missing_blocks == 51840 // One year left
-                || (missing_blocks <= 103680 && missing_blocks % 1008 == 0) // Less than two years left, notify every week
-                || missing_blocks == 105000 // Two years left
-                || (missing_blocks <= 1555520 && missing_blocks % 2016 == 0) // Less than three years left, notify two weeks
-                || missing_blocks == 1555520 // Three years left
-                || block_height % (6 * 24 * 30 * 3) == 0
-            {
-                let plain_text: String = format!(
-                    "🔥 {} blocks to the next Halving 🔥",
-                    util::format_number(missing_blocks as usize)
+
+            self.queue_notification(
+                Alert::new(AlertKind::Supply, block_height)
+                    .value("milestone", milestone.to_sat())
+                    .value("supply", current.to_sat())
+                    .var("milestone", milestone.to_btc() as u64),
+            )?;
+            self.bitcoin_store.set_last_supply_milestone(milestone)?;
+        }
+
+        self.bitcoin_store.set_last_supply(current)?;
+        self.bitcoin_store.set_last_supply_height(block_height)?;
+
+        Ok(())
+    }
+
+    /// Get the UTXO set supply at `block_height`, if `coinstatsindex` is available
+    async fn reconcile_supply(&self, block_height: u64) -> Result<Option<Amount>> {
+        let index_info = match self.rpc.get_index_info(DEFAULT_RPC_TIMEOUT).await {
+            Ok(index_info) => index_info,
+            Err(e) => {
+                tracing::warn!("Get index info: {e}");
+                return Ok(None);
+            }
+        };
+
+        match index_info.coinstatsindex {
+            Some(index) if index.best_block_height as u64 >= block_height => {
+                let txoutset_info = self
+                    .rpc
+                    .get_tx_out_set_info(block_height, TX_OUT_SET_INFO_TIMEOUT)
+                    .await?;
+
+                tracing::debug!(
+                    "Supply reconciled at block {}: {} BTC",
+                    txoutset_info.height,
+                    txoutset_info.total_amount.to_btc()
                 );
 
-                self.queue_notification(plain_text)?;
+                Ok(Some(txoutset_info.total_amount))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Save the outputs already paying the watches, so that their spending is detected
+    ///
+    /// Every watch is scanned once: again only if its scripts change.
+    async fn seed_watched_utxos(&self) -> Result<()> {
+        for watch in self.config.watch.iter() {
+            let mut scripts: Vec<String> = watch
+                .scripts
+                .iter()
+                .map(|script| script.to_hex_string())
+                .collect();
+            scripts.sort();
+
+            let key: String = util::sha512(format!("{}:{}", watch.label, scripts.join(",")));
+            if self.bitcoin_store.is_watch_seeded(&key) {
+                continue;
+            }
+
+            tracing::info!("Scanning the UTXO set for {}", watch.label);
+
+            let descriptors: Vec<ScanTxOutRequest> = scripts
+                .into_iter()
+                .map(|script| ScanTxOutRequest::Single(format!("raw({script})")))
+                .collect();
+            let res = self
+                .rpc
+                .scan_tx_out_set(descriptors, SCAN_TX_OUT_SET_TIMEOUT)
+                .await?;
+
+            let utxos: Vec<(OutPoint, WatchedUtxo)> = res
+                .unspents
+                .into_iter()
+                .map(|utxo| {
+                    (
+                        OutPoint::new(utxo.txid, utxo.vout),
+                        WatchedUtxo {
+                            label: watch.label.clone(),
+                            value: utxo.amount.to_sat(),
+                            height: utxo.height,
+                            spent_height: None,
+                        },
+                    )
+                })
+                .collect();
+            self.bitcoin_store.seed_watched_utxos(&key, &utxos)?;
+
+            tracing::info!("{} unspent outputs found for {}", utxos.len(), watch.label);
+        }
+
+        Ok(())
+    }
+
+    /// Scan block transactions for outputs paying, or inputs spending, watched scripts
+    async fn watch(&self, block_height: u64, block: &Block) -> Result<()> {
+        if self.config.watch.is_empty() {
+            return Ok(());
+        }
+
+        let tip: u64 = self.rpc.get_block_count(DEFAULT_RPC_TIMEOUT).await?;
+        let confirmations: u64 = tip.saturating_sub(block_height) + 1;
+
+        for tx in block.txdata.iter() {
+            let txid: Txid = tx.compute_txid();
+            let mut received: BTreeMap<&str, Amount> = BTreeMap::new();
+            let mut spent: BTreeMap<String, Amount> = BTreeMap::new();
+
+            if !tx.is_coinbase() {
+                for input in tx.input.iter() {
+                    if let Ok(mut utxo) =
+                        self.bitcoin_store.get_watched_utxo(&input.previous_output)
+                    {
+                        // Already marked as spent by this block if retrying after a failure
+                        if utxo
+                            .spent_height
+                            .is_none_or(|height| height == block_height)
+                        {
+                            *spent.entry(utxo.label.clone()).or_default() +=
+                                Amount::from_sat(utxo.value);
+                            utxo.spent_height = Some(block_height);
+                            self.bitcoin_store
+                                .set_watched_utxo(&input.previous_output, &utxo)?;
+                        }
+                    }
+                }
+            }
+
+            for (vout, output) in tx.output.iter().enumerate() {
+                if let Some(watch) = self
+                    .config
+                    .watch
+                    .iter()
+                    .find(|watch| watch.scripts.contains(&output.script_pubkey))
+                {
+                    *received.entry(&watch.label).or_default() += output.value;
+                    let utxo = WatchedUtxo {
+                        label: watch.label.clone(),
+                        value: output.value.to_sat(),
+                        height: block_height,
+                        spent_height: None,
+                    };
+                    self.bitcoin_store
+                        .set_watched_utxo(&OutPoint::new(txid, vout as u32), &utxo)?;
+                }
+            }
+
+            for (label, amount) in received.into_iter() {
+                self.queue_notification(
+                    Alert::new(AlertKind::WatchReceived, block_height)
+                        .value("label", label)
+                        .value("txid", txid.to_string())
+                        .value("amount", amount.to_sat())
+                        .value("confirmations", confirmations)
+                        .var("label", label)
+                        .var("txid", txid.to_string())
+                        .var("amount", Var::Btc(amount))
+                        .var("confirmations", confirmations)
+                        .var("confirmations_text", Var::Confirmations(confirmations)),
+                )?;
             }
+
+            for (label, amount) in spent.into_iter() {
+                self.queue_notification(
+                    Alert::new(AlertKind::WatchSpent, block_height)
+                        .value("label", label.as_str())
+                        .value("txid", txid.to_string())
+                        .value("amount", amount.to_sat())
+                        .value("confirmations", confirmations)
+                        .var("label", label)
+                        .var("txid", txid.to_string())
+                        .var("amount", Var::Btc(amount))
+                        .var("confirmations", confirmations)
+                        .var("confirmations_text", Var::Confirmations(confirmations)),
+                )?;
+            }
+        }
+
+        if let Some(height) = block_height.checked_sub(MAX_REORG_DEPTH) {
+            self.bitcoin_store.prune_spent_watched_utxos(height)?;
         }
 
         Ok(())
     }
 
-    fn difficulty_adjustment(
+    /// Update the confirmations of the tracked transactions
+    fn tracked_txs(
         &self,
         block_height: u64,
-        mining_info: &GetMiningInfoResult,
+        block: &Block,
+        tracked_txs: HashMap<Txid, TrackedTx>,
     ) -> Result<()> {
-        if block_height % 2016 == 0 {
-            let difficulty: f64 = mining_info.difficulty / u64::pow(10, 12) as f64;
+        let txids: HashSet<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
 
-            let last_difficulty: f64 = match self.bitcoin_store.get_last_difficulty() {
-                Ok(value) => value,
-                Err(_) => {
-                    self.bitcoin_store.set_last_difficculty(difficulty)?;
-                    difficulty
+        for (txid, mut tx) in tracked_txs.into_iter() {
+            if tx.block_height.is_none() && txids.contains(&txid) {
+                tx.block_height = Some(block_height);
+                tx.in_mempool = false;
+            }
+
+            self.confirmations(&txid, &mut tx, block_height)?;
+            self.notification_store.set_tracked_tx(&txid, &tx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Alert the confirmation milestones reached by a tracked transaction at `block_height`
+    fn confirmations(&self, txid: &Txid, tx: &mut TrackedTx, block_height: u64) -> Result<()> {
+        let milestones: &[u32] = &self.config.tracker.confirmations;
+
+        if let Some(height) = tx.block_height {
+            let confirmations: u64 = block_height.saturating_sub(height) + 1;
+
+            for milestone in milestones.iter().copied() {
+                if milestone > tx.confirmations && milestone as u64 <= confirmations {
+                    self.queue_notification(
+                        Alert::new(AlertKind::TxConfirmed, block_height)
+                            .value("txid", txid.to_string())
+                            .value("confirmations", milestone)
+                            .var("txid", txid.to_string())
+                            .var("confirmations", milestone as u64)
+                            .var("confirmations_text", Var::Confirmations(milestone as u64)),
+                    )?;
+                    tx.confirmations = milestone;
                 }
-            };
+            }
+
+            tx.done = milestones
+                .last()
+                .is_none_or(|last| tx.confirmations >= *last);
+        }
+
+        Ok(())
+    }
+
+    /// Check if the unconfirmed tracked transactions dropped out of the mempool or got replaced
+    async fn tracked_txs_mempool(&self) -> Result<()> {
+        let block_height: u64 = self.last_processed_block();
 
-            let change: f64 = (difficulty - last_difficulty) / last_difficulty * 100.0;
+        for (txid, mut tx) in self.notification_store.get_tracked_txs()?.into_iter() {
+            if tx.done || tx.block_height.is_some() {
+                continue;
+            }
+
+            // Already confirmed when the tracking started
+            if !tx.checked {
+                tx.checked = true;
+                tx.block_height = self
+                    .rpc
+                    .get_tx_block_height(txid, DEFAULT_RPC_TIMEOUT)
+                    .await?;
+
+                if tx.block_height.is_some_and(|height| height <= block_height) {
+                    self.confirmations(&txid, &mut tx, block_height)?;
+                }
+
+                self.notification_store.set_tracked_tx(&txid, &tx)?;
+
+                if tx.block_height.is_some() {
+                    continue;
+                }
+            }
+
+            if self
+                .rpc
+                .get_mempool_entry(txid, DEFAULT_RPC_TIMEOUT)
+                .await
+                .is_ok()
+            {
+                if !tx.in_mempool {
+                    let raw = self
+                        .rpc
+                        .get_raw_transaction(txid, DEFAULT_RPC_TIMEOUT)
+                        .await?;
+                    tx.inputs = raw
+                        .input
+                        .iter()
+                        .map(|input| input.previous_output)
+                        .collect();
+                    tx.outputs = raw.output.len() as u32;
+                    tx.in_mempool = true;
+                    self.notification_store.set_tracked_tx(&txid, &tx)?;
+                }
+
+                continue;
+            }
+
+            // Never seen in the mempool
+            if !tx.in_mempool {
+                continue;
+            }
+
+            // Confirmed in a block not processed yet
+            let mut confirmed: bool = false;
+            for vout in 0..tx.outputs {
+                if self
+                    .rpc
+                    .is_unspent(OutPoint::new(txid, vout), DEFAULT_RPC_TIMEOUT)
+                    .await?
+                {
+                    confirmed = true;
+                    break;
+                }
+            }
+
+            if confirmed {
+                continue;
+            }
+
+            // The replacement, if known
+            let mut replaced: Option<Option<Txid>> = None;
+            for input in tx.inputs.iter().copied() {
+                if let Some(spending_txid) = self
+                    .rpc
+                    .get_tx_spending_prevout(input, DEFAULT_RPC_TIMEOUT)
+                    .await?
+                {
+                    replaced = Some(Some(spending_txid));
+                    break;
+                }
+
+                if !self.rpc.is_unspent(input, DEFAULT_RPC_TIMEOUT).await? {
+                    replaced = Some(None);
+                    break;
+                }
+            }
 
-            let plain_text: String =
-                format!("⛏️ Difficulty adj: {difficulty:.2}T ({change:.2}%) ⛏️");
+            let alert: Alert = match replaced {
+                Some(replacement) => {
+                    tx.done = true;
+                    let alert = Alert::new(AlertKind::TxReplaced, block_height);
+                    match replacement {
+                        Some(replacement) => alert
+                            .value("replacement", replacement.to_string())
+                            .var("replacement", replacement.to_string()),
+                        None => alert.var("replacement", Var::Phrase(Phrase::ConflictingTx)),
+                    }
+                }
+                None => Alert::new(AlertKind::TxDropped, block_height),
+            };
+            self.queue_notification(
+                alert
+                    .value("txid", txid.to_string())
+                    .var("txid", txid.to_string()),
+            )?;
 
-            self.queue_notification(plain_text)?;
-            self.bitcoin_store.set_last_difficculty(difficulty)?;
+            tx.in_mempool = false;
+            self.notification_store.set_tracked_tx(&txid, &tx)?;
         }
 
         Ok(())
     }
 
-    // async fn supply(&self, block_height: u64) -> Result<()> {
-    //     let current_halving: u64 = block_height / 210_000;
-    //     let current_reward: f64 = 50.0 / f64::powf(2.0, current_halving as f64);
-    //
-    //     if block_height % 50_000 == 0 || self.bitcoin_store.get_last_supply().is_err() {
-    //         let txoutset_info = self
-    //             .rpc
-    //             .get_tx_out_set_info(Duration::from_secs(240))
-    //             .await?;
-    //         let mut total_supply: f64 = txoutset_info.total_amount.to_btc();
-    //
-    //         tracing::debug!(
-    //             "txoutset_info.height: {} - block_height: {}",
-    //             txoutset_info.height,
-    //             block_height
-    //         );
-    //
-    //         if txoutset_info.height != block_height {
-    //             total_supply -= (txoutset_info.height - block_height) as f64 * current_reward;
-    //         }
-    //
-    //         let _ = self.bitcoin_store.set_last_supply(total_supply);
-    //     } else if let Ok(last_supply) = self.bitcoin_store.get_last_supply() {
-    //         let _ = self
-    //             .bitcoin_store
-    //             .set_last_supply(last_supply + current_reward);
-    //     }
-    //
-    //     if let Ok(last_supply) = self.bitcoin_store.get_last_supply() {
-    //         tracing::debug!("Total supply: {} BTC", last_supply);
-    //
-    //         for supply_alert in SUPPLY_ALERTS.iter() {
-    //             if last_supply >= *supply_alert && last_supply - current_reward < *supply_alert {
-    //                 let plain_text: String = format!(
-    //                     "🎊 The supply has just reached {} BTC 🎊",
-    //                     util::format_number(*supply_alert as usize)
-    //                 );
-    //
-    //                 self.queue_notification(plain_text)?;
-    //             }
-    //         }
-    //     }
-    //
-    //     Ok(())
-    // }
-
-    fn hashrate(&self, mining_info: &GetMiningInfoResult) -> Result<()> {
-        let current_hashrate: f64 = mining_info.network_hash_ps / u64::pow(10, 18) as f64; // Hashrate in EH/s
-
-        let last_hashrate_ath: f64 = match self.bitcoin_store.get_last_hashrate_ath() {
-            Ok(value) => value,
-            Err(_) => {
-                self.bitcoin_store.set_last_hashrate_ath(current_hashrate)?;
-                current_hashrate
+    /// Update the state of a fee alert. Return `true` if the alert must be sent.
+    fn fee_alert(&self, name: &str, hysteresis: Hysteresis, value: f64) -> Result<bool> {
+        let active: bool = self
+            .bitcoin_store
+            .get_fee_alert_active(name)
+            .unwrap_or(false);
+
+        match hysteresis.update(active, value) {
+            Some(Transition::Enter) => {
+                self.bitcoin_store.set_fee_alert_active(name, true)?;
+                Ok(true)
+            }
+            Some(Transition::Exit) => {
+                self.bitcoin_store.set_fee_alert_active(name, false)?;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Instances of nostr sending alerts by direct message
+    fn dm_instances(&self) -> impl Iterator<Item = &Nostr> {
+        self.config
+            .nostr
+            .iter()
+            .filter(|nostr| nostr.enabled && nostr.dm_subscriptions)
+    }
+
+    async fn block_fees(&self, block_height: u64) -> Result<()> {
+        let threshold: Option<f64> = self
+            .config
+            .fees
+            .high_median_fee_rate
+            .filter(|_| self.config.fees.enabled);
+        let subscriptions: bool = self.dm_instances().next().is_some();
+
+        if threshold.is_some() || subscriptions {
+            let stats = self
+                .rpc
+                .get_block_stats(block_height, DEFAULT_RPC_TIMEOUT)
+                .await?;
+            let median: f64 = stats.fee_rate_percentiles.fr_50th.to_sat() as f64;
+
+            if let Some(threshold) = threshold {
+                let margin: f64 = threshold * self.config.fees.hysteresis / 100.0;
+
+                if self.fee_alert(
+                    "high_median_fee",
+                    Hysteresis::above(threshold, margin),
+                    median,
+                )? {
+                    self.queue_notification(fee_rate_alert(
+                        AlertKind::HighFee,
+                        block_height,
+                        median,
+                        threshold,
+                    ))?;
+                }
             }
-        };
 
-        if current_hashrate > last_hashrate_ath {
-            let plain_text: String = format!("🎉  New hashrate ATH: {current_hashrate:.2} EH/s 🎉");
-            self.queue_notification(plain_text)?;
-            self.bitcoin_store.set_last_hashrate_ath(current_hashrate)?;
+            self.subscribed_fees(AlertKind::HighFee, block_height, median)?;
+        }
+
+        self.mempool_fees().await
+    }
+
+    /// Alert by direct message the subscribers whose fee rate threshold has been crossed
+    ///
+    /// Every subscriber has its own threshold, with the hysteresis of the `[fees]` section.
+    fn subscribed_fees(&self, kind: AlertKind, block_height: u64, fee_rate: f64) -> Result<()> {
+        for nostr in self.dm_instances() {
+            for (public_key, subscription) in self
+                .subscription_store
+                .get_subscriptions(&nostr.instance)?
+                .into_iter()
+            {
+                let (threshold, hysteresis): (f64, fn(f64, f64) -> Hysteresis) = match kind {
+                    AlertKind::LowFee => match subscription.fees_below {
+                        Some(threshold) => (threshold, Hysteresis::below),
+                        None => continue,
+                    },
+                    AlertKind::HighFee => match subscription.fees_above {
+                        Some(threshold) => (threshold, Hysteresis::above),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                let margin: f64 = threshold * self.config.fees.hysteresis / 100.0;
+                let name: String = format!("{kind}:{}:{public_key}", nostr.instance);
+
+                if self.fee_alert(&name, hysteresis(threshold, margin), fee_rate)? {
+                    let alert: Alert = fee_rate_alert(kind, block_height, fee_rate, threshold);
+                    self.queue_direct_message(nostr, public_key, subscription.nip04, &alert);
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn block(&self, block_height: u64) -> Result<()> {
-        let is_round: bool = util::is_round_number(block_height, BLOCK_HEIGHT_ROUND_MAGNITUDE);
-        let is_palindrome: bool = util::is_palindrome(block_height);
+    async fn mempool_fees(&self) -> Result<()> {
+        if !self.config.fees.enabled && self.dm_instances().next().is_none() {
+            return Ok(());
+        }
+
+        let fees: MempoolFees = fee::mempool_fees(&self.rpc).await?;
+        let block_height: u64 = self.last_processed_block();
 
-        if is_round || is_palindrome {
-            let plain_text: String = format!(
-                "⛓️ Reached block {} ⛓️",
-                util::format_number(block_height as usize)
-            );
-            self.queue_notification(plain_text)?;
+        tracing::debug!("Mempool fees: {fees:?}");
+
+        self.chain_state.send_if_modified(|state| match state {
+            Some(state) => {
+                let modified: bool = state.next_block_fee_rate != fees.next_block
+                    || state.mempool_min_fee != Some(fees.mempool_min_fee);
+                state.next_block_fee_rate = fees.next_block;
+                state.mempool_min_fee = Some(fees.mempool_min_fee);
+                modified
+            }
+            None => false,
+        });
+
+        if let Some(next_block) = fees.next_block {
+            self.subscribed_fees(AlertKind::LowFee, block_height, next_block)?;
         }
 
-        if self.config.bitcoin.network == Network::Regtest {
-            let plain_text: String = format!(
-                "⛓️ Reached block {} ⛓️",
-                util::format_number(block_height as usize)
-            );
-            self.queue_notification(plain_text)?;
+        if !self.config.fees.enabled {
+            return Ok(());
+        }
+
+        if let (Some(threshold), Some(next_block)) =
+            (self.config.fees.low_fee_rate, fees.next_block)
+        {
+            let margin: f64 = threshold * self.config.fees.hysteresis / 100.0;
+
+            if self.fee_alert("low_fee", Hysteresis::below(threshold, margin), next_block)? {
+                self.queue_notification(fee_rate_alert(
+                    AlertKind::LowFee,
+                    block_height,
+                    next_block,
+                    threshold,
+                ))?;
+            }
+        }
+
+        if self.config.fees.mempool_purging {
+            let active: bool = self
+                .bitcoin_store
+                .get_fee_alert_active("mempool_purging")
+                .unwrap_or(false);
+            let purging: bool = fees.is_purging();
+
+            if purging != active {
+                self.bitcoin_store
+                    .set_fee_alert_active("mempool_purging", purging)?;
+
+                if purging {
+                    self.queue_notification(
+                        Alert::new(AlertKind::MempoolPurging, block_height)
+                            .value("mempool_min_fee", fees.mempool_min_fee)
+                            .var("min_fee", Var::Decimal(fees.mempool_min_fee, 2)),
+                    )?;
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn queue_notification<S>(&self, plain_text: S) -> Result<()>
-    where
-        S: AsRef<str>,
-    {
-        let plain_text: &str = plain_text.as_ref();
+    /// Height used to order the notifications not tied to a block
+    fn last_processed_block(&self) -> u64 {
+        self.bitcoin_store
+            .get_last_processed_block()
+            .unwrap_or_default()
+    }
+
+    /// Queue a notification for the enabled targets, or only for the targets of the
+    /// first matching route.
+    ///
+    /// Notifications are sent in the order they are queued, starting from the alert block height.
+    fn queue_notification(&self, mut alert: Alert) -> Result<()> {
+        let route: Option<&Route> = self
+            .config
+            .routes
+            .iter()
+            .find(|route| route.matches(&alert));
+        alert.priority = route.and_then(|route| route.priority);
 
-        if self.config.ntfy.enabled {
-            self.queue_notification_with_target(Target::Ntfy, plain_text, plain_text)?;
+        for destination in destinations(&self.config).into_iter() {
+            if destination.enabled
+                && route
+                    .is_none_or(|route| route.routes_to(destination.target, destination.instance))
+            {
+                self.queue_notification_with_target(&destination, &alert)?;
+            }
         }
 
-        if self.config.nostr.enabled {
-            self.queue_notification_with_target(Target::Nostr, plain_text, plain_text)?;
+        // Subscriptions by direct message don't depend on the routes
+        for nostr in self.dm_instances() {
+            let subscriptions = match self.subscription_store.get_subscriptions(&nostr.instance) {
+                Ok(subscriptions) => subscriptions,
+                Err(e) => {
+                    tracing::error!("Impossible to get {} subscriptions: {e}", nostr.instance);
+                    continue;
+                }
+            };
+
+            for (public_key, subscription) in subscriptions.into_iter() {
+                if subscription.kinds.contains(&alert.kind) {
+                    self.queue_direct_message(nostr, public_key, subscription.nip04, &alert);
+                }
+            }
         }
 
         Ok(())
     }
 
+    fn queue_direct_message(&self, nostr: &Nostr, public_key: String, nip04: bool, alert: &Alert) {
+        let plain_text: String =
+            self.config
+                .templates
+                .plain_text(Target::Nostr, nostr.locale, alert);
+        let recipient = Recipient { public_key, nip04 };
+
+        match self.notification_store.create_direct_message(
+            &nostr.instance,
+            &recipient,
+            alert,
+            &plain_text,
+        ) {
+            Ok(_) => tracing::info!(
+                "Queued a new direct message for {} ({})",
+                recipient.public_key,
+                nostr.instance
+            ),
+            Err(e) => tracing::error!(
+                "Impossible to queue direct message for {}: {e}",
+                recipient.public_key
+            ),
+        }
+    }
+
     fn queue_notification_with_target(
         &self,
-        target: Target,
-        plain_text: &str,
-        html: &str,
+        destination: &Destination,
+        alert: &Alert,
     ) -> Result<()> {
-        match self
-            .notification_store
-            .create_notification(target, plain_text, html)
-        {
-            Ok(_) => tracing::info!("Queued a new notification for {}", target),
+        let Destination {
+            target,
+            instance,
+            html,
+            locale,
+            ..
+        } = *destination;
+
+        let templates: &Templates = &self.config.templates;
+        let plain_text: String = templates.plain_text(target, locale, alert);
+        let html: String = if html {
+            templates.html(target, locale, alert)
+        } else {
+            plain_text.clone()
+        };
+
+        match self.notification_store.create_notification(
+            target,
+            instance,
+            alert,
+            &plain_text,
+            &html,
+        ) {
+            Ok(_) => tracing::info!("Queued a new notification for {}", instance),
             Err(err) => {
-                tracing::error!("Impossible to queue notification for {}: {:?}", target, err)
+                tracing::error!(
+                    "Impossible to queue notification for {}: {:?}",
+                    instance,
+                    err
+                )
             }
         };
 
@@ -4,8 +4,14 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use bitcoincore_rpc::json::{GetBlockchainInfoResult, GetMiningInfoResult, GetNetworkInfoResult};
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::json::{
+    EstimateSmartFeeResult, GetBlockStatsResult, GetBlockchainInfoResult, GetIndexInfoResult,
+    GetMempoolEntryResult, GetMempoolInfoResult, GetMiningInfoResult, GetNetworkInfoResult,
+    GetTxOutSetInfoResult, HashOrHeight, ScanTxOutRequest, ScanTxOutResult, TxOutSetHashType,
+};
 use bitcoincore_rpc::{Auth, Client, RpcApi};
+use serde_json::{json, Value};
 use tokio::{task, time};
 
 use nostr_sdk::Result;
@@ -59,6 +65,20 @@ impl RpcClient {
             .await??)
     }
 
+    #[inline]
+    pub async fn get_block_hash(&self, height: u64, timeout: Duration) -> Result<BlockHash> {
+        Ok(self
+            .interact(move |client| client.get_block_hash(height), timeout)
+            .await??)
+    }
+
+    #[inline]
+    pub async fn get_block(&self, hash: BlockHash, timeout: Duration) -> Result<Block> {
+        Ok(self
+            .interact(move |client| client.get_block(&hash), timeout)
+            .await??)
+    }
+
     #[inline]
     pub async fn get_mining_info(&self, timeout: Duration) -> Result<GetMiningInfoResult> {
         Ok(self
@@ -66,13 +86,165 @@ impl RpcClient {
             .await??)
     }
 
-    // #[inline]
-    // pub async fn get_tx_out_set_info(&self, timeout: Duration) -> Result<GetTxOutSetInfoResult> {
-    //     Ok(self
-    //         .interact(
-    //             move |client| client.get_tx_out_set_info(None, None, None),
-    //             timeout,
-    //         )
-    //         .await??)
-    // }
+    #[inline]
+    pub async fn get_block_stats(
+        &self,
+        height: u64,
+        timeout: Duration,
+    ) -> Result<GetBlockStatsResult> {
+        Ok(self
+            .interact(move |client| client.get_block_stats(height), timeout)
+            .await??)
+    }
+
+    #[inline]
+    pub async fn get_mempool_info(&self, timeout: Duration) -> Result<GetMempoolInfoResult> {
+        Ok(self
+            .interact(move |client| client.get_mempool_info(), timeout)
+            .await??)
+    }
+
+    #[inline]
+    pub async fn estimate_smart_fee(
+        &self,
+        conf_target: u16,
+        timeout: Duration,
+    ) -> Result<EstimateSmartFeeResult> {
+        Ok(self
+            .interact(
+                move |client| client.estimate_smart_fee(conf_target, None),
+                timeout,
+            )
+            .await??)
+    }
+
+    #[inline]
+    pub async fn get_mempool_entry(
+        &self,
+        txid: Txid,
+        timeout: Duration,
+    ) -> Result<GetMempoolEntryResult> {
+        Ok(self
+            .interact(move |client| client.get_mempool_entry(&txid), timeout)
+            .await??)
+    }
+
+    #[inline]
+    pub async fn get_raw_transaction(&self, txid: Txid, timeout: Duration) -> Result<Transaction> {
+        Ok(self
+            .interact(
+                move |client| client.get_raw_transaction(&txid, None),
+                timeout,
+            )
+            .await??)
+    }
+
+    /// Check if an output is unspent in the active chain (mempool excluded)
+    #[inline]
+    pub async fn is_unspent(&self, outpoint: OutPoint, timeout: Duration) -> Result<bool> {
+        Ok(self
+            .interact(
+                move |client| client.get_tx_out(&outpoint.txid, outpoint.vout, Some(false)),
+                timeout,
+            )
+            .await??
+            .is_some())
+    }
+
+    /// Height of the block that confirmed a transaction, if any.
+    ///
+    /// Without `txindex`, `getrawtransaction` doesn't find confirmed transactions: the first
+    /// output is then looked up in the UTXO set, which only works while it's unspent.
+    pub async fn get_tx_block_height(&self, txid: Txid, timeout: Duration) -> Result<Option<u64>> {
+        Ok(self
+            .interact(
+                move |client| -> bitcoincore_rpc::Result<Option<u64>> {
+                    if let Ok(info) = client.get_raw_transaction_info(&txid, None) {
+                        return match info.blockhash {
+                            Some(hash) => {
+                                Ok(Some(client.get_block_header_info(&hash)?.height as u64))
+                            }
+                            None => Ok(None),
+                        };
+                    }
+
+                    match client.get_tx_out(&txid, 0, Some(false))? {
+                        Some(output) if output.confirmations > 0 => {
+                            let tip: u64 =
+                                client.get_block_header_info(&output.bestblock)?.height as u64;
+                            Ok(Some(tip + 1 - output.confirmations as u64))
+                        }
+                        _ => Ok(None),
+                    }
+                },
+                timeout,
+            )
+            .await??)
+    }
+
+    /// Get the mempool transaction spending an output (requires Bitcoin Core 24.0+)
+    pub async fn get_tx_spending_prevout(
+        &self,
+        outpoint: OutPoint,
+        timeout: Duration,
+    ) -> Result<Option<Txid>> {
+        let res: Vec<Value> = self
+            .interact(
+                move |client| {
+                    client.call(
+                        "gettxspendingprevout",
+                        &[json!([{ "txid": outpoint.txid, "vout": outpoint.vout }])],
+                    )
+                },
+                timeout,
+            )
+            .await??;
+        Ok(res
+            .first()
+            .and_then(|output| output.get("spendingtxid"))
+            .and_then(|txid| txid.as_str())
+            .and_then(|txid| txid.parse().ok()))
+    }
+
+    #[inline]
+    pub async fn get_index_info(&self, timeout: Duration) -> Result<GetIndexInfoResult> {
+        Ok(self
+            .interact(move |client| client.get_index_info(), timeout)
+            .await??)
+    }
+
+    /// Scan the UTXO set for the outputs matching `descriptors`
+    pub async fn scan_tx_out_set(
+        &self,
+        descriptors: Vec<ScanTxOutRequest>,
+        timeout: Duration,
+    ) -> Result<ScanTxOutResult> {
+        Ok(self
+            .interact(
+                move |client| client.scan_tx_out_set_blocking(&descriptors),
+                timeout,
+            )
+            .await??)
+    }
+
+    /// Get UTXO set statistics at `height` (requires `coinstatsindex`)
+    #[inline]
+    pub async fn get_tx_out_set_info(
+        &self,
+        height: u64,
+        timeout: Duration,
+    ) -> Result<GetTxOutSetInfoResult> {
+        Ok(self
+            .interact(
+                move |client| {
+                    client.get_tx_out_set_info(
+                        Some(TxOutSetHashType::Muhash),
+                        Some(HashOrHeight::Height(height)),
+                        Some(true),
+                    )
+                },
+                timeout,
+            )
+            .await??)
+    }
 }
@@ -0,0 +1,53 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use nostr_sdk::Result;
+
+use super::{AlertRule, BlockContext, RuleState};
+use crate::config::model::BlockRule;
+use crate::primitives::{Alert, AlertKind};
+use crate::util;
+
+/// Notable block heights: round numbers and palindromes
+pub struct Block {
+    round_magnitude: u32,
+    palindrome: bool,
+    every_block: bool,
+}
+
+impl Block {
+    pub fn new(config: &BlockRule) -> Self {
+        Self {
+            round_magnitude: config.round_magnitude,
+            palindrome: config.palindrome,
+            every_block: config.every_block,
+        }
+    }
+
+    fn kind(&self, block_height: u64) -> Option<AlertKind> {
+        if self.palindrome && util::is_palindrome(block_height) {
+            Some(AlertKind::PalindromeBlock)
+        } else if util::is_round_number(block_height, self.round_magnitude) {
+            Some(AlertKind::RoundBlock)
+        } else if self.every_block {
+            Some(AlertKind::Block)
+        } else {
+            None
+        }
+    }
+}
+
+impl AlertRule for Block {
+    fn name(&self) -> &'static str {
+        "block"
+    }
+
+    fn check(&self, ctx: &BlockContext, _state: &mut RuleState) -> Result<Vec<Alert>> {
+        let kind: AlertKind = match self.kind(ctx.block_height) {
+            Some(kind) => kind,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(vec![Alert::new(kind, ctx.block_height)])
+    }
+}
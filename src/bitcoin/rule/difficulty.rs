@@ -0,0 +1,36 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use nostr_sdk::Result;
+
+use super::{AlertRule, BlockContext, RuleState};
+use crate::primitives::{Alert, AlertKind, Var};
+
+/// Difficulty adjustments, every 2016 blocks
+pub struct Difficulty;
+
+impl AlertRule for Difficulty {
+    fn name(&self) -> &'static str {
+        "difficulty"
+    }
+
+    fn check(&self, ctx: &BlockContext, state: &mut RuleState) -> Result<Vec<Alert>> {
+        if ctx.block_height % 2016 != 0 {
+            return Ok(Vec::new());
+        }
+
+        let difficulty: f64 = ctx.mining_info.difficulty / u64::pow(10, 12) as f64;
+
+        let last_difficulty: f64 = state.get("last_difficulty").unwrap_or(difficulty);
+
+        let change: f64 = (difficulty - last_difficulty) / last_difficulty * 100.0;
+
+        state.set("last_difficulty", difficulty);
+
+        Ok(vec![Alert::new(AlertKind::Difficulty, ctx.block_height)
+            .value("difficulty", ctx.mining_info.difficulty)
+            .value("change", change)
+            .var("difficulty", Var::Decimal(difficulty, 2))
+            .var("change_pct", Var::Decimal(change, 2))])
+    }
+}
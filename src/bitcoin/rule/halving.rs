@@ -0,0 +1,103 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use nostr_sdk::Result;
+
+use super::{AlertRule, BlockContext, RuleState};
+use crate::bitcoin::supply::HALVING_INTERVAL;
+use crate::primitives::{Alert, AlertKind, Var};
+
+/// Halvings, and countdown to the next one
+pub struct Halving {
+    countdown: bool,
+}
+
+impl Halving {
+    pub fn new(countdown: bool) -> Self {
+        Self { countdown }
+    }
+}
+
+/// Notify more often as the halving gets closer
+fn is_countdown_block(block_height: u64, missing_blocks: u64) -> bool {
+    missing_blocks <= 144 * 7 // Less that a week left, notify every block
+        || (missing_blocks <= 4320 && missing_blocks % 6 == 0) // Less than 1 month left, notify every hour
+        || missing_blocks == 4320 // One month left
+        || (missing_blocks <= 8640 && missing_blocks % 144 == 0) // Less than 2 months left, notify every day
+        || missing_blocks == 8640 // 2 months left
+        || (missing_blocks <= 51840 && missing_blocks % 432 == 0) // Less than one year left, notify every 3 days
+        || missing_blocks == 51840 // One year left
+        || (missing_blocks <= 103680 && missing_blocks % 1008 == 0) // Less than two years left, notify every week
+        || missing_blocks == 105000 // Two years left
+        || (missing_blocks <= 1555520 && missing_blocks % 2016 == 0) // Less than three years left, notify two weeks
+        || missing_blocks == 1555520 // Three years left
+        || block_height % (6 * 24 * 30 * 3) == 0
+}
+
+impl AlertRule for Halving {
+    fn name(&self) -> &'static str {
+        "halving"
+    }
+
+    fn check(&self, ctx: &BlockContext, _state: &mut RuleState) -> Result<Vec<Alert>> {
+        let block_height: u64 = ctx.block_height;
+        let mut alerts: Vec<Alert> = Vec::new();
+
+        if block_height % HALVING_INTERVAL == 0 {
+            let halving: u64 = block_height / HALVING_INTERVAL;
+
+            if halving <= 32 {
+                // Calc block reward
+                let block_reward: f64 = 50.0 / f64::powf(2.0, halving as f64);
+
+                // Calc epoch
+                let epoch: u64 = halving + 1;
+
+                alerts.push(
+                    Alert::new(AlertKind::Halving, block_height)
+                        .value("halving", halving)
+                        .value("epoch", epoch)
+                        .var("halving", halving)
+                        .var("epoch", epoch)
+                        .var("epoch_ordinal", Var::Ordinal(epoch)),
+                );
+
+                alerts.push(
+                    Alert::new(AlertKind::BlockReward, block_height)
+                        .value("block_reward", block_reward)
+                        .var("block_reward", Var::Decimal(block_reward, 2)),
+                );
+            } else {
+                tracing::warn!("Halving > 32 ({halving})");
+            }
+        } else if self.countdown {
+            let missing_blocks: u64 =
+                (block_height / HALVING_INTERVAL + 1) * HALVING_INTERVAL - block_height;
+
+            if is_countdown_block(block_height, missing_blocks) {
+                alerts.push(
+                    Alert::new(AlertKind::HalvingCountdown, block_height)
+                        .value("missing_blocks", missing_blocks)
+                        .var("blocks_left", missing_blocks)
+                        .var("eta", Var::Minutes(missing_blocks * 10)),
+                );
+            }
+        }
+
+        Ok(alerts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_countdown_block() {
+        assert!(is_countdown_block(1_049_000, 1_000));
+        assert!(is_countdown_block(1_046_004, 3_996));
+        assert!(!is_countdown_block(1_046_005, 3_995));
+        assert!(is_countdown_block(945_000, 105_000));
+        assert!(!is_countdown_block(945_001, 104_999));
+    }
+}
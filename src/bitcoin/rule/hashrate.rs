@@ -0,0 +1,37 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use nostr_sdk::Result;
+
+use super::{AlertRule, BlockContext, RuleState};
+use crate::primitives::{Alert, AlertKind, Var};
+
+/// Hashrate all-time highs
+pub struct Hashrate;
+
+impl AlertRule for Hashrate {
+    fn name(&self) -> &'static str {
+        "hashrate"
+    }
+
+    fn check(&self, ctx: &BlockContext, state: &mut RuleState) -> Result<Vec<Alert>> {
+        let current_hashrate: f64 = ctx.mining_info.network_hash_ps / u64::pow(10, 18) as f64; // Hashrate in EH/s
+
+        let last_hashrate_ath: f64 = match state.get("last_hashrate_ath") {
+            Some(value) => value,
+            None => {
+                state.set("last_hashrate_ath", current_hashrate);
+                current_hashrate
+            }
+        };
+
+        if current_hashrate > last_hashrate_ath {
+            state.set("last_hashrate_ath", current_hashrate);
+            return Ok(vec![Alert::new(AlertKind::Hashrate, ctx.block_height)
+                .value("hashrate", ctx.mining_info.network_hash_ps)
+                .var("hashrate", Var::Decimal(current_hashrate, 2))]);
+        }
+
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Block alert rules
+
+use std::str::FromStr;
+
+use bitcoincore_rpc::json::GetMiningInfoResult;
+use nostr_sdk::Result;
+
+mod block;
+mod difficulty;
+mod halving;
+mod hashrate;
+
+use self::block::Block as BlockAlert;
+use self::difficulty::Difficulty;
+use self::halving::Halving;
+use self::hashrate::Hashrate;
+use crate::config::Config;
+use crate::db::{BitcoinStore, StateUpdates};
+use crate::primitives::Alert;
+
+/// Data available to the rules for every processed block
+pub struct BlockContext<'a> {
+    pub block_height: u64,
+    pub mining_info: &'a GetMiningInfoResult,
+}
+
+/// State of a rule, kept between blocks
+///
+/// The changes are written only once the whole block has been processed.
+pub struct RuleState<'a> {
+    rule: &'static str,
+    store: &'a BitcoinStore,
+    updates: &'a mut StateUpdates,
+}
+
+impl RuleState<'_> {
+    fn key(&self, key: &str) -> String {
+        format!("{}/{key}", self.rule)
+    }
+
+    pub fn get<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+    {
+        let key: String = self.key(key);
+        match self.updates.get(&key) {
+            Some(value) => value.parse().ok(),
+            None => self.store.get_rule_state(&key).ok(),
+        }
+    }
+
+    pub fn set<T>(&mut self, key: &str, value: T)
+    where
+        T: ToString,
+    {
+        let key: String = self.key(key);
+        self.updates.set(key, value);
+    }
+}
+
+pub trait AlertRule: Send + Sync {
+    /// Also the namespace of the rule state
+    fn name(&self) -> &'static str;
+
+    /// Check the rule against a new block
+    fn check(&self, ctx: &BlockContext, state: &mut RuleState) -> Result<Vec<Alert>>;
+}
+
+#[derive(Default)]
+pub struct Registry {
+    rules: Vec<Box<dyn AlertRule>>,
+}
+
+impl Registry {
+    /// Registry with the built-in rules enabled in config
+    pub fn from_config(config: &Config) -> Self {
+        let rules = &config.rules;
+        let mut registry = Self::default();
+
+        if rules.halving.enabled {
+            registry.register(Halving::new(rules.halving.countdown));
+        }
+
+        if rules.difficulty.enabled {
+            registry.register(Difficulty);
+        }
+
+        if rules.hashrate.enabled {
+            registry.register(Hashrate);
+        }
+
+        if rules.block.enabled {
+            registry.register(BlockAlert::new(&rules.block));
+        }
+
+        registry
+    }
+
+    pub fn register<R>(&mut self, rule: R)
+    where
+        R: AlertRule + 'static,
+    {
+        tracing::debug!("Registered {} alert rule", rule.name());
+        self.rules.push(Box::new(rule));
+    }
+
+    /// Check all the rules, in registration order
+    pub fn check(
+        &self,
+        ctx: &BlockContext,
+        store: &BitcoinStore,
+        updates: &mut StateUpdates,
+    ) -> Result<Vec<Alert>> {
+        let mut alerts: Vec<Alert> = Vec::new();
+
+        for rule in self.rules.iter() {
+            let mut state = RuleState {
+                rule: rule.name(),
+                store,
+                updates,
+            };
+            let rule_alerts: Vec<Alert> = rule
+                .check(ctx, &mut state)
+                .map_err(|e| format!("{} rule: {e}", rule.name()))?;
+            alerts.extend(rule_alerts);
+        }
+
+        Ok(alerts)
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use bitcoin::Amount;
+
+pub const HALVING_INTERVAL: u64 = 210_000;
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000; // sat
+
+/// Block subsidy at `block_height`, as computed by consensus (`GetBlockSubsidy`)
+pub fn block_subsidy(block_height: u64) -> Amount {
+    let halvings: u64 = block_height / HALVING_INTERVAL;
+
+    // Force block reward to zero when right shift is undefined
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+
+    Amount::from_sat(INITIAL_SUBSIDY >> halvings)
+}
+
+/// Sum of the subsidies of all blocks up to `block_height` (genesis included)
+pub fn scheduled_supply(block_height: u64) -> Amount {
+    let mut supply: u64 = 0;
+    let mut epoch_start: u64 = 0;
+
+    while epoch_start <= block_height {
+        let subsidy: u64 = block_subsidy(epoch_start).to_sat();
+
+        if subsidy == 0 {
+            break;
+        }
+
+        let epoch_end: u64 = (epoch_start + HALVING_INTERVAL - 1).min(block_height);
+        supply += (epoch_end - epoch_start + 1) * subsidy;
+        epoch_start += HALVING_INTERVAL;
+    }
+
+    Amount::from_sat(supply)
+}
+
+/// Milestones reached moving the supply from `previous` to `current`
+pub fn crossed_milestones(
+    milestones: &[Amount],
+    previous: Amount,
+    current: Amount,
+) -> impl Iterator<Item = Amount> + '_ {
+    milestones
+        .iter()
+        .copied()
+        .filter(move |milestone| previous < *milestone && *milestone <= current)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0), Amount::from_btc(50.0).unwrap());
+        assert_eq!(block_subsidy(209_999), Amount::from_btc(50.0).unwrap());
+        assert_eq!(block_subsidy(210_000), Amount::from_btc(25.0).unwrap());
+        assert_eq!(block_subsidy(840_000), Amount::from_sat(312_500_000));
+        assert_eq!(block_subsidy(6_720_000), Amount::from_sat(1));
+        assert_eq!(block_subsidy(6_930_000), Amount::ZERO);
+        assert_eq!(block_subsidy(64 * HALVING_INTERVAL), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_scheduled_supply() {
+        assert_eq!(scheduled_supply(0), Amount::from_btc(50.0).unwrap());
+        assert_eq!(
+            scheduled_supply(209_999),
+            Amount::from_btc(10_500_000.0).unwrap()
+        );
+        assert_eq!(
+            scheduled_supply(839_999),
+            Amount::from_btc(19_687_500.0).unwrap()
+        );
+        // Final supply, in sat
+        assert_eq!(
+            scheduled_supply(u64::MAX / 2).to_sat(),
+            2_099_999_997_690_000
+        );
+    }
+
+    #[test]
+    fn test_crossed_milestones() {
+        let milestones = [
+            Amount::from_btc(19_900_000.0).unwrap(),
+            Amount::from_btc(20_000_000.0).unwrap(),
+        ];
+        let previous = Amount::from_btc(19_899_999.0).unwrap();
+        let current = Amount::from_btc(19_900_002.125).unwrap();
+        assert_eq!(
+            crossed_milestones(&milestones, previous, current).collect::<Vec<_>>(),
+            vec![milestones[0]]
+        );
+        assert_eq!(crossed_milestones(&milestones, current, current).count(), 0);
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time;
+use zeromq::{Socket, SocketRecv, SubSocket, ZmqMessage, ZmqResult};
+
+use crate::config::model::Zmq;
+
+async fn subscribe(zmq: &Zmq, notify: &Notify) -> ZmqResult<()> {
+    let mut socket = SubSocket::new();
+    socket.connect(&zmq.endpoint).await?;
+    socket.subscribe(zmq.topic.as_str()).await?;
+
+    tracing::info!(
+        "Subscribed to {} notifications on {}",
+        zmq.topic,
+        zmq.endpoint
+    );
+
+    loop {
+        let msg: ZmqMessage = socket.recv().await?;
+
+        // Frames: topic, body, sequence number
+        if let Some(sequence) = msg.get(2) {
+            tracing::debug!(
+                "Received {} notification (sequence {:?})",
+                zmq.topic,
+                sequence.as_ref()
+            );
+        }
+
+        notify.notify_one();
+    }
+}
+
+/// Wake the processor every time the node announces a new block
+pub async fn run(zmq: Zmq, notify: Arc<Notify>) {
+    loop {
+        if let Err(e) = subscribe(&zmq, &notify).await {
+            tracing::error!(
+                "ZMQ subscription to {} failed: {e} - retrying in 60 sec",
+                zmq.endpoint
+            );
+        }
+
+        time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zeromq::{PubSocket, SocketSend};
+
+    use super::*;
+    use crate::config::model::ZmqTopic;
+
+    #[tokio::test]
+    async fn test_notify_on_new_block() {
+        let mut publisher = PubSocket::new();
+        let endpoint = publisher.bind("tcp://127.0.0.1:0").await.unwrap();
+
+        let notify = Arc::new(Notify::new());
+        let zmq = Zmq {
+            endpoint: endpoint.to_string(),
+            topic: ZmqTopic::HashBlock,
+        };
+        tokio::spawn(run(zmq, notify.clone()));
+
+        // Keep publishing until the subscriber is connected
+        let publish = async {
+            loop {
+                let mut msg = ZmqMessage::from("hashblock");
+                msg.push_back(vec![0u8; 32].into());
+                msg.push_back(0u32.to_le_bytes().to_vec().into());
+                publisher.send(msg).await.unwrap();
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = publish => {}
+            _ = time::sleep(Duration::from_secs(10)) => panic!("Not notified"),
+        }
+    }
+}
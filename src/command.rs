@@ -0,0 +1,161 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Commands queued by the CLI while the daemon is running
+//!
+//! The database can't be opened for writing by two processes: the CLI writes the commands in
+//! `<main_path>/commands` instead, and the running instance applies them.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::Txid;
+use nostr_sdk::Result;
+use tokio::time;
+
+use crate::config::Config;
+use crate::db::NotificationStore;
+
+/// Interval between two checks of the queued commands
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum QueuedCommand {
+    Track { txid: Txid },
+    Untrack { txid: Txid },
+    Requeue { id: String },
+}
+
+impl fmt::Display for QueuedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Track { txid } => write!(f, "Tracking {txid}"),
+            Self::Untrack { txid } => write!(f, "Stopped tracking {txid}"),
+            Self::Requeue { id } => write!(f, "Requeued {id}"),
+        }
+    }
+}
+
+impl QueuedCommand {
+    pub fn apply(&self, store: &NotificationStore) -> Result<()> {
+        match self {
+            Self::Track { txid } => store.track_tx(txid)?,
+            Self::Untrack { txid } => store.untrack_tx(txid)?,
+            Self::Requeue { id } => store.requeue_dead_letter(id)?,
+        }
+        Ok(())
+    }
+}
+
+/// Directory of the queued commands
+pub fn path(config: &Config) -> PathBuf {
+    config.main_path.join("commands")
+}
+
+/// Queue a command for the running instance
+pub fn queue(path: &Path, command: &QueuedCommand) -> Result<()> {
+    fs::create_dir_all(path)?;
+
+    // File names keep the commands in order
+    let nanos: u128 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let name: String = format!("{nanos:024}-{}", std::process::id());
+
+    // Written under a temporary name and renamed, so that a partial file is never applied
+    let tmp: PathBuf = path.join(format!("{name}.tmp"));
+    fs::write(&tmp, serde_json::to_vec(command)?)?;
+    fs::rename(&tmp, path.join(format!("{name}.json")))?;
+
+    Ok(())
+}
+
+/// Apply the queued commands, in order, and delete them
+fn apply_queued(path: &Path, store: &NotificationStore) -> Result<()> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+            .collect(),
+        Err(_) => return Ok(()),
+    };
+    files.sort();
+
+    for file in files.into_iter() {
+        match serde_json::from_slice::<QueuedCommand>(&fs::read(&file)?) {
+            Ok(command) => match command.apply(store) {
+                Ok(()) => tracing::info!("{command}"),
+                Err(e) => tracing::error!("Impossible to apply queued command {command:?}: {e}"),
+            },
+            Err(e) => tracing::error!("Invalid queued command {}: {e}", file.display()),
+        }
+
+        // Failed commands are not retried: the error is reported and the next ones applied
+        fs::remove_file(&file)?;
+    }
+
+    Ok(())
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) {
+    let path: PathBuf = path(config);
+
+    loop {
+        if let Err(e) = apply_queued(&path, store) {
+            tracing::error!("Apply queued commands: {e}");
+        }
+
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_queued_command_format() {
+        let txid =
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        let command = QueuedCommand::Track { txid };
+        let json: String = serde_json::to_string(&command).unwrap();
+        assert_eq!(
+            json,
+            r#"{"command":"track","txid":"4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<QueuedCommand>(&json).unwrap(),
+            command
+        );
+    }
+
+    #[test]
+    fn test_apply_queued() {
+        let root =
+            std::env::temp_dir().join(format!("bitcoin-alerts-command-{}", std::process::id()));
+        let path = root.join("commands");
+        let store = NotificationStore::open(&root.join("notification")).unwrap();
+        let txid =
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+
+        queue(&path, &QueuedCommand::Track { txid }).unwrap();
+        queue(
+            &path,
+            &QueuedCommand::Requeue {
+                id: String::from("missing"),
+            },
+        )
+        .unwrap();
+        apply_queued(&path, &store).unwrap();
+
+        assert!(store.get_tracked_txs().unwrap().contains_key(&txid));
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,21 +1,51 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::collections::HashSet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use bitcoin::address::{Address, NetworkUnchecked};
 use bitcoin::network::Network;
-use clap::Parser;
+use bitcoin::{Amount, ScriptBuf, Txid};
+use clap::{Parser, Subcommand};
 use dirs::home_dir;
-use nostr_sdk::{Keys, Url};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use nostr_sdk::nips::nip46::NostrConnectURI;
+use nostr_sdk::{Keys, RelayUrl, SecretKey, Url};
 use ntfy::Auth;
 use tracing::Level;
 
+use crate::locale::Locale;
+use crate::primitives::Target;
+use crate::template::Templates;
+use crate::util;
+
 pub mod model;
 
 pub use self::model::Config;
-use self::model::{Bitcoin, ConfigFile, Nostr, Ntfy};
+use self::model::{
+    Bitcoin, BlockRule, ConfigFile, ConfigFileNostr, ConfigFileNtfy, ConfigFileWatch, Delivery,
+    DifficultyRule, Discord, Email, Fees, HalvingRule, HashrateRule, Matrix, MatrixAuth, Mqtt,
+    Nostr, Ntfy, Route, Rules, Signer, Slack, SmtpTls, Supply, Telegram, Tracker, Watch, Webhook,
+    Zmq, ZmqTopic,
+};
+
+/// Notify blocks divisible by 10^4
+const DEFAULT_ROUND_BLOCK_MAGNITUDE: u32 = 4;
+
+/// Total supply milestones, in BTC
+const DEFAULT_SUPPLY_MILESTONES: &[u64] = &[
+    19_900_000, 20_000_000, 20_100_000, 20_200_000, 20_300_000, 20_400_000, 20_500_000, 20_600_000,
+    20_700_000, 20_800_000, 20_900_000, 20_950_000, 20_990_000, 20_999_000,
+];
+
+/// Confirmation milestones of tracked transactions
+const DEFAULT_TRACKER_CONFIRMATIONS: &[u32] = &[1, 3, 6];
+
+/// Default number of addresses derived from ranged descriptors
+const DEFAULT_WATCH_RANGE: u32 = 1000;
 
 fn default_dir() -> PathBuf {
     let home: PathBuf = home_dir().unwrap_or_else(|| {
@@ -31,19 +61,209 @@ fn default_config_file() -> PathBuf {
     default
 }
 
+fn watch_scripts(watch: &ConfigFileWatch, network: Network) -> HashSet<ScriptBuf> {
+    let mut scripts: HashSet<ScriptBuf> = HashSet::new();
+
+    if let Some(address) = &watch.address {
+        let address: Address = Address::<NetworkUnchecked>::from_str(address)
+            .and_then(|address| address.require_network(network))
+            .unwrap_or_else(|e| panic!("Invalid watch address {address}: {e}"));
+        scripts.insert(address.script_pubkey());
+    }
+
+    if let Some(descriptor) = &watch.descriptor {
+        let descriptor: Descriptor<DescriptorPublicKey> = Descriptor::from_str(descriptor)
+            .unwrap_or_else(|e| panic!("Invalid watch descriptor {descriptor}: {e}"));
+
+        // Split `<0;1>` multipath descriptors (i.e. receive and change)
+        let descriptors = descriptor
+            .into_single_descriptors()
+            .unwrap_or_else(|e| panic!("Invalid watch descriptor: {e}"));
+
+        for descriptor in descriptors.into_iter() {
+            let range: u32 = if descriptor.has_wildcard() {
+                watch.range.unwrap_or(DEFAULT_WATCH_RANGE)
+            } else {
+                1
+            };
+
+            for index in 0..range {
+                let derived = descriptor
+                    .at_derivation_index(index)
+                    .unwrap_or_else(|e| panic!("Impossible to derive descriptor: {e}"));
+                scripts.insert(derived.script_pubkey());
+            }
+        }
+    }
+
+    scripts
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeadLetterCommand {
+    /// List the notifications in the dead-letter queue
+    List,
+    /// Move notifications back to the delivery queue
+    Requeue {
+        ids: Vec<String>,
+        /// Requeue all the notifications
+        #[clap(long)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Track a transaction until it reaches the last confirmation milestone
+    Track { txid: Txid },
+    /// Stop tracking a transaction
+    Untrack { txid: Txid },
+    /// Inspect notifications that exceeded the max delivery attempts or age
+    DeadLetter {
+        #[clap(subcommand)]
+        command: DeadLetterCommand,
+    },
+}
+
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+pub struct Args {
     #[clap(short, long)]
     config_file: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 }
 
-impl Config {
-    pub fn from_args() -> Self {
-        let args: Args = Args::parse();
+/// Supply milestone, from BTC
+fn supply_milestone(btc: u64) -> Amount {
+    btc.checked_mul(100_000_000)
+        .map(Amount::from_sat)
+        .filter(|amount| *amount <= Amount::MAX_MONEY)
+        .unwrap_or_else(|| panic!("Invalid supply milestone in config file: {btc} BTC"))
+}
+
+fn ntfy_instance(ntfy: ConfigFileNtfy, locale: Locale) -> Ntfy {
+    let auth: Option<Auth> = match (ntfy.username, ntfy.password) {
+        (Some(username), Some(password)) => Some(Auth::credentials(username, password)),
+        _ => None,
+    };
+
+    Ntfy {
+        instance: ntfy.instance.unwrap_or_else(|| Target::Ntfy.to_string()),
+        enabled: ntfy.enabled.unwrap_or(false),
+        locale: ntfy.locale.unwrap_or(locale),
+        url: ntfy.url.unwrap_or_default(),
+        topic: ntfy.topic.unwrap_or_else(|| String::from("bitcoin_alerts")),
+        auth,
+        proxy: ntfy.proxy,
+        priorities: ntfy.priorities.unwrap_or_default(),
+        tags: ntfy.tags.unwrap_or_default(),
+        explorer_url: ntfy.explorer_url,
+        markdown: ntfy.markdown.unwrap_or(false),
+    }
+}
 
+/// Signer of a nostr instance: a remote signer, a key file or a secret key
+fn nostr_signer(nostr: &ConfigFileNostr, instance: &str, main_path: &Path) -> Option<Signer> {
+    match (&nostr.bunker_uri, &nostr.key_file, &nostr.secret_key) {
+        (Some(uri), None, None) => {
+            let uri: NostrConnectURI = NostrConnectURI::parse(uri)
+                .unwrap_or_else(|e| panic!("Invalid nostr bunker URI: {e}"));
+            if !matches!(uri, NostrConnectURI::Bunker { .. }) {
+                panic!("Invalid nostr bunker URI: expected bunker://");
+            }
+            Some(Signer::Bunker {
+                uri,
+                app_key_file: main_path.join(format!("nostr-connect-{instance}.key")),
+            })
+        }
+        (None, Some(path), None) => {
+            let secret_key: SecretKey = util::read_secret_key(path)
+                .unwrap_or_else(|e| panic!("Invalid nostr key file {}: {e}", path.display()));
+            Some(Signer::Keys(Keys::new(secret_key)))
+        }
+        (None, None, Some(secret_key)) => Some(Signer::Keys(Keys::new(secret_key.clone()))),
+        (None, None, None) => None,
+        _ => panic!(
+            "Set only one of secret_key, key_file and bunker_uri for nostr instance {instance}"
+        ),
+    }
+}
+
+fn nostr_instance(nostr: ConfigFileNostr, locale: Locale, main_path: &Path) -> Nostr {
+    let instance: String = nostr
+        .instance
+        .clone()
+        .unwrap_or_else(|| Target::Nostr.to_string());
+    let enabled: bool = nostr.enabled.unwrap_or(false);
+    let signer: Option<Signer> = nostr_signer(&nostr, &instance, main_path);
+
+    let relays: HashSet<RelayUrl> = nostr.relays.unwrap_or_default();
+    let min_relay_acks: usize = nostr.min_relay_acks.unwrap_or(1);
+
+    if enabled && signer.is_none() {
+        panic!("Nostr instance {instance} requires secret_key, key_file or bunker_uri");
+    }
+
+    if enabled && (min_relay_acks == 0 || min_relay_acks > relays.len()) {
+        panic!(
+            "Nostr instance {instance}: min_relay_acks must be between 1 and the number of relays"
+        );
+    }
+
+    Nostr {
+        instance,
+        enabled,
+        locale: nostr.locale.unwrap_or(locale),
+        signer,
+        name: nostr.name.unwrap_or_else(|| String::from("bitcoin_alerts")),
+        display_name: nostr.display_name.unwrap_or_else(|| String::from("Bitcoin Alerts")),
+        description: nostr.description.unwrap_or_else(|| String::from("Hashrate, supply, blocks until halving, difficulty adjustment and more.\n\nBuilt with https://crates.io/crates/nostr-sdk 🦀")),
+        picture: nostr.picture.unwrap_or_else(|| Url::parse("https://avatars.githubusercontent.com/u/13464320").expect("Invalid url")),
+        nip05: nostr.nip05,
+        lud16: nostr.lud16.unwrap_or_else(|| String::from("yuki@getalby.com")),
+        relays,
+        pow_difficulty: nostr.pow_difficulty.unwrap_or(0),
+        dm_subscriptions: nostr.dm_subscriptions.unwrap_or(false),
+        expiration: nostr.expiration.unwrap_or_default(),
+        min_relay_acks,
+        nip42_auth: nostr.nip42_auth.unwrap_or(true),
+        state_events: nostr.state_events.unwrap_or(false),
+        metrics_file: nostr.metrics_file,
+    }
+}
+
+/// Instances of the same target must have unique names
+fn check_instance_names<'a, I>(target: Target, names: I)
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut seen: HashSet<&str> = HashSet::new();
+    for name in names.into_iter() {
+        if !seen.insert(name) {
+            panic!("Duplicate {target} instance name in config file: {name}");
+        }
+    }
+}
+
+/// An enabled target without destinations would drop every alert
+fn check_destinations(target: Target, enabled: bool, field: &str, destinations: usize) {
+    if enabled && destinations == 0 {
+        panic!("Enabled {target} requires at least one destination in `{field}` in config file");
+    }
+}
+
+fn check_required(target: Target, enabled: bool, field: &str, value: Option<&str>) {
+    if enabled && value.is_none_or(|value| value.trim().is_empty()) {
+        panic!("Enabled {target} requires `{field}` in config file");
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &Args) -> Self {
         // Read and parse config file
-        let config_file_path: PathBuf = args.config_file.unwrap_or_else(default_config_file);
+        let config_file_path: PathBuf =
+            args.config_file.clone().unwrap_or_else(default_config_file);
         let config_content = std::fs::read_to_string(config_file_path).unwrap();
         let config_file: ConfigFile = toml::from_str(&config_content).unwrap();
 
@@ -79,13 +299,46 @@ impl Config {
             None => Level::INFO,
         };
 
-        let ntfy_auth: Option<Auth> = if let Some(username) = config_file.ntfy.username {
-            config_file
-                .ntfy
-                .password
-                .map(|password| Auth::credentials(username, password))
-        } else {
-            None
+        // Prefer `hashblock`: the processor only needs to be woken up
+        let zmq: Option<Zmq> = match (
+            config_file.bitcoin.zmqpubhashblock,
+            config_file.bitcoin.zmqpubrawblock,
+        ) {
+            (Some(endpoint), _) => Some(Zmq {
+                endpoint,
+                topic: ZmqTopic::HashBlock,
+            }),
+            (None, Some(endpoint)) => Some(Zmq {
+                endpoint,
+                topic: ZmqTopic::RawBlock,
+            }),
+            (None, None) => None,
+        };
+
+        let locale: Locale = config_file.locale.unwrap_or_default();
+
+        let ntfy: Vec<Ntfy> = config_file
+            .ntfy
+            .into_vec()
+            .into_iter()
+            .map(|ntfy| ntfy_instance(ntfy, locale))
+            .collect();
+        check_instance_names(Target::Ntfy, ntfy.iter().map(|n| n.instance.as_str()));
+
+        let nostr: Vec<Nostr> = config_file
+            .nostr
+            .into_vec()
+            .into_iter()
+            .map(|nostr| nostr_instance(nostr, locale, &main_path))
+            .collect();
+        check_instance_names(Target::Nostr, nostr.iter().map(|n| n.instance.as_str()));
+
+        let matrix_auth: Option<MatrixAuth> = match config_file.matrix.access_token {
+            Some(access_token) => Some(MatrixAuth::AccessToken(access_token)),
+            None => match (config_file.matrix.user, config_file.matrix.password) {
+                (Some(user), Some(password)) => Some(MatrixAuth::Password { user, password }),
+                _ => None,
+            },
         };
 
         let config = Self {
@@ -101,34 +354,386 @@ impl Config {
                 }),
                 rpc_username: config_file.bitcoin.rpc_username,
                 rpc_password: config_file.bitcoin.rpc_password,
+                zmq,
                 db_path: main_path.join("chainstate"),
             },
-            ntfy: Ntfy {
-                enabled: config_file.ntfy.enabled.unwrap_or(false),
-                url: config_file.ntfy.url.unwrap_or_default(),
-                topic: config_file
-                    .ntfy
-                    .topic
+            rules: Rules {
+                halving: HalvingRule {
+                    enabled: config_file.rules.halving.enabled.unwrap_or(true),
+                    countdown: config_file.rules.halving.countdown.unwrap_or(true),
+                },
+                difficulty: DifficultyRule {
+                    enabled: config_file.rules.difficulty.enabled.unwrap_or(true),
+                },
+                hashrate: HashrateRule {
+                    enabled: config_file.rules.hashrate.enabled.unwrap_or(true),
+                },
+                block: BlockRule {
+                    enabled: config_file.rules.block.enabled.unwrap_or(true),
+                    round_magnitude: config_file
+                        .rules
+                        .block
+                        .round_magnitude
+                        .unwrap_or(DEFAULT_ROUND_BLOCK_MAGNITUDE),
+                    palindrome: config_file.rules.block.palindrome.unwrap_or(true),
+                    every_block: config_file
+                        .rules
+                        .block
+                        .every_block
+                        .unwrap_or(network == Network::Regtest),
+                },
+            },
+            supply: Supply {
+                enabled: config_file.supply.enabled.unwrap_or(false),
+                milestones: config_file
+                    .supply
+                    .milestones
+                    .unwrap_or_else(|| DEFAULT_SUPPLY_MILESTONES.to_vec())
+                    .into_iter()
+                    .map(supply_milestone)
+                    .collect(),
+            },
+            fees: Fees {
+                enabled: config_file.fees.enabled.unwrap_or(false),
+                low_fee_rate: config_file.fees.low_fee_rate,
+                high_median_fee_rate: config_file.fees.high_median_fee_rate,
+                mempool_purging: config_file.fees.mempool_purging.unwrap_or(true),
+                hysteresis: config_file.fees.hysteresis.unwrap_or(20.0),
+            },
+            watch: config_file
+                .watch
+                .iter()
+                .enumerate()
+                .map(|(index, watch)| Watch {
+                    label: watch
+                        .label
+                        .clone()
+                        .or_else(|| watch.address.clone())
+                        .unwrap_or_else(|| format!("Descriptor #{}", index + 1)),
+                    scripts: watch_scripts(watch, network),
+                })
+                .collect(),
+            tracker: Tracker {
+                txids: config_file.tracker.txids.unwrap_or_default(),
+                confirmations: {
+                    let mut confirmations: Vec<u32> = DEFAULT_TRACKER_CONFIRMATIONS.to_vec();
+                    confirmations.extend(config_file.tracker.confirmations);
+                    confirmations.sort();
+                    confirmations.dedup();
+                    confirmations
+                },
+            },
+            delivery: Delivery {
+                max_attempts: config_file.delivery.max_attempts.unwrap_or(20),
+                max_age: config_file.delivery.max_age.unwrap_or(7 * 24 * 60 * 60),
+                max_backoff: config_file.delivery.max_backoff.unwrap_or(6 * 60 * 60),
+                max_blocking_attempts: config_file.delivery.max_blocking_attempts.unwrap_or(3),
+            },
+            routes: config_file
+                .route
+                .into_iter()
+                .map(|route| {
+                    for target in route.targets.iter() {
+                        let known: bool = Target::from_str(target).is_ok()
+                            || ntfy.iter().any(|n| &n.instance == target)
+                            || nostr.iter().any(|n| &n.instance == target);
+                        if !known {
+                            panic!("Invalid route in config file: unknown target: {target}");
+                        }
+                    }
+
+                    Route {
+                        kinds: route.kinds.unwrap_or_default(),
+                        severity: route.severity,
+                        targets: route.targets,
+                        priority: route.priority,
+                    }
+                })
+                .collect(),
+            templates: match config_file.templates {
+                Some(path) => match Templates::from_file(&path) {
+                    Ok(templates) => templates,
+                    Err(e) => panic!("Invalid templates file {}: {e}", path.display()),
+                },
+                None => Templates::default(),
+            },
+            ntfy,
+            nostr,
+            matrix: Matrix {
+                enabled: config_file.matrix.enabled.unwrap_or(false),
+                locale: config_file.matrix.locale.unwrap_or(locale),
+                homeserver: config_file
+                    .matrix
+                    .homeserver
+                    .unwrap_or_else(|| Url::parse("https://matrix.org").expect("Invalid url")),
+                auth: matrix_auth,
+                rooms: config_file.matrix.rooms.unwrap_or_default(),
+                proxy: config_file.matrix.proxy,
+                session_file: main_path.join("matrix-session.json"),
+            },
+            discord: Discord {
+                enabled: config_file.discord.enabled.unwrap_or(false),
+                locale: config_file.discord.locale.unwrap_or(locale),
+                webhook_urls: config_file.discord.webhook_urls.unwrap_or_default(),
+                username: config_file.discord.username,
+                proxy: config_file.discord.proxy,
+            },
+            slack: Slack {
+                enabled: config_file.slack.enabled.unwrap_or(false),
+                locale: config_file.slack.locale.unwrap_or(locale),
+                webhook_urls: config_file.slack.webhook_urls.unwrap_or_default(),
+                proxy: config_file.slack.proxy,
+            },
+            email: {
+                let tls: SmtpTls = config_file.email.tls.unwrap_or(SmtpTls::StartTls);
+                Email {
+                    enabled: config_file.email.enabled.unwrap_or(false),
+                    locale: config_file.email.locale.unwrap_or(locale),
+                    smtp_host: config_file
+                        .email
+                        .smtp_host
+                        .unwrap_or_else(|| String::from("localhost")),
+                    smtp_port: config_file
+                        .email
+                        .smtp_port
+                        .unwrap_or_else(|| tls.default_port()),
+                    tls,
+                    username: config_file.email.username,
+                    password: config_file.email.password,
+                    from: config_file.email.from.unwrap_or_else(|| {
+                        String::from("Bitcoin Alerts <bitcoin-alerts@localhost>")
+                    }),
+                    to: config_file.email.to.unwrap_or_default(),
+                    subject: config_file
+                        .email
+                        .subject
+                        .unwrap_or_else(|| String::from("[Bitcoin Alerts] {plain_text}")),
+                }
+            },
+            mqtt: Mqtt {
+                enabled: config_file.mqtt.enabled.unwrap_or(false),
+                locale: config_file.mqtt.locale.unwrap_or(locale),
+                host: config_file
+                    .mqtt
+                    .host
+                    .unwrap_or_else(|| String::from("localhost")),
+                port: config_file.mqtt.port.unwrap_or(1883),
+                client_id: config_file
+                    .mqtt
+                    .client_id
+                    .unwrap_or_else(|| String::from("bitcoin_alerts")),
+                username: config_file.mqtt.username,
+                password: config_file.mqtt.password,
+                topic_prefix: config_file
+                    .mqtt
+                    .topic_prefix
                     .unwrap_or_else(|| String::from("bitcoin_alerts")),
-                auth: ntfy_auth,
-                proxy: config_file.ntfy.proxy,
-            },
-            nostr: Nostr {
-                enabled: config_file.nostr.enabled.unwrap_or(false),
-                keys: config_file.nostr.secret_key.map(Keys::new),
-                name: config_file.nostr.name.unwrap_or_else(|| String::from("bitcoin_alerts")),
-                display_name: config_file.nostr.display_name.unwrap_or_else(|| String::from("Bitcoin Alerts")),
-                description: config_file.nostr.description.unwrap_or_else(|| String::from("Hashrate, supply, blocks until halving, difficulty adjustment and more.\n\nBuilt with https://crates.io/crates/nostr-sdk 🦀")),
-                picture: config_file.nostr.picture.unwrap_or_else(|| Url::parse("https://avatars.githubusercontent.com/u/13464320").expect("Invalid url")),
-                nip05: config_file.nostr.nip05,
-                lud16: config_file.nostr.lud16.unwrap_or_else(|| String::from("yuki@getalby.com")),
-                relays: config_file.nostr.relays.unwrap_or_default(),
-                pow_difficulty: config_file.nostr.pow_difficulty.unwrap_or(0),
+                discovery_prefix: if config_file.mqtt.discovery.unwrap_or(true) {
+                    Some(
+                        config_file
+                            .mqtt
+                            .discovery_prefix
+                            .unwrap_or_else(|| String::from("homeassistant")),
+                    )
+                } else {
+                    None
+                },
+            },
+            telegram: Telegram {
+                enabled: config_file.telegram.enabled.unwrap_or(false),
+                locale: config_file.telegram.locale.unwrap_or(locale),
+                api_url: config_file.telegram.api_url.unwrap_or_else(|| {
+                    Url::parse("https://api.telegram.org").expect("Invalid url")
+                }),
+                bot_token: config_file.telegram.bot_token,
+                chat_ids: config_file.telegram.chat_ids.unwrap_or_default(),
+                message_thread_id: config_file.telegram.message_thread_id,
+                proxy: config_file.telegram.proxy,
+            },
+            webhook: Webhook {
+                enabled: config_file.webhook.enabled.unwrap_or(false),
+                locale: config_file.webhook.locale.unwrap_or(locale),
+                urls: config_file.webhook.urls.unwrap_or_default(),
+                headers: config_file.webhook.headers.unwrap_or_default(),
+                secret: config_file.webhook.secret,
+                proxy: config_file.webhook.proxy,
             },
         };
 
+        check_destinations(
+            Target::Matrix,
+            config.matrix.enabled,
+            "rooms",
+            config.matrix.rooms.len(),
+        );
+        check_destinations(
+            Target::Discord,
+            config.discord.enabled,
+            "webhook_urls",
+            config.discord.webhook_urls.len(),
+        );
+        check_destinations(
+            Target::Slack,
+            config.slack.enabled,
+            "webhook_urls",
+            config.slack.webhook_urls.len(),
+        );
+        check_destinations(
+            Target::Email,
+            config.email.enabled,
+            "to",
+            config.email.to.len(),
+        );
+        check_destinations(
+            Target::Telegram,
+            config.telegram.enabled,
+            "chat_ids",
+            config.telegram.chat_ids.len(),
+        );
+        check_required(
+            Target::Telegram,
+            config.telegram.enabled,
+            "bot_token",
+            config.telegram.bot_token.as_deref(),
+        );
+        check_destinations(
+            Target::Webhook,
+            config.webhook.enabled,
+            "urls",
+            config.webhook.urls.len(),
+        );
+
         println!("{config:?}");
 
         config
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch_scripts() {
+        // BIP84 test vector
+        let watch = ConfigFileWatch {
+            label: None,
+            address: Some(String::from("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu")),
+            descriptor: Some(String::from("wpkh(xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V/<0;1>/*)")),
+            range: Some(10),
+        };
+        let scripts = watch_scripts(&watch, Network::Bitcoin);
+
+        // The address is the first one derived from the descriptor
+        assert_eq!(scripts.len(), 20);
+
+        let change = Address::from_str("bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el")
+            .unwrap()
+            .assume_checked();
+        assert!(scripts.contains(&change.script_pubkey()));
+    }
+
+    #[test]
+    fn test_supply_milestone() {
+        assert_eq!(supply_milestone(21_000_000), Amount::MAX_MONEY);
+        assert!(std::panic::catch_unwind(|| supply_milestone(21_000_001)).is_err());
+        assert!(std::panic::catch_unwind(|| supply_milestone(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_check_destinations() {
+        check_destinations(Target::Telegram, true, "chat_ids", 1);
+        check_destinations(Target::Telegram, false, "chat_ids", 0);
+        assert!(std::panic::catch_unwind(|| {
+            check_destinations(Target::Telegram, true, "chat_ids", 0)
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_required() {
+        check_required(Target::Telegram, true, "bot_token", Some("123:abc"));
+        check_required(Target::Telegram, false, "bot_token", None);
+        assert!(std::panic::catch_unwind(|| {
+            check_required(Target::Telegram, true, "bot_token", None)
+        })
+        .is_err());
+        assert!(std::panic::catch_unwind(|| {
+            check_required(Target::Telegram, true, "bot_token", Some(" "))
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_ntfy_instances() {
+        use serde::Deserialize;
+
+        use self::model::OneOrMany;
+
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            ntfy: OneOrMany<ConfigFileNtfy>,
+        }
+
+        let file: File = toml::from_str("[ntfy]\nenabled = true").unwrap();
+        let ntfy: Vec<Ntfy> = file
+            .ntfy
+            .into_vec()
+            .into_iter()
+            .map(|ntfy| ntfy_instance(ntfy, Locale::En))
+            .collect();
+        assert_eq!(ntfy.len(), 1);
+        assert_eq!(ntfy[0].instance, "ntfy");
+
+        let file: File =
+            toml::from_str("[[ntfy]]\nenabled = true\n\n[[ntfy]]\ninstance = \"ops\"").unwrap();
+        let ntfy: Vec<Ntfy> = file
+            .ntfy
+            .into_vec()
+            .into_iter()
+            .map(|ntfy| ntfy_instance(ntfy, Locale::En))
+            .collect();
+        assert_eq!(ntfy.len(), 2);
+        assert_eq!(ntfy[1].instance, "ops");
+        assert!(!ntfy[1].enabled);
+
+        let file: File = toml::from_str("").unwrap();
+        assert!(file.ntfy.into_vec().is_empty());
+    }
+
+    #[test]
+    fn test_route_matches() {
+        use crate::primitives::{Alert, AlertKind, Severity};
+
+        let route = Route {
+            kinds: vec![AlertKind::Halving, AlertKind::Reorg],
+            severity: None,
+            targets: vec![String::from("ntfy")],
+            priority: None,
+        };
+        assert!(route.matches(&Alert::new(AlertKind::Reorg, 1)));
+        assert!(!route.matches(&Alert::new(AlertKind::Block, 1)));
+
+        let route = Route {
+            kinds: Vec::new(),
+            severity: Some(Severity::Warning),
+            targets: vec![String::from("ntfy")],
+            priority: None,
+        };
+        assert!(route.matches(&Alert::new(AlertKind::Reorg, 1)));
+        assert!(route.matches(&Alert::new(AlertKind::HighFee, 1)));
+        assert!(!route.matches(&Alert::new(AlertKind::Block, 1)));
+
+        let route = Route {
+            kinds: Vec::new(),
+            severity: None,
+            targets: vec![String::from("nostr"), String::from("ops")],
+            priority: None,
+        };
+        assert!(route.routes_to(Target::Nostr, "nostr"));
+        assert!(route.routes_to(Target::Nostr, "testnet"));
+        assert!(route.routes_to(Target::Ntfy, "ops"));
+        assert!(!route.routes_to(Target::Ntfy, "ntfy"));
+    }
+}
@@ -1,22 +1,56 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use bitcoin::network::Network;
+use bitcoin::{Amount, ScriptBuf, Txid};
+use nostr_sdk::nips::nip46::NostrConnectURI;
 use nostr_sdk::{Keys, RelayUrl, SecretKey, Url};
 use ntfy::Auth;
 use tracing::Level;
 
+use crate::locale::Locale;
+use crate::primitives::{Alert, AlertKind, Priority, Severity, Target};
+use crate::template::Templates;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZmqTopic {
+    HashBlock,
+    RawBlock,
+}
+
+impl ZmqTopic {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::HashBlock => "hashblock",
+            Self::RawBlock => "rawblock",
+        }
+    }
+}
+
+impl fmt::Display for ZmqTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Zmq {
+    pub endpoint: String,
+    pub topic: ZmqTopic,
+}
+
 #[derive(Clone)]
 pub struct Bitcoin {
     pub network: Network,
     pub rpc_addr: SocketAddr,
     pub rpc_username: String,
     pub rpc_password: String,
+    pub zmq: Option<Zmq>,
     pub db_path: PathBuf,
 }
 
@@ -26,31 +60,468 @@ pub struct ConfigFileBitcoin {
     pub rpc_addr: Option<SocketAddr>,
     pub rpc_username: String,
     pub rpc_password: String,
+    pub zmqpubhashblock: Option<String>,
+    pub zmqpubrawblock: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Supply {
+    pub enabled: bool,
+    pub milestones: Vec<Amount>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileSupply {
+    pub enabled: Option<bool>,
+    pub milestones: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HalvingRule {
+    pub enabled: bool,
+    /// Count down the blocks to the next halving
+    pub countdown: bool,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileHalvingRule {
+    pub enabled: Option<bool>,
+    pub countdown: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DifficultyRule {
+    pub enabled: bool,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileDifficultyRule {
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HashrateRule {
+    pub enabled: bool,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileHashrateRule {
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockRule {
+    pub enabled: bool,
+    /// Notify blocks divisible by 10^round_magnitude
+    pub round_magnitude: u32,
+    pub palindrome: bool,
+    pub every_block: bool,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileBlockRule {
+    pub enabled: Option<bool>,
+    pub round_magnitude: Option<u32>,
+    pub palindrome: Option<bool>,
+    pub every_block: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rules {
+    pub halving: HalvingRule,
+    pub difficulty: DifficultyRule,
+    pub hashrate: HashrateRule,
+    pub block: BlockRule,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileRules {
+    #[serde(default)]
+    pub halving: ConfigFileHalvingRule,
+    #[serde(default)]
+    pub difficulty: ConfigFileDifficultyRule,
+    #[serde(default)]
+    pub hashrate: ConfigFileHashrateRule,
+    #[serde(default)]
+    pub block: ConfigFileBlockRule,
+}
+
+/// Send the matching alerts only to some targets
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Empty to match any kind
+    pub kinds: Vec<AlertKind>,
+    /// Minimum severity
+    pub severity: Option<Severity>,
+    /// Target types (all their instances) or instance names
+    pub targets: Vec<String>,
+    pub priority: Option<Priority>,
+}
+
+impl Route {
+    pub fn matches(&self, alert: &Alert) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&alert.kind))
+            && self
+                .severity
+                .is_none_or(|severity| alert.kind.severity() >= severity)
+    }
+
+    pub fn routes_to(&self, target: Target, instance: &str) -> bool {
+        self.targets
+            .iter()
+            .any(|t| t == instance || t == target.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfigFileRoute {
+    pub kinds: Option<Vec<AlertKind>>,
+    pub severity: Option<Severity>,
+    pub targets: Vec<String>,
+    pub priority: Option<Priority>,
+}
+
+/// Either a single `[table]` or an array of `[[table]]`
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        Self::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(value) => vec![value],
+            Self::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Watch {
+    pub label: String,
+    pub scripts: HashSet<ScriptBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigFileWatch {
+    pub label: Option<String>,
+    pub address: Option<String>,
+    pub descriptor: Option<String>,
+    pub range: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    pub txids: Vec<Txid>,
+    /// Confirmation milestones, sorted
+    pub confirmations: Vec<u32>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileTracker {
+    pub txids: Option<Vec<Txid>>,
+    pub confirmations: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fees {
+    pub enabled: bool,
+    pub low_fee_rate: Option<f64>,
+    pub high_median_fee_rate: Option<f64>,
+    pub mempool_purging: bool,
+    pub hysteresis: f64,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileFees {
+    pub enabled: Option<bool>,
+    pub low_fee_rate: Option<f64>,
+    pub high_median_fee_rate: Option<f64>,
+    pub mempool_purging: Option<bool>,
+    pub hysteresis: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub max_attempts: u32,
+    /// Secs
+    pub max_age: u64,
+    /// Secs
+    pub max_backoff: u64,
+    /// Failed attempts after which a notification stops delaying the following ones
+    pub max_blocking_attempts: u32,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileDelivery {
+    pub max_attempts: Option<u32>,
+    pub max_age: Option<u64>,
+    pub max_backoff: Option<u64>,
+    pub max_blocking_attempts: Option<u32>,
 }
 
 #[derive(Clone)]
 pub struct Ntfy {
+    /// Instance name
+    pub instance: String,
     pub enabled: bool,
+    pub locale: Locale,
     pub url: String,
     pub topic: String,
     pub auth: Option<Auth>,
     pub proxy: Option<String>,
+    /// Override the priority of the alert kinds
+    pub priorities: BTreeMap<AlertKind, Priority>,
+    /// Override the tags of the alert kinds
+    pub tags: BTreeMap<AlertKind, Vec<String>>,
+    /// Block explorer, for the click URL and the action buttons
+    pub explorer_url: Option<Url>,
+    pub markdown: bool,
 }
 
 #[derive(Deserialize)]
 pub struct ConfigFileNtfy {
+    pub instance: Option<String>,
     pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
     pub url: Option<String>,
     pub topic: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
     pub proxy: Option<String>,
+    pub priorities: Option<BTreeMap<AlertKind, Priority>>,
+    pub tags: Option<BTreeMap<AlertKind, Vec<String>>>,
+    pub explorer_url: Option<Url>,
+    pub markdown: Option<bool>,
+}
+
+#[derive(Clone)]
+pub enum MatrixAuth {
+    AccessToken(String),
+    Password { user: String, password: String },
+}
+
+#[derive(Clone)]
+pub struct Matrix {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub homeserver: Url,
+    pub auth: Option<MatrixAuth>,
+    pub rooms: Vec<String>,
+    pub proxy: Option<String>,
+    /// Access token and device of the password login, reused after a restart
+    pub session_file: PathBuf,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileMatrix {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub homeserver: Option<Url>,
+    pub access_token: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub rooms: Option<Vec<String>>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Discord {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub webhook_urls: Vec<Url>,
+    pub username: Option<String>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileDiscord {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub webhook_urls: Option<Vec<Url>>,
+    pub username: Option<String>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Slack {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub webhook_urls: Vec<Url>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileSlack {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub webhook_urls: Option<Vec<Url>>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade the plain connection with `STARTTLS`
+    StartTls,
+    /// TLS from the start (SMTPS)
+    Implicit,
+    /// Unencrypted, only for local relays
+    None,
+}
+
+impl SmtpTls {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::StartTls => 587,
+            Self::Implicit => 465,
+            Self::None => 25,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Email {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Subject template: `{kind}`, `{block_height}` and `{plain_text}` are replaced
+    pub subject: String,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileEmail {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub tls: Option<SmtpTls>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<Vec<String>>,
+    pub subject: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Mqtt {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Root of the published topics
+    pub topic_prefix: String,
+    /// Home Assistant MQTT discovery prefix, `None` to disable discovery
+    pub discovery_prefix: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileMqtt {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub discovery: Option<bool>,
+    pub discovery_prefix: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Telegram {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub api_url: Url,
+    pub bot_token: Option<String>,
+    pub chat_ids: Vec<String>,
+    /// Forum topic where to send the messages
+    pub message_thread_id: Option<i64>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileTelegram {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub api_url: Option<Url>,
+    pub bot_token: Option<String>,
+    pub chat_ids: Option<Vec<String>>,
+    pub message_thread_id: Option<i64>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Webhook {
+    pub enabled: bool,
+    pub locale: Locale,
+    pub urls: Vec<Url>,
+    pub headers: BTreeMap<String, String>,
+    /// Key of the HMAC-SHA256 signature of the body
+    pub secret: Option<String>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct ConfigFileWebhook {
+    pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
+    pub urls: Option<Vec<Url>>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub secret: Option<String>,
+    pub proxy: Option<String>,
+}
+
+/// Signer of the nostr events
+#[derive(Clone)]
+pub enum Signer {
+    Keys(Keys),
+    /// NIP-46 remote signer. The keys of the client are stored in `app_key_file`, so that the
+    /// bunker doesn't have to authorize it again after a restart.
+    Bunker {
+        uri: NostrConnectURI,
+        app_key_file: PathBuf,
+    },
+}
+
+impl fmt::Display for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Keys(keys) => write!(f, "{}", keys.public_key()),
+            Self::Bunker { uri, .. } => match uri.remote_signer_public_key() {
+                Some(public_key) => write!(f, "bunker {public_key}"),
+                None => write!(f, "bunker"),
+            },
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Nostr {
+    /// Instance name
+    pub instance: String,
     pub enabled: bool,
-    pub keys: Option<Keys>,
+    pub locale: Locale,
+    pub signer: Option<Signer>,
+    /// Profile name
     pub name: String,
     pub display_name: String,
     pub description: String,
@@ -59,12 +530,30 @@ pub struct Nostr {
     pub lud16: String,
     pub relays: HashSet<RelayUrl>,
     pub pow_difficulty: u8,
+    /// Accept subscription commands by direct message and send the matching alerts privately
+    pub dm_subscriptions: bool,
+    /// Expiration (NIP-40) of the notes, in secs, by alert kind
+    pub expiration: BTreeMap<AlertKind, u64>,
+    /// Relays that must acknowledge a note before the notification is considered delivered
+    pub min_relay_acks: usize,
+    /// Authenticate (NIP-42) to the relays requiring it
+    pub nip42_auth: bool,
+    /// Publish the chain state as application-specific data events (NIP-78)
+    pub state_events: bool,
+    /// Prometheus text file with the delivery stats of the relays
+    pub metrics_file: Option<PathBuf>,
 }
 
 #[derive(Deserialize)]
 pub struct ConfigFileNostr {
+    pub instance: Option<String>,
     pub enabled: Option<bool>,
+    pub locale: Option<Locale>,
     pub secret_key: Option<SecretKey>,
+    /// File containing the secret key, only accessible by its owner
+    pub key_file: Option<PathBuf>,
+    /// NIP-46 remote signer (`bunker://...`)
+    pub bunker_uri: Option<String>,
     pub name: Option<String>,
     pub display_name: Option<String>,
     pub description: Option<String>,
@@ -73,6 +562,12 @@ pub struct ConfigFileNostr {
     pub lud16: Option<String>,
     pub relays: Option<HashSet<RelayUrl>>,
     pub pow_difficulty: Option<u8>,
+    pub dm_subscriptions: Option<bool>,
+    pub expiration: Option<BTreeMap<AlertKind, u64>>,
+    pub min_relay_acks: Option<usize>,
+    pub nip42_auth: Option<bool>,
+    pub state_events: Option<bool>,
+    pub metrics_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -80,8 +575,23 @@ pub struct Config {
     pub main_path: PathBuf,
     pub log_level: Level,
     pub bitcoin: Bitcoin,
-    pub ntfy: Ntfy,
-    pub nostr: Nostr,
+    pub rules: Rules,
+    pub supply: Supply,
+    pub fees: Fees,
+    pub watch: Vec<Watch>,
+    pub tracker: Tracker,
+    pub delivery: Delivery,
+    pub routes: Vec<Route>,
+    pub templates: Templates,
+    pub ntfy: Vec<Ntfy>,
+    pub nostr: Vec<Nostr>,
+    pub matrix: Matrix,
+    pub discord: Discord,
+    pub slack: Slack,
+    pub email: Email,
+    pub mqtt: Mqtt,
+    pub telegram: Telegram,
+    pub webhook: Webhook,
 }
 
 #[derive(Deserialize)]
@@ -89,16 +599,60 @@ pub struct ConfigFile {
     pub main_path: Option<PathBuf>,
     pub log_level: Option<String>,
     pub bitcoin: ConfigFileBitcoin,
-    pub ntfy: ConfigFileNtfy,
-    pub nostr: ConfigFileNostr,
+    #[serde(default)]
+    pub rules: ConfigFileRules,
+    #[serde(default)]
+    pub supply: ConfigFileSupply,
+    #[serde(default)]
+    pub fees: ConfigFileFees,
+    #[serde(default)]
+    pub watch: Vec<ConfigFileWatch>,
+    #[serde(default)]
+    pub tracker: ConfigFileTracker,
+    #[serde(default)]
+    pub delivery: ConfigFileDelivery,
+    #[serde(default)]
+    pub route: Vec<ConfigFileRoute>,
+    /// Templates file
+    pub templates: Option<PathBuf>,
+    pub locale: Option<Locale>,
+    #[serde(default)]
+    pub ntfy: OneOrMany<ConfigFileNtfy>,
+    #[serde(default)]
+    pub nostr: OneOrMany<ConfigFileNostr>,
+    #[serde(default)]
+    pub matrix: ConfigFileMatrix,
+    #[serde(default)]
+    pub discord: ConfigFileDiscord,
+    #[serde(default)]
+    pub slack: ConfigFileSlack,
+    #[serde(default)]
+    pub email: ConfigFileEmail,
+    #[serde(default)]
+    pub mqtt: ConfigFileMqtt,
+    #[serde(default)]
+    pub telegram: ConfigFileTelegram,
+    #[serde(default)]
+    pub webhook: ConfigFileWebhook,
 }
 
 impl fmt::Debug for Bitcoin {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ network: {}, rpc_addr: {:?}, rpc_username: {} }}",
-            self.network, self.rpc_addr, self.rpc_username
+            "{{ network: {}, rpc_addr: {:?}, rpc_username: {}, zmq: {:?} }}",
+            self.network, self.rpc_addr, self.rpc_username, self.zmq
+        )
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ label: {}, scripts: {} }}",
+            self.label,
+            self.scripts.len()
         )
     }
 }
@@ -107,12 +661,18 @@ impl fmt::Debug for Ntfy {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ enabled: {}, url: {:?}, topic: {}, credentials: {}, proxy: {:?} }}",
+            "{{ instance: {}, enabled: {}, locale: {}, url: {:?}, topic: {}, credentials: {}, proxy: {:?}, priorities: {:?}, tags: {:?}, explorer_url: {:?}, markdown: {} }}",
+            self.instance,
             self.enabled,
+            self.locale,
             self.url,
             self.topic,
             self.auth.is_some(),
-            self.proxy
+            self.proxy,
+            self.priorities,
+            self.tags,
+            self.explorer_url.as_ref().map(|url| url.as_str()),
+            self.markdown
         )
     }
 }
@@ -121,14 +681,133 @@ impl fmt::Debug for Nostr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ enabled: {}, relays: [{}], pow_difficulty: {} }}",
+            "{{ instance: {}, enabled: {}, locale: {}, signer: {:?}, relays: [{}], pow_difficulty: {}, dm_subscriptions: {}, expiration: {:?}, min_relay_acks: {}, nip42_auth: {}, state_events: {}, metrics_file: {:?} }}",
+            self.instance,
             self.enabled,
+            self.locale,
+            self.signer.as_ref().map(|signer| signer.to_string()),
             self.relays
                 .iter()
                 .map(|u| u.as_str())
                 .collect::<Vec<_>>()
                 .join(","),
-            self.pow_difficulty
+            self.pow_difficulty,
+            self.dm_subscriptions,
+            self.expiration,
+            self.min_relay_acks,
+            self.nip42_auth,
+            self.state_events,
+            self.metrics_file
+        )
+    }
+}
+
+impl fmt::Debug for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, homeserver: {}, credentials: {}, rooms: [{}], proxy: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.homeserver,
+            self.auth.is_some(),
+            self.rooms.join(","),
+            self.proxy
+        )
+    }
+}
+
+impl fmt::Debug for Discord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, webhook_urls: {}, username: {:?}, proxy: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.webhook_urls.len(),
+            self.username,
+            self.proxy
+        )
+    }
+}
+
+impl fmt::Debug for Slack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, webhook_urls: {}, proxy: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.webhook_urls.len(),
+            self.proxy
+        )
+    }
+}
+
+impl fmt::Debug for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, smtp: {}:{}, tls: {:?}, username: {:?}, password: {}, from: {}, to: [{}], subject: {} }}",
+            self.enabled,
+            self.locale,
+            self.smtp_host,
+            self.smtp_port,
+            self.tls,
+            self.username,
+            self.password.is_some(),
+            self.from,
+            self.to.join(","),
+            self.subject
+        )
+    }
+}
+
+impl fmt::Debug for Mqtt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, broker: {}:{}, client_id: {}, username: {:?}, password: {}, topic_prefix: {}, discovery_prefix: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.host,
+            self.port,
+            self.client_id,
+            self.username,
+            self.password.is_some(),
+            self.topic_prefix,
+            self.discovery_prefix
+        )
+    }
+}
+
+impl fmt::Debug for Telegram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, api_url: {}, bot_token: {}, chat_ids: [{}], message_thread_id: {:?}, proxy: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.api_url,
+            self.bot_token.is_some(),
+            self.chat_ids.join(","),
+            self.message_thread_id,
+            self.proxy
+        )
+    }
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{{ enabled: {}, locale: {}, urls: {}, headers: {:?}, secret: {}, proxy: {:?} }}",
+            self.enabled,
+            self.locale,
+            self.urls.len(),
+            self.headers.keys().collect::<Vec<_>>(),
+            self.secret.is_some(),
+            self.proxy
         )
     }
 }
@@ -1,31 +1,130 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 
-use crate::db::rocks::{BoundColumnFamily, Error, Store};
+use bitcoin::{Amount, BlockHash, OutPoint};
+
+use crate::db::rocks::{BoundColumnFamily, Error, Store, WriteBatch};
 use crate::util;
 
+/// Output paying a watched address or descriptor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchedUtxo {
+    pub label: String,
+    /// Value in sat
+    pub value: u64,
+    pub height: u64,
+    pub spent_height: Option<u64>,
+}
+
+/// Rule state changed by a block, by `<rule>/<key>`
+///
+/// Only written, together with the block, once the whole block has been processed:
+/// a block retried after a failure is checked against the same state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateUpdates(BTreeMap<String, String>);
+
+impl StateUpdates {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|value| value.as_str())
+    }
+
+    pub fn set<S>(&mut self, key: String, value: S)
+    where
+        S: ToString,
+    {
+        self.0.insert(key, value.to_string());
+    }
+}
+
 pub struct BitcoinStore {
     pub db: Store,
 }
 
 const NETWORK_CF: &str = "network";
+const BLOCK_HASH_CF: &str = "block_hash";
+const WATCH_UTXO_CF: &str = "watch_utxo";
+/// Spent watched outputs, by spent height: `<height>:<outpoint>`
+const WATCH_SPENT_CF: &str = "watch_spent";
+/// State of the alert rules, by `<rule>/<key>`
+const RULE_STATE_CF: &str = "rule_state";
+
+const COLUMN_FAMILIES: &[&str] = &[
+    NETWORK_CF,
+    BLOCK_HASH_CF,
+    WATCH_UTXO_CF,
+    WATCH_SPENT_CF,
+    RULE_STATE_CF,
+];
 
-const COLUMN_FAMILIES: &[&str] = &[NETWORK_CF];
+/// Rule state saved in the network column family, before it was keyed by rule
+const LEGACY_RULE_STATE: &[(&str, &str)] = &[
+    ("last_difficulty", "difficulty/last_difficulty"),
+    ("last_hashrate_ath", "hashrate/last_hashrate_ath"),
+];
+
+fn watch_spent_key(spent_height: u64, outpoint: &str) -> String {
+    format!("{spent_height:012}:{outpoint}")
+}
 
 impl BitcoinStore {
     pub fn open(path: &Path) -> Result<Self, Error> {
-        Ok(Self {
+        let store = Self {
             db: Store::open(path, COLUMN_FAMILIES)?,
-        })
+        };
+        store.migrate_rule_state()?;
+        Ok(store)
+    }
+
+    /// Move the legacy rule state to the rule state column family
+    fn migrate_rule_state(&self) -> Result<(), Error> {
+        let network_cf = self.network_cf();
+        let mut batch = WriteBatch::default();
+        let mut migrated: bool = false;
+
+        for (legacy, key) in LEGACY_RULE_STATE.iter() {
+            match self.db.get(self.network_cf(), legacy) {
+                Ok(value) => {
+                    batch.put_cf(&self.rule_state_cf(), key, value);
+                    batch.delete_cf(&network_cf, legacy);
+                    migrated = true;
+                }
+                Err(Error::ValueNotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if migrated {
+            self.db.write(batch)?;
+        }
+
+        Ok(())
     }
 
     fn network_cf(&self) -> Arc<BoundColumnFamily> {
         self.db.cf_handle(NETWORK_CF)
     }
 
+    fn block_hash_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(BLOCK_HASH_CF)
+    }
+
+    fn watch_utxo_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(WATCH_UTXO_CF)
+    }
+
+    fn watch_spent_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(WATCH_SPENT_CF)
+    }
+
+    fn rule_state_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(RULE_STATE_CF)
+    }
+
     pub fn get_last_processed_block(&self) -> Result<u64, Error> {
         let cf = self.network_cf();
         match self.db.get(cf, "last_processed_block") {
@@ -45,39 +144,83 @@ impl BitcoinStore {
         )
     }
 
-    pub fn get_last_difficulty(&self) -> Result<f64, Error> {
-        match self.db.get(self.network_cf(), "last_difficulty") {
-            Ok(result) => match util::bytes_to_number::<f64>(result) {
-                Some(num) => Ok(num),
+    /// Mark `block_height` as processed and apply the rule state `updates`, atomically
+    pub fn set_processed_block(
+        &self,
+        block_height: u64,
+        hash: &BlockHash,
+        updates: &StateUpdates,
+    ) -> Result<(), Error> {
+        let network_cf = self.network_cf();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            &self.block_hash_cf(),
+            block_height.to_string(),
+            hash.to_string(),
+        );
+        batch.put_cf(
+            &network_cf,
+            "last_processed_block",
+            block_height.to_string(),
+        );
+        let rule_state_cf = self.rule_state_cf();
+        for (key, value) in updates.0.iter() {
+            batch.put_cf(&rule_state_cf, key, value);
+        }
+        self.db.write(batch)
+    }
+
+    pub fn get_block_hash(&self, block_height: u64) -> Result<BlockHash, Error> {
+        let result = self
+            .db
+            .get(self.block_hash_cf(), block_height.to_string())?;
+        let hash: String = String::from_utf8(result).map_err(|_| Error::FailedToDeserialize)?;
+        BlockHash::from_str(&hash).map_err(|_| Error::FailedToDeserialize)
+    }
+
+    pub fn set_block_hash(&self, block_height: u64, hash: &BlockHash) -> Result<(), Error> {
+        self.db.put(
+            self.block_hash_cf(),
+            block_height.to_string(),
+            hash.to_string(),
+        )
+    }
+
+    pub fn delete_block_hash(&self, block_height: u64) -> Result<(), Error> {
+        self.db
+            .delete(&self.block_hash_cf(), block_height.to_string())
+    }
+
+    /// State of a rule, by `<rule>/<key>`
+    pub fn get_rule_state<T>(&self, key: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+    {
+        let result = self.db.get(self.rule_state_cf(), key)?;
+        util::bytes_to_number::<T>(result).ok_or(Error::FailedToDeserialize)
+    }
+
+    pub fn get_last_supply(&self) -> Result<Amount, Error> {
+        match self.db.get(self.network_cf(), "last_supply") {
+            Ok(result) => match util::bytes_to_number::<u64>(result) {
+                Some(num) => Ok(Amount::from_sat(num)),
                 None => Err(Error::FailedToDeserialize),
             },
             Err(error) => Err(error),
         }
     }
 
-    pub fn set_last_difficculty(&self, difficulty: f64) -> Result<(), Error> {
-        self.db
-            .put(self.network_cf(), "last_difficulty", difficulty.to_string())
-    }
-
-    // pub fn get_last_supply(&self) -> Result<f64, Error> {
-    //     match self.db.get(self.network_cf(), "last_supply") {
-    //         Ok(result) => match util::bytes_to_number::<f64>(result) {
-    //             Some(num) => Ok(num),
-    //             None => Err(Error::FailedToDeserialize),
-    //         },
-    //         Err(error) => Err(error),
-    //     }
-    // }
-    //
-    // pub fn set_last_supply(&self, hashrate: f64) -> Result<(), Error> {
-    //     self.db
-    //         .put(self.network_cf(), "last_supply", hashrate.to_string())
-    // }
-
-    pub fn get_last_hashrate_ath(&self) -> Result<f64, Error> {
-        match self.db.get(self.network_cf(), "last_hashrate_ath") {
-            Ok(result) => match util::bytes_to_number::<f64>(result) {
+    pub fn set_last_supply(&self, supply: Amount) -> Result<(), Error> {
+        self.db.put(
+            self.network_cf(),
+            "last_supply",
+            supply.to_sat().to_string(),
+        )
+    }
+
+    pub fn get_last_supply_height(&self) -> Result<u64, Error> {
+        match self.db.get(self.network_cf(), "last_supply_height") {
+            Ok(result) => match util::bytes_to_number::<u64>(result) {
                 Some(num) => Ok(num),
                 None => Err(Error::FailedToDeserialize),
             },
@@ -85,8 +228,238 @@ impl BitcoinStore {
         }
     }
 
-    pub fn set_last_hashrate_ath(&self, hashrate: f64) -> Result<(), Error> {
+    pub fn set_last_supply_height(&self, block_height: u64) -> Result<(), Error> {
+        self.db.put(
+            self.network_cf(),
+            "last_supply_height",
+            block_height.to_string(),
+        )
+    }
+
+    pub fn get_last_supply_milestone(&self) -> Result<Amount, Error> {
+        match self.db.get(self.network_cf(), "last_supply_milestone") {
+            Ok(result) => match util::bytes_to_number::<u64>(result) {
+                Some(num) => Ok(Amount::from_sat(num)),
+                None => Err(Error::FailedToDeserialize),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn set_last_supply_milestone(&self, milestone: Amount) -> Result<(), Error> {
+        self.db.put(
+            self.network_cf(),
+            "last_supply_milestone",
+            milestone.to_sat().to_string(),
+        )
+    }
+
+    pub fn get_fee_alert_active(&self, name: &str) -> Result<bool, Error> {
+        match self.db.get(self.network_cf(), format!("fee_alert:{name}")) {
+            Ok(result) => match util::bytes_to_number::<u8>(result) {
+                Some(num) => Ok(num != 0),
+                None => Err(Error::FailedToDeserialize),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn set_fee_alert_active(&self, name: &str, active: bool) -> Result<(), Error> {
+        self.db.put(
+            self.network_cf(),
+            format!("fee_alert:{name}"),
+            (active as u8).to_string(),
+        )
+    }
+
+    pub fn get_watched_utxo(&self, outpoint: &OutPoint) -> Result<WatchedUtxo, Error> {
+        let result = self.db.get(self.watch_utxo_cf(), outpoint.to_string())?;
+        self.db.deserialize(result)
+    }
+
+    pub fn set_watched_utxo(&self, outpoint: &OutPoint, utxo: &WatchedUtxo) -> Result<(), Error> {
+        let outpoint: String = outpoint.to_string();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&self.watch_utxo_cf(), &outpoint, self.db.serialize(utxo)?);
+        if let Some(spent_height) = utxo.spent_height {
+            batch.put_cf(
+                &self.watch_spent_cf(),
+                watch_spent_key(spent_height, &outpoint),
+                "",
+            );
+        }
+        self.db.write(batch)
+    }
+
+    /// Whether the UTXO set has already been scanned for the watch with this `key`
+    pub fn is_watch_seeded(&self, key: &str) -> bool {
         self.db
-            .put(self.network_cf(), "last_hashrate_ath", hashrate.to_string())
+            .get(self.network_cf(), format!("watch_seeded:{key}"))
+            .is_ok()
+    }
+
+    /// Save the outputs found by a UTXO set scan, and mark the watch with this `key` as seeded
+    pub fn seed_watched_utxos(
+        &self,
+        key: &str,
+        utxos: &[(OutPoint, WatchedUtxo)],
+    ) -> Result<(), Error> {
+        let cf = self.watch_utxo_cf();
+        let mut batch = WriteBatch::default();
+        for (outpoint, utxo) in utxos.iter() {
+            batch.put_cf(&cf, outpoint.to_string(), self.db.serialize(utxo)?);
+        }
+        batch.put_cf(&self.network_cf(), format!("watch_seeded:{key}"), "1");
+        self.db.write(batch)
+    }
+
+    /// Undo the changes of the blocks after `fork_height`
+    pub fn rewind_watched_utxos(&self, fork_height: u64) -> Result<(), Error> {
+        let cf = self.watch_utxo_cf();
+        let spent_cf = self.watch_spent_cf();
+        let mut batch = WriteBatch::default();
+        for (outpoint, mut utxo) in self
+            .db
+            .iterator_str_serialized::<WatchedUtxo>(cf.clone())?
+            .into_iter()
+        {
+            let spent_height: Option<u64> =
+                utxo.spent_height.filter(|height| *height > fork_height);
+            if let Some(spent_height) = spent_height {
+                batch.delete_cf(&spent_cf, watch_spent_key(spent_height, &outpoint));
+            }
+
+            if utxo.height > fork_height {
+                batch.delete_cf(&cf, &outpoint);
+            } else if spent_height.is_some() {
+                utxo.spent_height = None;
+                batch.put_cf(&cf, &outpoint, self.db.serialize(&utxo)?);
+            }
+        }
+        self.db.write(batch)
+    }
+
+    /// Delete the outputs spent at or before `block_height`
+    pub fn prune_spent_watched_utxos(&self, block_height: u64) -> Result<(), Error> {
+        let cf = self.watch_utxo_cf();
+        let spent_cf = self.watch_spent_cf();
+        let mut batch = WriteBatch::default();
+        for key in self
+            .db
+            .keys_before(&spent_cf, format!("{:012}", block_height + 1))
+            .into_iter()
+        {
+            if let Some((_, outpoint)) = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|key| key.split_once(':'))
+            {
+                batch.delete_cf(&cf, outpoint);
+            }
+            batch.delete_cf(&spent_cf, key);
+        }
+        self.db.write(batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    use super::*;
+
+    #[test]
+    fn test_set_processed_block() {
+        let path =
+            std::env::temp_dir().join(format!("bitcoin-alerts-bitcoin-{}", std::process::id()));
+        let store = BitcoinStore::open(&path).unwrap();
+        let hash = BlockHash::all_zeros();
+
+        store
+            .set_processed_block(1, &hash, &StateUpdates::default())
+            .unwrap();
+        assert_eq!(store.get_last_processed_block().unwrap(), 1);
+        assert_eq!(store.get_block_hash(1).unwrap(), hash);
+        assert!(store
+            .get_rule_state::<f64>("difficulty/last_difficulty")
+            .is_err());
+
+        let mut updates = StateUpdates::default();
+        updates.set(String::from("difficulty/last_difficulty"), 90.5);
+        store.set_processed_block(2, &hash, &updates).unwrap();
+        assert_eq!(store.get_last_processed_block().unwrap(), 2);
+        assert_eq!(
+            store
+                .get_rule_state::<f64>("difficulty/last_difficulty")
+                .unwrap(),
+            90.5
+        );
+        assert!(store
+            .get_rule_state::<f64>("hashrate/last_hashrate_ath")
+            .is_err());
+    }
+
+    #[test]
+    fn test_migrate_rule_state() {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin-alerts-bitcoin-migrate-{}",
+            std::process::id()
+        ));
+        let store = BitcoinStore::open(&path).unwrap();
+        store
+            .db
+            .put(store.network_cf(), "last_difficulty", "90.5")
+            .unwrap();
+        store.migrate_rule_state().unwrap();
+
+        assert_eq!(
+            store
+                .get_rule_state::<f64>("difficulty/last_difficulty")
+                .unwrap(),
+            90.5
+        );
+        assert!(store.db.get(store.network_cf(), "last_difficulty").is_err());
+    }
+
+    #[test]
+    fn test_prune_spent_watched_utxos() {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin-alerts-bitcoin-watch-{}",
+            std::process::id()
+        ));
+        let store = BitcoinStore::open(&path).unwrap();
+        let utxo = |height: u64, spent_height: Option<u64>| WatchedUtxo {
+            label: String::from("cold"),
+            value: 1000,
+            height,
+            spent_height,
+        };
+        let outpoint = |vout: u32| OutPoint::new(Txid::all_zeros(), vout);
+
+        store
+            .set_watched_utxo(&outpoint(0), &utxo(1, Some(5)))
+            .unwrap();
+        store
+            .set_watched_utxo(&outpoint(1), &utxo(2, Some(20)))
+            .unwrap();
+        store
+            .set_watched_utxo(&outpoint(2), &utxo(3, None))
+            .unwrap();
+
+        // Unspent by a reorg: not pruned anymore
+        store.rewind_watched_utxos(10).unwrap();
+        assert!(store
+            .get_watched_utxo(&outpoint(1))
+            .unwrap()
+            .spent_height
+            .is_none());
+
+        store.prune_spent_watched_utxos(4).unwrap();
+        assert!(store.get_watched_utxo(&outpoint(0)).is_ok());
+
+        store.prune_spent_watched_utxos(100).unwrap();
+        assert!(store.get_watched_utxo(&outpoint(0)).is_err());
+        assert!(store.get_watched_utxo(&outpoint(1)).is_ok());
+        assert!(store.get_watched_utxo(&outpoint(2)).is_ok());
     }
 }
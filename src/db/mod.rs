@@ -1,6 +1,8 @@
 mod bitcoin;
 mod notification;
 pub mod rocks;
+mod subscription;
 
-pub use self::bitcoin::BitcoinStore;
-pub use self::notification::NotificationStore;
+pub use self::bitcoin::{BitcoinStore, StateUpdates, WatchedUtxo};
+pub use self::notification::{Notification, NotificationStore, Recipient, TrackedTx};
+pub use self::subscription::{Subscription, SubscriptionStore};
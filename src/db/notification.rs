@@ -1,19 +1,160 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 
-use crate::db::rocks::{BoundColumnFamily, Error, Store};
-use crate::primitives::Target;
+use bitcoin::{OutPoint, Txid};
+use serde_json::Value;
+
+use crate::db::rocks::{BoundColumnFamily, Error, Store, WriteBatch};
+use crate::primitives::{Alert, AlertKind, Priority, Target};
 use crate::util;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Notification {
     pub target: Target,
+    /// Name of the target instance. Missing for the default instance.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Missing for notifications queued before alerts carried structured data
+    #[serde(default)]
+    pub kind: Option<AlertKind>,
+    #[serde(default)]
+    pub block_height: u64,
+    #[serde(default)]
+    pub values: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub priority: Option<Priority>,
     pub plain_text: String,
     pub html: String,
+    /// Failed delivery attempts
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix timestamp
+    #[serde(default)]
+    pub first_queued_at: u64,
+    /// Unix timestamp
+    #[serde(default)]
+    pub next_attempt_at: u64,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Labels of the destinations that already received the notification
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub delivered: BTreeSet<String>,
+    /// Nostr user receiving the notification by direct message, instead of the public feed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<Recipient>,
+}
+
+/// Receiver of a direct message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recipient {
+    /// Hex public key
+    pub public_key: String,
+    /// NIP-04 message, instead of NIP-17
+    pub nip04: bool,
+}
+
+impl Notification {
+    /// Notification of an `alert`, not attempted yet
+    pub fn new(
+        target: Target,
+        instance: Option<String>,
+        alert: &Alert,
+        plain_text: &str,
+        html: &str,
+    ) -> Self {
+        Self {
+            target,
+            instance,
+            kind: Some(alert.kind),
+            block_height: alert.block_height,
+            values: alert.values.clone(),
+            priority: alert.priority,
+            plain_text: plain_text.to_string(),
+            html: html.to_string(),
+            attempts: 0,
+            first_queued_at: util::unix_timestamp(),
+            next_attempt_at: 0,
+            last_error: None,
+            delivered: BTreeSet::new(),
+            recipient: None,
+        }
+    }
+
+    /// Name of the target instance
+    pub fn instance(&self) -> String {
+        self.instance
+            .clone()
+            .unwrap_or_else(|| self.target.to_string())
+    }
+}
+
+/// Position of a notification in the queue: notifications are sent in ascending order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sequence {
+    pub block_height: u64,
+    pub index: u32,
+}
+
+impl Sequence {
+    /// Next sequence after `last`. Never goes backward, even if `block_height` does (i.e. reorgs).
+    pub fn next(last: Option<Sequence>, block_height: u64) -> Self {
+        match last {
+            Some(last) if last.block_height >= block_height => Self {
+                block_height: last.block_height,
+                index: last.index + 1,
+            },
+            _ => Self {
+                block_height,
+                index: 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Zero-padded, so that the lexicographic order of the keys is the sequence order
+        write!(f, "{:012}-{:010}", self.block_height, self.index)
+    }
+}
+
+impl FromStr for Sequence {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block_height, index) = s.split_once('-').ok_or(Error::FailedToDeserialize)?;
+        Ok(Self {
+            block_height: block_height
+                .parse()
+                .map_err(|_| Error::FailedToDeserialize)?,
+            index: index.parse().map_err(|_| Error::FailedToDeserialize)?,
+        })
+    }
+}
+
+/// Transaction tracked until it reaches the last confirmation milestone
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TrackedTx {
+    /// Height of the block that confirmed the transaction
+    pub block_height: Option<u64>,
+    /// Last confirmation milestone alerted
+    pub confirmations: u32,
+    /// Inputs spent by the transaction, known once seen in the mempool
+    pub inputs: Vec<OutPoint>,
+    /// Number of outputs, known once seen in the mempool
+    pub outputs: u32,
+    pub in_mempool: bool,
+    /// Last milestone reached or transaction replaced
+    pub done: bool,
+    /// Looked up in the chain, in case it was already confirmed when the tracking started
+    #[serde(default)]
+    pub checked: bool,
 }
 
 #[derive(Clone)]
@@ -22,8 +163,20 @@ pub struct NotificationStore {
 }
 
 const NOTIFICATION_CF: &str = "notification";
+const NOTIFICATION_INDEX_CF: &str = "notification_index";
+const DEAD_LETTER_CF: &str = "dead_letter";
+const TRACKED_TX_CF: &str = "tracked_tx";
+const METADATA_CF: &str = "metadata";
+const THREAD_CF: &str = "thread";
 
-const COLUMN_FAMILIES: &[&str] = &[NOTIFICATION_CF];
+const COLUMN_FAMILIES: &[&str] = &[
+    NOTIFICATION_CF,
+    NOTIFICATION_INDEX_CF,
+    DEAD_LETTER_CF,
+    TRACKED_TX_CF,
+    METADATA_CF,
+    THREAD_CF,
+];
 
 impl NotificationStore {
     pub fn open(path: &Path) -> Result<Self, Error> {
@@ -32,40 +185,418 @@ impl NotificationStore {
         })
     }
 
+    /// Open the store without locking it, i.e. while the daemon is running
+    pub fn open_read_only(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            db: Store::open_read_only(path, COLUMN_FAMILIES)?,
+        })
+    }
+
     fn notification_cf(&self) -> Arc<BoundColumnFamily> {
         self.db.cf_handle(NOTIFICATION_CF)
     }
 
+    fn notification_index_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(NOTIFICATION_INDEX_CF)
+    }
+
+    fn metadata_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(METADATA_CF)
+    }
+
+    fn dead_letter_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(DEAD_LETTER_CF)
+    }
+
+    fn tracked_tx_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(TRACKED_TX_CF)
+    }
+
+    fn thread_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(THREAD_CF)
+    }
+
+    fn get_last_sequence(&self) -> Result<Sequence, Error> {
+        let result = self.db.get(self.metadata_cf(), "last_sequence")?;
+        String::from_utf8(result)
+            .map_err(|_| Error::FailedToDeserialize)?
+            .parse()
+    }
+
+    /// Key of the dedup index
+    fn content_hash(
+        target: Target,
+        instance: &str,
+        recipient: Option<&Recipient>,
+        plain_text: &str,
+        html: &str,
+    ) -> String {
+        // Same hash as before instances, for the default one
+        let content: String = match recipient {
+            Some(recipient) => format!(
+                "{target}:{instance}:{}:{plain_text}:{html}",
+                recipient.public_key
+            ),
+            None if instance == target.as_str() => format!("{target}:{plain_text}:{html}"),
+            None => format!("{target}:{instance}:{plain_text}:{html}"),
+        };
+        util::sha512(content)[..32].to_string()
+    }
+
+    /// Queue a notification after the ones already queued.
+    ///
+    /// Do nothing if an identical notification is still waiting to be sent.
     pub fn create_notification(
         &self,
         target: Target,
+        instance: &str,
+        alert: &Alert,
         plain_text: &str,
         html: &str,
     ) -> Result<(), Error> {
-        let key: &str = &util::sha512(format!("{target}:{plain_text}:{html}"))[..32];
+        self.insert(target, instance, None, alert, plain_text, html)
+    }
+
+    /// Queue a direct message to `recipient`, from the `instance` of nostr
+    pub fn create_direct_message(
+        &self,
+        instance: &str,
+        recipient: &Recipient,
+        alert: &Alert,
+        plain_text: &str,
+    ) -> Result<(), Error> {
+        self.insert(
+            Target::Nostr,
+            instance,
+            Some(recipient),
+            alert,
+            plain_text,
+            plain_text,
+        )
+    }
+
+    fn insert(
+        &self,
+        target: Target,
+        instance: &str,
+        recipient: Option<&Recipient>,
+        alert: &Alert,
+        plain_text: &str,
+        html: &str,
+    ) -> Result<(), Error> {
+        let hash: String = Self::content_hash(target, instance, recipient, plain_text, html);
+
+        if let Ok(key) = self.db.get(self.notification_index_cf(), &hash) {
+            if self.db.get(self.notification_cf(), key).is_ok() {
+                tracing::debug!("Notification already queued");
+                return Ok(());
+            }
+        }
+
+        let sequence: Sequence = Sequence::next(self.get_last_sequence().ok(), alert.block_height);
+        let key: String = sequence.to_string();
         let value: Notification = Notification {
-            target,
-            plain_text: plain_text.to_string(),
-            html: html.to_string(),
+            recipient: recipient.cloned(),
+            ..Notification::new(
+                target,
+                (instance != target.as_str()).then(|| instance.to_string()),
+                alert,
+                plain_text,
+                html,
+            )
         };
 
-        self.db.put_serialized(self.notification_cf(), key, &value)
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&self.notification_cf(), &key, self.db.serialize(&value)?);
+        batch.put_cf(&self.notification_index_cf(), hash, &key);
+        batch.put_cf(&self.metadata_cf(), "last_sequence", key);
+        self.db.write(batch)
     }
 
+    /// Get the notifications of the `instance` of `target`, in creation order
     pub fn get_notifications_by_target(
         &self,
         target: Target,
-    ) -> Result<HashMap<String, Notification>, Error> {
+        instance: &str,
+    ) -> Result<BTreeMap<String, Notification>, Error> {
         let collection = self
             .db
             .iterator_str_serialized::<Notification>(self.notification_cf())?;
         Ok(collection
             .into_iter()
-            .filter(|(_, value)| value.target == target)
+            .filter(|(_, value)| value.target == target && value.instance() == instance)
             .collect())
     }
 
+    /// Get the notifications of the `instance` of `target` that can be sent now, in creation order.
+    ///
+    /// Stop at the first notification waiting for its backoff delay, to not send the
+    /// following ones before it, unless it already failed `max_blocking_attempts` times.
+    /// Direct messages never block the others.
+    pub fn get_due_notifications(
+        &self,
+        target: Target,
+        instance: &str,
+        max_blocking_attempts: u32,
+    ) -> Result<BTreeMap<String, Notification>, Error> {
+        let now: u64 = util::unix_timestamp();
+        let mut due: BTreeMap<String, Notification> = BTreeMap::new();
+
+        for (id, notification) in self.get_notifications_by_target(target, instance)? {
+            if notification.next_attempt_at <= now {
+                due.insert(id, notification);
+            } else if notification.recipient.is_none()
+                && notification.attempts < max_blocking_attempts
+            {
+                break;
+            }
+        }
+
+        Ok(due)
+    }
+
+    /// Record a failed delivery attempt and schedule the next one after `delay` secs
+    pub fn record_failure(
+        &self,
+        id: &str,
+        notification: &mut Notification,
+        error: String,
+        delay: u64,
+    ) -> Result<(), Error> {
+        let now: u64 = util::unix_timestamp();
+
+        // Queued before attempts were tracked
+        if notification.first_queued_at == 0 {
+            notification.first_queued_at = now;
+        }
+
+        notification.attempts += 1;
+        notification.next_attempt_at = now + delay;
+        notification.last_error = Some(error);
+
+        self.db
+            .put_serialized(self.notification_cf(), id, notification)
+    }
+
+    /// Add the deletion of a queued notification, and of its dedup index entry, to `batch`
+    fn delete_queued(&self, batch: &mut WriteBatch, id: &str) {
+        if let Ok(value) = self.db.get(self.notification_cf(), id) {
+            if let Ok(notification) = self.db.deserialize::<Notification>(value) {
+                let hash: String = Self::content_hash(
+                    notification.target,
+                    &notification.instance(),
+                    notification.recipient.as_ref(),
+                    &notification.plain_text,
+                    &notification.html,
+                );
+                batch.delete_cf(&self.notification_index_cf(), hash);
+            }
+        }
+
+        batch.delete_cf(&self.notification_cf(), id);
+    }
+
+    /// Save the progress of a notification still in the queue
+    pub fn update_notification(&self, id: &str, notification: &Notification) -> Result<(), Error> {
+        self.db
+            .put_serialized(self.notification_cf(), id, notification)
+    }
+
     pub fn delete_notification(&self, id: &str) -> Result<(), Error> {
-        self.db.delete(&self.notification_cf(), id)
+        let mut batch = WriteBatch::default();
+        self.delete_queued(&mut batch, id);
+        self.db.write(batch)
+    }
+
+    /// Move a notification out of the queue, to the dead-letter queue
+    pub fn move_to_dead_letter(&self, id: &str, notification: &Notification) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&self.dead_letter_cf(), id, self.db.serialize(notification)?);
+        self.delete_queued(&mut batch, id);
+        self.db.write(batch)
+    }
+
+    pub fn get_dead_letters(&self) -> Result<BTreeMap<String, Notification>, Error> {
+        self.db
+            .iterator_str_serialized::<Notification>(self.dead_letter_cf())
+    }
+
+    /// Move a notification from the dead-letter queue back to the queue, resetting its attempts
+    pub fn requeue_dead_letter(&self, id: &str) -> Result<(), Error> {
+        let mut notification: Notification = self
+            .db
+            .deserialize(self.db.get(self.dead_letter_cf(), id)?)?;
+        notification.attempts = 0;
+        notification.first_queued_at = util::unix_timestamp();
+        notification.next_attempt_at = 0;
+
+        let hash: String = Self::content_hash(
+            notification.target,
+            &notification.instance(),
+            notification.recipient.as_ref(),
+            &notification.plain_text,
+            &notification.html,
+        );
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            &self.notification_cf(),
+            id,
+            self.db.serialize(&notification)?,
+        );
+        batch.put_cf(&self.notification_index_cf(), hash, id);
+        batch.delete_cf(&self.dead_letter_cf(), id);
+        self.db.write(batch)
+    }
+
+    /// Id of the first event of a `thread` of nostr notes
+    pub fn get_thread_root(&self, instance: &str, thread: &str) -> Result<String, Error> {
+        let result = self
+            .db
+            .get(self.thread_cf(), format!("{instance}:{thread}"))?;
+        String::from_utf8(result).map_err(|_| Error::FailedToDeserialize)
+    }
+
+    pub fn set_thread_root(
+        &self,
+        instance: &str,
+        thread: &str,
+        event_id: &str,
+    ) -> Result<(), Error> {
+        self.db
+            .put(self.thread_cf(), format!("{instance}:{thread}"), event_id)
+    }
+
+    /// Start tracking a transaction. Do nothing if it's already tracked.
+    pub fn track_tx(&self, txid: &Txid) -> Result<(), Error> {
+        match self.db.get(self.tracked_tx_cf(), txid.to_string()) {
+            Ok(_) => Ok(()),
+            Err(Error::ValueNotFound) => self.set_tracked_tx(txid, &TrackedTx::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn untrack_tx(&self, txid: &Txid) -> Result<(), Error> {
+        self.db.delete(&self.tracked_tx_cf(), txid.to_string())
+    }
+
+    pub fn set_tracked_tx(&self, txid: &Txid, tx: &TrackedTx) -> Result<(), Error> {
+        self.db
+            .put_serialized(self.tracked_tx_cf(), txid.to_string(), tx)
+    }
+
+    pub fn get_tracked_txs(&self) -> Result<HashMap<Txid, TrackedTx>, Error> {
+        let collection = self
+            .db
+            .iterator_str_serialized::<TrackedTx>(self.tracked_tx_cf())?;
+        Ok(collection
+            .into_iter()
+            .filter_map(|(txid, tx)| Some((Txid::from_str(&txid).ok()?, tx)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_due_notifications() {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin-alerts-notification-{}",
+            std::process::id()
+        ));
+        let store = NotificationStore::open(&path).unwrap();
+
+        for height in [1, 2] {
+            let alert = Alert::new(AlertKind::Block, height);
+            store
+                .create_notification(Target::Ntfy, "ntfy", &alert, &height.to_string(), "")
+                .unwrap();
+        }
+
+        let (id, mut first) = store
+            .get_due_notifications(Target::Ntfy, "ntfy", 3)
+            .unwrap()
+            .pop_first()
+            .unwrap();
+        store
+            .record_failure(&id, &mut first, String::from("error"), 60)
+            .unwrap();
+
+        // The first one is waiting for its retry: the second one waits for it
+        assert!(store
+            .get_due_notifications(Target::Ntfy, "ntfy", 3)
+            .unwrap()
+            .is_empty());
+
+        // Unless it already failed too many times
+        let due = store
+            .get_due_notifications(Target::Ntfy, "ntfy", 1)
+            .unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due.values().next().unwrap().plain_text, "2");
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_direct_messages_dont_block() {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin-alerts-notification-dm-{}",
+            std::process::id()
+        ));
+        let store = NotificationStore::open(&path).unwrap();
+        let alert = Alert::new(AlertKind::Halving, 1);
+        let recipient = Recipient {
+            public_key: String::from("npub"),
+            nip04: false,
+        };
+
+        store
+            .create_direct_message("nostr", &recipient, &alert, "halving")
+            .unwrap();
+        store
+            .create_notification(Target::Nostr, "nostr", &alert, "halving", "halving")
+            .unwrap();
+
+        // Same content, but not the same destination
+        let due = store
+            .get_due_notifications(Target::Nostr, "nostr", 3)
+            .unwrap();
+        assert_eq!(due.len(), 2);
+
+        let (id, mut dm) = due.into_iter().next().unwrap();
+        assert_eq!(dm.recipient, Some(recipient));
+        store
+            .record_failure(&id, &mut dm, String::from("error"), 60)
+            .unwrap();
+
+        let due = store
+            .get_due_notifications(Target::Nostr, "nostr", 3)
+            .unwrap();
+        assert_eq!(due.len(), 1);
+        assert!(due.values().next().unwrap().recipient.is_none());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let first = Sequence::next(None, 800_000);
+        assert_eq!(first.to_string(), "000000800000-0000000000");
+
+        let second = Sequence::next(Some(first), 800_000);
+        assert_eq!(second.index, 1);
+
+        // Reorg: never go backward
+        let third = Sequence::next(Some(second), 799_999);
+        assert!(third > second);
+        assert!(third.to_string() > second.to_string());
+
+        let fourth = Sequence::next(Some(third), 800_001);
+        assert_eq!(fourth.to_string(), "000000800001-0000000000");
+        assert_eq!(fourth.to_string().parse::<Sequence>().unwrap(), fourth);
     }
 }
@@ -1,13 +1,13 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::Path;
 use std::sync::Arc;
 
 pub use rocksdb::{
-    BoundColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle, DBCompressionType,
+    BoundColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle, DBCompressionType, WriteBatch,
 };
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -39,6 +39,13 @@ impl fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// Whether the database is already open in another process
+    pub fn is_locked(&self) -> bool {
+        matches!(self, Self::RocksDb(e) if e.to_string().contains("lock file"))
+    }
+}
+
 impl From<rocksdb::Error> for Error {
     fn from(err: rocksdb::Error) -> Self {
         Error::RocksDb(err)
@@ -69,14 +76,28 @@ impl Store {
         let mut db_opts = default_opts();
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
-        let db = match rocksdb::DB::open_cf_descriptors(
+        let db = rocksdb::DB::open_cf_descriptors(
             &db_opts,
             path,
             Self::create_cf_descriptors(column_families),
-        ) {
-            Ok(data) => data,
-            Err(error) => panic!("{:?}", error),
-        };
+        )?;
+        Self::log_live_files(path, &db);
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Open without taking the lock, to read a database in use by another process
+    pub fn open_read_only(path: &Path, column_families: &[&str]) -> Result<Self, Error> {
+        tracing::debug!("Opening {} (read only)", path.display());
+        let db = rocksdb::DB::open_cf_descriptors_read_only(
+            &default_opts(),
+            path,
+            Self::create_cf_descriptors(column_families),
+            false,
+        )?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    fn log_live_files(path: &Path, db: &rocksdb::DB) {
         match db.live_files() {
             Ok(live_files) => tracing::info!(
                 "{}: {} SST files, {} GB, {} Grows",
@@ -87,7 +108,6 @@ impl Store {
             ),
             Err(_) => tracing::warn!("Impossible to get live files"),
         };
-        Ok(Self { db: Arc::new(db) })
     }
 
     fn create_cf_descriptors(column_families: &[&str]) -> Vec<ColumnFamilyDescriptor> {
@@ -161,11 +181,23 @@ impl Store {
         self.put(cf, key, self.serialize(value)?)
     }
 
+    /// Apply all the puts and deletes of `batch` atomically
+    pub fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        match self.db.write(batch) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                tracing::error!("Impossible to write batch in database: {}", error);
+                Err(Error::FailedToPut)
+            }
+        }
+    }
+
+    /// Iterate all the entries of a column family, in key order
     pub fn iterator(
         &self,
         cf: &Arc<BoundColumnFamily>,
-    ) -> Result<HashMap<Vec<u8>, Vec<u8>>, Error> {
-        let mut collection = HashMap::new();
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Error> {
+        let mut collection = BTreeMap::new();
         let mut iter = self.db.raw_iterator_cf(cf);
         iter.seek_to_first();
         while iter.valid() {
@@ -178,14 +210,33 @@ impl Store {
         }
         Ok(collection)
     }
+
+    /// Keys of a column family lower than `end`, in key order
+    pub fn keys_before<K>(&self, cf: &Arc<BoundColumnFamily>, end: K) -> Vec<Vec<u8>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let mut keys = Vec::new();
+        let mut iter = self.db.raw_iterator_cf(cf);
+        iter.seek_to_first();
+        while iter.valid() {
+            match iter.key() {
+                Some(key) if key < end.as_ref() => keys.push(key.to_vec()),
+                _ => break,
+            }
+            iter.next();
+        }
+        keys
+    }
+
     pub fn iterator_str_serialized<V>(
         &self,
         cf: Arc<BoundColumnFamily>,
-    ) -> Result<HashMap<String, V>, Error>
+    ) -> Result<BTreeMap<String, V>, Error>
     where
         V: DeserializeOwned,
     {
-        let mut collection = HashMap::new();
+        let mut collection = BTreeMap::new();
         for (key, value) in self.iterator(&cf)?.iter() {
             match String::from_utf8(key.to_vec()) {
                 Ok(key) => {
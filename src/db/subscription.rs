@@ -0,0 +1,138 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::db::rocks::{BoundColumnFamily, Error, Store};
+use crate::primitives::AlertKind;
+
+/// Alerts a nostr user subscribed to by direct message
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Subscription {
+    pub kinds: BTreeSet<AlertKind>,
+    /// Alert when the next block fee estimate drops below this fee rate, in sat/vB
+    pub fees_below: Option<f64>,
+    /// Alert when the median fee rate of a block rises above this one, in sat/vB
+    pub fees_above: Option<f64>,
+    /// The last command was a NIP-04 message: reply with the same scheme instead of NIP-17
+    pub nip04: bool,
+}
+
+impl Subscription {
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty() && self.fees_below.is_none() && self.fees_above.is_none()
+    }
+}
+
+#[derive(Clone)]
+pub struct SubscriptionStore {
+    pub db: Store,
+}
+
+const SUBSCRIPTION_CF: &str = "subscription";
+
+const COLUMN_FAMILIES: &[&str] = &[SUBSCRIPTION_CF];
+
+impl SubscriptionStore {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            db: Store::open(path, COLUMN_FAMILIES)?,
+        })
+    }
+
+    fn subscription_cf(&self) -> Arc<BoundColumnFamily> {
+        self.db.cf_handle(SUBSCRIPTION_CF)
+    }
+
+    fn key(instance: &str, public_key: &str) -> String {
+        format!("{instance}:{public_key}")
+    }
+
+    /// Subscription of `public_key` to the `instance` of nostr. Empty if there isn't one.
+    pub fn get_subscription(&self, instance: &str, public_key: &str) -> Subscription {
+        self.db
+            .get(self.subscription_cf(), Self::key(instance, public_key))
+            .and_then(|value| self.db.deserialize(value))
+            .unwrap_or_default()
+    }
+
+    /// Save the subscription of `public_key`, deleting it if empty
+    pub fn set_subscription(
+        &self,
+        instance: &str,
+        public_key: &str,
+        subscription: &Subscription,
+    ) -> Result<(), Error> {
+        let key: String = Self::key(instance, public_key);
+        if subscription.is_empty() {
+            self.db.delete(&self.subscription_cf(), key)
+        } else {
+            self.db
+                .put_serialized(self.subscription_cf(), key, subscription)
+        }
+    }
+
+    /// Subscriptions to the `instance` of nostr, by public key
+    pub fn get_subscriptions(
+        &self,
+        instance: &str,
+    ) -> Result<BTreeMap<String, Subscription>, Error> {
+        let prefix: String = format!("{instance}:");
+        Ok(self
+            .db
+            .iterator_str_serialized::<Subscription>(self.subscription_cf())?
+            .into_iter()
+            .filter_map(|(key, subscription)| {
+                key.strip_prefix(&prefix)
+                    .map(|public_key| (public_key.to_string(), subscription))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions() {
+        let path = std::env::temp_dir().join(format!(
+            "bitcoin-alerts-subscription-{}",
+            std::process::id()
+        ));
+        let store = SubscriptionStore::open(&path).unwrap();
+        let subscription = Subscription {
+            kinds: BTreeSet::from([AlertKind::Halving]),
+            fees_below: Some(5.0),
+            ..Default::default()
+        };
+
+        store
+            .set_subscription("nostr", "alice", &subscription)
+            .unwrap();
+        store
+            .set_subscription("other", "bob", &subscription)
+            .unwrap();
+        assert_eq!(store.get_subscription("nostr", "alice"), subscription);
+        assert_eq!(
+            store
+                .get_subscriptions("nostr")
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![String::from("alice")]
+        );
+
+        // Deleted once empty
+        store
+            .set_subscription("nostr", "alice", &Subscription::default())
+            .unwrap();
+        assert!(store.get_subscriptions("nostr").unwrap().is_empty());
+        assert!(Subscription::default().is_empty());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use nostr_sdk::{Result, Url};
+use reqwest::{Client, Proxy, StatusCode};
+use serde_json::{json, Value};
+use tokio::time;
+
+use super::{alert_color, http_error, process_notifications, retry_after, url_label, RateLimited};
+use crate::config::model::Discord;
+use crate::config::Config;
+use crate::db::{Notification, NotificationStore};
+use crate::locale::Locale;
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+/// Max number of fields in an embed
+const MAX_FIELDS: usize = 25;
+
+fn embed(notification: &Notification, locale: Locale) -> Value {
+    let fields: Vec<Value> = notification
+        .values
+        .iter()
+        .take(MAX_FIELDS)
+        .map(|(name, value)| {
+            json!({
+                "name": name.replace('_', " "),
+                "value": match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                },
+                "inline": true,
+            })
+        })
+        .collect();
+
+    json!({
+        "description": notification.plain_text,
+        "color": alert_color(notification.kind),
+        "fields": fields,
+        "footer": {
+            "text": locale.block(notification.block_height),
+        },
+    })
+}
+
+struct DiscordClient {
+    client: Client,
+    username: Option<String>,
+    locale: Locale,
+}
+
+impl DiscordClient {
+    fn new(config: &Discord) -> Result<Self> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            username: config.username.clone(),
+            locale: config.locale,
+        })
+    }
+
+    async fn execute_webhook(&self, url: &Url, notification: &Notification) -> Result<()> {
+        let mut body: Value = json!({
+            "embeds": [embed(notification, self.locale)],
+        });
+
+        if let Some(username) = &self.username {
+            body["username"] = json!(username);
+        }
+
+        // The URL contains the webhook token: keep it out of the errors
+        let res = self
+            .client
+            .post(url.clone())
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        let status: StatusCode = res.status();
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Box::new(RateLimited(retry_after(res.headers()))));
+        }
+
+        if status.is_success() {
+            Ok(())
+        } else {
+            let res: String = res.text().await.unwrap_or_default();
+            Err(http_error(status, &res))
+        }
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.discord.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: DiscordClient = DiscordClient::new(&config.discord)?;
+
+    tracing::info!("Discord Dispatcher started");
+
+    let webhook_urls: Vec<(String, Url)> = config
+        .discord
+        .webhook_urls
+        .iter()
+        .map(|url| (url_label(Target::Discord.as_str(), url), url.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Discord,
+            Target::Discord.as_str(),
+            RETRY_DELAY,
+            &webhook_urls,
+            |url, _, notification| {
+                let client: &DiscordClient = &client;
+                async move { client.execute_webhook(url, &notification).await }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispatcher::mock::{MockServer, Request, Response};
+    use crate::primitives::{Alert, AlertKind};
+
+    fn notification() -> Notification {
+        let alert = Alert::new(AlertKind::HighFee, 840_000).value("fee_rate", 52.5);
+        Notification::new(
+            Target::Discord,
+            None,
+            &alert,
+            "Next block fee rate: 52.5 sat/vB",
+            "",
+        )
+    }
+
+    #[test]
+    fn test_embed() {
+        let embed: Value = embed(&notification(), Locale::Es);
+        assert_eq!(embed["description"], "Next block fee rate: 52.5 sat/vB");
+        assert_eq!(embed["color"], 0xE67E22);
+        assert_eq!(
+            embed["fields"],
+            json!([{ "name": "fee rate", "value": "52.5", "inline": true }])
+        );
+        assert_eq!(embed["footer"]["text"], "Bloque 840.000");
+    }
+
+    #[tokio::test]
+    async fn test_execute_webhook() {
+        let server = MockServer::new();
+        let url: Url = server.url().join("/api/webhooks/1/token").unwrap();
+        let client = DiscordClient::new(&Discord {
+            enabled: true,
+            locale: Locale::En,
+            webhook_urls: vec![url.clone()],
+            username: Some(String::from("Bitcoin Alerts")),
+            proxy: None,
+        })
+        .unwrap();
+        let server = server.serve(vec![
+            Response::new("204 No Content", ""),
+            Response::new(
+                "429 Too Many Requests",
+                r#"{"message":"You are being rate limited.","retry_after":3.5}"#,
+            )
+            .header("Retry-After", "3.5"),
+        ]);
+
+        client.execute_webhook(&url, &notification()).await.unwrap();
+
+        let err = client
+            .execute_webhook(&url, &notification())
+            .await
+            .unwrap_err();
+        let RateLimited(delay) = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(*delay, Duration::from_millis(3500));
+
+        let requests: Vec<Request> = server.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /api/webhooks/1/token HTTP/1.1"
+        );
+        let body: Value = requests[0].json();
+        assert_eq!(body["username"], "Bitcoin Alerts");
+        assert_eq!(body["embeds"][0]["footer"]["text"], "Block 840,000");
+    }
+}
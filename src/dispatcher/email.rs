@@ -0,0 +1,245 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use nostr_sdk::Result;
+use tokio::time;
+
+use super::{process_notifications, Rejected};
+use crate::config::model::{Email, SmtpTls};
+use crate::config::Config;
+use crate::db::{Notification, NotificationStore};
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 60;
+
+/// Fill the subject template
+fn subject(template: &str, notification: &Notification) -> String {
+    template
+        .replace(
+            "{kind}",
+            &notification
+                .kind
+                .map(|kind| kind.to_string())
+                .unwrap_or_default(),
+        )
+        .replace("{block_height}", &notification.block_height.to_string())
+        .replace("{plain_text}", &notification.plain_text)
+}
+
+struct EmailClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject: String,
+}
+
+impl EmailClient {
+    fn new(config: &Email) -> Result<Self> {
+        let mut builder = match config.tls {
+            SmtpTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?
+            }
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)?,
+            SmtpTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+            }
+        }
+        .port(config.smtp_port)
+        .timeout(Some(TIMEOUT));
+
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse()?,
+            to: config
+                .to
+                .iter()
+                .map(|to| to.parse())
+                .collect::<Result<Vec<Mailbox>, _>>()?,
+            subject: config.subject.clone(),
+        })
+    }
+
+    /// Send a multipart plain text + HTML mail
+    async fn send(&self, to: &Mailbox, id: &str, notification: &Notification) -> Result<()> {
+        let html: String = format!(
+            "<!DOCTYPE html><html><body><p>{}</p></body></html>",
+            notification.html
+        );
+        let message: Message = Message::builder()
+            .message_id(Some(format!("<{id}@bitcoin-alerts>")))
+            .from(self.from.clone())
+            .to(to.clone())
+            .subject(subject(&self.subject, notification))
+            .multipart(MultiPart::alternative_plain_html(
+                notification.plain_text.clone(),
+                html,
+            ))
+            .map_err(|e| Rejected(e.to_string()))?;
+
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            // 5xx reply, i.e. unknown recipient or message too big
+            Err(e) if e.is_permanent() => Err(Box::new(Rejected(e.to_string()))),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.email.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: EmailClient = EmailClient::new(&config.email)?;
+
+    tracing::info!("Email Dispatcher started");
+
+    let to: Vec<(String, Mailbox)> = client
+        .to
+        .iter()
+        .map(|to| (to.to_string(), to.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Email,
+            Target::Email.as_str(),
+            RETRY_DELAY,
+            &to,
+            |to, id, notification| {
+                let client: &EmailClient = &client;
+                async move { client.send(to, &id, &notification).await }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::locale::Locale;
+    use crate::primitives::{Alert, AlertKind};
+
+    fn notification() -> Notification {
+        Notification::new(
+            Target::Email,
+            None,
+            &Alert::new(AlertKind::Difficulty, 840_672),
+            "Difficulty adj: 86.39T (+3.92%)",
+            "Difficulty adj: 86.39T (+3.92%) &lt;3",
+        )
+    }
+
+    /// Accept a single SMTP session, returning the received DATA
+    fn smtp_sink(listener: TcpListener) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut data = String::new();
+            let mut in_data: bool = false;
+
+            writer.write_all(b"220 localhost ESMTP sink\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 2.0.0 queued\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                    continue;
+                }
+
+                let command: String = line.get(..4).unwrap_or_default().to_uppercase();
+                let reply: &[u8] = match command.as_str() {
+                    "EHLO" | "HELO" => b"250 localhost\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 end with .\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).unwrap();
+            }
+
+            data
+        })
+    }
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(
+            subject("[{kind}] #{block_height}: {plain_text}", &notification()),
+            "[difficulty] #840672: Difficulty adj: 86.39T (+3.92%)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Email {
+            enabled: true,
+            locale: Locale::En,
+            smtp_host: String::from("127.0.0.1"),
+            smtp_port: listener.local_addr().unwrap().port(),
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: String::from("Bitcoin Alerts <alerts@example.com>"),
+            to: vec![String::from("user@example.com")],
+            subject: String::from("Alert: {kind}"),
+        };
+        let client = EmailClient::new(&config).unwrap();
+        let sink = smtp_sink(listener);
+
+        client
+            .send(&client.to[0], "000000840672-0000000000", &notification())
+            .await
+            .unwrap();
+        drop(client);
+
+        let data: String = sink.join().unwrap();
+        assert!(data.contains("Subject: Alert: difficulty"));
+        assert!(data.contains("To: user@example.com"));
+        assert!(data.contains("multipart/alternative"));
+        assert!(data.contains("Content-Type: text/plain"));
+        assert!(data.contains("Content-Type: text/html"));
+        assert!(data.contains("&lt;3"));
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+
+use nostr_sdk::{Result, Url};
+use reqwest::{Client, Proxy, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tokio::time;
+
+use super::{http_error, process_notifications, retry_after, RateLimited};
+use crate::config::model::{Matrix, MatrixAuth};
+use crate::config::Config;
+use crate::db::NotificationStore;
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+/// Session created by a password login
+///
+/// Reused after a restart, so that the homeserver doesn't register a new device every time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Session {
+    user: String,
+    access_token: String,
+    device_id: String,
+}
+
+impl Session {
+    /// Load the session of `user`, if any
+    fn load(path: &Path, user: &str) -> Option<Self> {
+        let content: String = fs::read_to_string(path).ok()?;
+        match serde_json::from_str::<Self>(&content) {
+            Ok(session) if session.user == user => Some(session),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("Invalid matrix session file: {e}");
+                None
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to a new file
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct MatrixClient {
+    client: Client,
+    config: Matrix,
+    access_token: RwLock<String>,
+}
+
+impl MatrixClient {
+    fn endpoint(homeserver: &Url, segments: &[&str]) -> Result<Url> {
+        let mut url: Url = homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| "Invalid homeserver url")?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Check if the access token is still valid
+    async fn whoami(homeserver: &Url, client: &Client, access_token: &str) -> Result<bool> {
+        let url: Url = Self::endpoint(homeserver, &["account", "whoami"])?;
+        let res = client.get(url).bearer_auth(access_token).send().await?;
+        match res.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::UNAUTHORIZED => Ok(false),
+            status => Err(format!("whoami failed with status {status}").into()),
+        }
+    }
+
+    async fn login(config: &Matrix, client: &Client) -> Result<String> {
+        match &config.auth {
+            Some(MatrixAuth::AccessToken(access_token)) => Ok(access_token.clone()),
+            Some(MatrixAuth::Password { user, password }) => {
+                let session: Option<Session> = Session::load(&config.session_file, user);
+
+                if let Some(session) = &session {
+                    if Self::whoami(&config.homeserver, client, &session.access_token).await? {
+                        tracing::debug!("Reusing matrix session of device {}", session.device_id);
+                        return Ok(session.access_token.clone());
+                    }
+                    tracing::warn!("Matrix session expired, logging in again");
+                }
+
+                let device_id: Option<String> = session.map(|session| session.device_id);
+                Self::password_login(config, client, user, password, device_id).await
+            }
+            None => Err("missing matrix credentials".into()),
+        }
+    }
+
+    /// Log in with the password, to the `device_id` if any, and save the new session
+    async fn password_login(
+        config: &Matrix,
+        client: &Client,
+        user: &str,
+        password: &str,
+        device_id: Option<String>,
+    ) -> Result<String> {
+        let url: Url = Self::endpoint(&config.homeserver, &["login"])?;
+        let mut body: Value = json!({
+            "type": "m.login.password",
+            "identifier": {
+                "type": "m.id.user",
+                "user": user,
+            },
+            "password": password,
+            "initial_device_display_name": "Bitcoin Alerts",
+        });
+
+        // Log in again to the same device, instead of creating a new one
+        if let Some(device_id) = device_id {
+            body["device_id"] = json!(device_id);
+        }
+
+        let res = client.post(url).json(&body).send().await?;
+        let status: StatusCode = res.status();
+        let res: Value = res.json().await?;
+
+        if !status.is_success() {
+            return Err(format!("login failed with status {status}: {res}").into());
+        }
+
+        let access_token: &str = res
+            .get("access_token")
+            .and_then(|t| t.as_str())
+            .ok_or("login response without access token")?;
+        let device_id: &str = res
+            .get("device_id")
+            .and_then(|d| d.as_str())
+            .ok_or("login response without device id")?;
+
+        let session = Session {
+            user: user.to_string(),
+            access_token: access_token.to_string(),
+            device_id: device_id.to_string(),
+        };
+        if let Err(e) = session.save(&config.session_file) {
+            tracing::error!("Impossible to save matrix session: {e}");
+        }
+
+        Ok(session.access_token)
+    }
+
+    /// Log in again, after the homeserver rejected the access token (i.e. revoked or expired)
+    ///
+    /// Returns `false` if the access token comes from the config, so there is no way to renew it.
+    async fn relogin(&self) -> Result<bool> {
+        match &self.config.auth {
+            Some(MatrixAuth::Password { user, password }) => {
+                tracing::warn!("Matrix access token rejected, logging in again");
+                let device_id: Option<String> =
+                    Session::load(&self.config.session_file, user).map(|session| session.device_id);
+                let access_token: String =
+                    Self::password_login(&self.config, &self.client, user, password, device_id)
+                        .await?;
+                *self.access_token.write().await = access_token;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn new(config: &Matrix) -> Result<Self> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        let client: Client = builder.build()?;
+        let access_token: String = Self::login(config, &client).await?;
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+            access_token: RwLock::new(access_token),
+        })
+    }
+
+    async fn put(&self, url: &Url, body: &Value) -> Result<Response> {
+        let access_token: String = self.access_token.read().await.clone();
+        Ok(self
+            .client
+            .put(url.clone())
+            .bearer_auth(access_token)
+            .json(body)
+            .send()
+            .await?)
+    }
+
+    /// Send an `m.room.message` event
+    ///
+    /// The transaction ID makes retries idempotent: the homeserver will not
+    /// duplicate a message already delivered with the same ID.
+    async fn send_message(
+        &self,
+        room_id: &str,
+        txn_id: &str,
+        plain_text: &str,
+        html: &str,
+    ) -> Result<()> {
+        let url: Url = Self::endpoint(
+            &self.config.homeserver,
+            &["rooms", room_id, "send", "m.room.message", txn_id],
+        )?;
+        let body: Value = json!({
+            "msgtype": "m.text",
+            "body": plain_text,
+            "format": "org.matrix.custom.html",
+            "formatted_body": html,
+        });
+
+        let mut res: Response = self.put(&url, &body).await?;
+
+        // `M_UNKNOWN_TOKEN`: retry once with a new access token
+        if res.status() == StatusCode::UNAUTHORIZED && self.relogin().await? {
+            res = self.put(&url, &body).await?;
+        }
+
+        let status: StatusCode = res.status();
+
+        if status.is_success() {
+            return Ok(());
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let header: Duration = retry_after(res.headers());
+            let res: Value = res.json().await.unwrap_or_default();
+            // `M_LIMIT_EXCEEDED`
+            let delay: Duration = res
+                .get("retry_after_ms")
+                .and_then(|ms| ms.as_u64())
+                .map(Duration::from_millis)
+                .unwrap_or(header);
+            return Err(Box::new(RateLimited(delay)));
+        }
+
+        let res: String = res.text().await.unwrap_or_default();
+        Err(http_error(status, &res))
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.matrix.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: MatrixClient = loop {
+        match MatrixClient::new(&config.matrix).await {
+            Ok(client) => break client,
+            Err(e) => {
+                tracing::error!(
+                    "Impossible to login to matrix homeserver: {e} - retrying in 60 sec"
+                );
+                time::sleep(Duration::from_secs(60)).await;
+            }
+        }
+    };
+
+    tracing::info!("Matrix Dispatcher started");
+
+    let rooms: Vec<(String, String)> = config
+        .matrix
+        .rooms
+        .iter()
+        .map(|room_id| (room_id.clone(), room_id.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Matrix,
+            Target::Matrix.as_str(),
+            RETRY_DELAY,
+            &rooms,
+            |room_id, id, notification| {
+                let client: &MatrixClient = &client;
+                async move {
+                    client
+                        .send_message(room_id, &id, &notification.plain_text, &notification.html)
+                        .await
+                }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispatcher::mock::{MockServer, Request, Response};
+    use crate::locale::Locale;
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("matrix-session-{}.json", std::process::id()));
+        let session = Session {
+            user: String::from("alice"),
+            access_token: String::from("token"),
+            device_id: String::from("DEVICE"),
+        };
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        session.save(&path).unwrap();
+
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(Session::load(&path, "alice"), Some(session));
+        assert_eq!(Session::load(&path, "bob"), None);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Session::load(&path, "alice"), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let session_file =
+            std::env::temp_dir().join(format!("matrix-send-{}.json", std::process::id()));
+        let server = MockServer::new();
+        let config = Matrix {
+            enabled: true,
+            locale: Locale::En,
+            homeserver: server.url(),
+            auth: Some(MatrixAuth::Password {
+                user: String::from("alice"),
+                password: String::from("secret"),
+            }),
+            rooms: vec![String::from("!room:example.org")],
+            proxy: None,
+            session_file: session_file.clone(),
+        };
+        let server = server.serve(vec![
+            Response::new(
+                "200 OK",
+                r#"{"access_token":"token1","device_id":"DEVICE"}"#,
+            ),
+            Response::new("200 OK", r#"{"event_id":"$1"}"#),
+            Response::new(
+                "429 Too Many Requests",
+                r#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":2000}"#,
+            ),
+            Response::new(
+                "401 Unauthorized",
+                r#"{"errcode":"M_UNKNOWN_TOKEN","error":"Invalid access token"}"#,
+            ),
+            Response::new(
+                "200 OK",
+                r#"{"access_token":"token2","device_id":"DEVICE"}"#,
+            ),
+            Response::new("200 OK", r#"{"event_id":"$2"}"#),
+        ]);
+
+        let client = MatrixClient::new(&config).await.unwrap();
+        client
+            .send_message("!room:example.org", "1", "text", "<b>text</b>")
+            .await
+            .unwrap();
+
+        let err = client
+            .send_message("!room:example.org", "2", "text", "html")
+            .await
+            .unwrap_err();
+        let RateLimited(delay) = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(*delay, Duration::from_secs(2));
+
+        // Revoked token: log in again to the same device and retry
+        client
+            .send_message("!room:example.org", "2", "text", "html")
+            .await
+            .unwrap();
+
+        let requests: Vec<Request> = server.join().unwrap();
+        assert!(requests[0]
+            .request_line
+            .starts_with("POST /_matrix/client/v3/login "));
+        assert!(requests[1]
+            .request_line
+            .starts_with("PUT /_matrix/client/v3/rooms/!room:example.org/send/m.room.message/1 "));
+        assert_eq!(requests[1].headers["authorization"], "Bearer token1");
+        assert_eq!(requests[1].json()["formatted_body"], "<b>text</b>");
+        assert_eq!(requests[3].headers["authorization"], "Bearer token1");
+        assert_eq!(requests[4].json()["device_id"], "DEVICE");
+        assert!(requests[5].request_line.contains("/send/m.room.message/2 "));
+        assert_eq!(requests[5].headers["authorization"], "Bearer token2");
+
+        assert_eq!(
+            Session::load(&session_file, "alice").unwrap().access_token,
+            "token2"
+        );
+        fs::remove_file(&session_file).unwrap();
+    }
+}
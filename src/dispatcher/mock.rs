@@ -0,0 +1,126 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Mock HTTP server, for the tests of the dispatchers
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use nostr_sdk::Url;
+use serde_json::Value;
+
+/// Request received by the mock server
+pub struct Request {
+    /// i.e. `POST /path HTTP/1.1`
+    pub request_line: String,
+    /// By lowercase name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// Response of the mock server
+pub struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    /// `status` is the status line without the version, i.e. `200 OK`
+    pub fn new<S>(status: &'static str, body: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn header<S>(mut self, name: &'static str, value: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+pub struct MockServer {
+    listener: TcpListener,
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}", self.listener.local_addr().unwrap())).unwrap()
+    }
+
+    /// Answer one request with each of the `responses`, in order, returning the requests
+    pub fn serve(self, responses: Vec<Response>) -> thread::JoinHandle<Vec<Request>> {
+        thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (stream, _) = self.listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+
+                    let mut headers: HashMap<String, String> = HashMap::new();
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        if header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                        }
+                    }
+
+                    let content_length: usize = headers
+                        .get("content-length")
+                        .map(|value| value.parse().unwrap())
+                        .unwrap_or_default();
+                    let mut body = vec![0u8; content_length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let mut stream = reader.into_inner();
+                    write!(stream, "HTTP/1.1 {}\r\n", response.status).unwrap();
+                    for (name, value) in response.headers.iter() {
+                        write!(stream, "{name}: {value}\r\n").unwrap();
+                    }
+                    write!(
+                        stream,
+                        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.body.len(),
+                        response.body
+                    )
+                    .unwrap();
+
+                    Request {
+                        request_line: request_line.trim_end().to_string(),
+                        headers,
+                        body,
+                    }
+                })
+                .collect()
+        })
+    }
+}
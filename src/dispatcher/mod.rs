@@ -1,19 +1,443 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use nostr_sdk::{Result, Url};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio::time;
+
+mod discord;
+mod email;
+mod matrix;
+#[cfg(test)]
+mod mock;
+mod mqtt;
 mod nostr;
 mod ntfy;
+mod slack;
+mod telegram;
+mod webhook;
 
 use crate::config::Config;
-use crate::db::NotificationStore;
+use crate::db::{Notification, NotificationStore, SubscriptionStore};
+use crate::primitives::{AlertKind, ChainState, Target};
+use crate::util;
+
+/// Wait used when a rate-limited response doesn't say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// The target rejected the request with HTTP 429: wait before sending again
+#[derive(Debug)]
+struct RateLimited(Duration);
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited, retry after {:?}", self.0)
+    }
+}
+
+impl Error for RateLimited {}
+
+/// The target rejected the notification: sending it again would fail the same way
+#[derive(Debug)]
+struct Rejected(String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Rejected {}
+
+/// Error of an unsuccessful HTTP response.
+///
+/// Client errors are permanent, except authentication, timeout and rate limit errors.
+fn http_error(status: StatusCode, body: &str) -> Box<dyn Error> {
+    let error: String = format!("status {status}: {body}");
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+            error.into()
+        }
+        status if status.is_client_error() => Box::new(Rejected(error)),
+        _ => error.into(),
+    }
+}
+
+/// Parse the `Retry-After` header (secs)
+fn retry_after(headers: &HeaderMap) -> Duration {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// Label of a URL carrying a secret (i.e. the token of a webhook), to log it.
+///
+/// The instance, followed by a short hash to tell the URLs of an instance apart.
+fn url_label(instance: &str, url: &Url) -> String {
+    format!("{instance}#{}", &util::sha512(url.as_str())[..8])
+}
+
+/// Structured alert, for machine consumers
+fn alert_payload(id: &str, notification: &Notification) -> Value {
+    json!({
+        "id": id,
+        "kind": notification.kind,
+        "block_height": notification.block_height,
+        "values": notification.values,
+        "plain_text": notification.plain_text,
+        "html": notification.html,
+        "timestamp": notification.first_queued_at,
+    })
+}
+
+/// Accent color of an alert, as RGB
+fn alert_color(kind: Option<AlertKind>) -> u32 {
+    match kind {
+        Some(AlertKind::Halving | AlertKind::BlockReward | AlertKind::HalvingCountdown) => 0xF7931A,
+        Some(AlertKind::Difficulty | AlertKind::Hashrate) => 0x3498DB,
+        Some(
+            AlertKind::Block
+            | AlertKind::RoundBlock
+            | AlertKind::PalindromeBlock
+            | AlertKind::Supply
+            | AlertKind::LowFee,
+        ) => 0x2ECC71,
+        Some(AlertKind::HighFee) => 0xE67E22,
+        Some(AlertKind::Reorg | AlertKind::MempoolPurging | AlertKind::TxDropped) => 0xE74C3C,
+        Some(
+            AlertKind::WatchReceived
+            | AlertKind::WatchSpent
+            | AlertKind::TxConfirmed
+            | AlertKind::TxReplaced,
+        ) => 0x9B59B6,
+        None => 0x95A5A6,
+    }
+}
+
+/// Exponential backoff between delivery attempts
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// Secs
+    base: u64,
+    /// Secs
+    max: u64,
+}
+
+impl Backoff {
+    /// Delay before the next attempt, after `attempts` failed attempts
+    fn delay(&self, attempts: u32) -> u64 {
+        let factor: u64 = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// What happens to a failed notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Retry {
+    /// Retried before the following notifications
+    Blocking,
+    /// Retried, but the following notifications don't wait for it
+    NonBlocking,
+    /// Moved to the dead-letter queue
+    DeadLetter,
+}
+
+/// Schedule a new attempt for a failed notification, or move it to the dead-letter queue.
+///
+/// `retry_delay` is the delay, in secs, after the first failed attempt.
+fn delivery_failed(
+    config: &Config,
+    store: &NotificationStore,
+    id: &str,
+    mut notification: Notification,
+    error: String,
+    retry_delay: u64,
+) -> Retry {
+    let backoff = Backoff {
+        base: retry_delay,
+        max: config.delivery.max_backoff,
+    };
+    let delay: u64 = backoff.delay(notification.attempts + 1);
+
+    if let Err(e) = store.record_failure(id, &mut notification, error, delay) {
+        tracing::error!("Impossible to update notification {id}: {e}");
+        return Retry::Blocking;
+    }
+
+    let age: u64 = util::unix_timestamp().saturating_sub(notification.first_queued_at);
+
+    if notification.attempts >= config.delivery.max_attempts || age >= config.delivery.max_age {
+        tracing::warn!(
+            "Notification {id} failed {} times, moving to dead-letter queue",
+            notification.attempts
+        );
+        move_to_dead_letter(store, id, &notification)
+    } else {
+        tracing::debug!(
+            "Notification {id} failed {} times, retrying in {delay} secs",
+            notification.attempts
+        );
+
+        if notification.attempts < config.delivery.max_blocking_attempts {
+            Retry::Blocking
+        } else {
+            Retry::NonBlocking
+        }
+    }
+}
+
+/// Move a notification rejected by the target to the dead-letter queue, without retrying it
+fn delivery_rejected(
+    store: &NotificationStore,
+    id: &str,
+    mut notification: Notification,
+    error: String,
+) -> Retry {
+    if let Err(e) = store.record_failure(id, &mut notification, error, 0) {
+        tracing::error!("Impossible to update notification {id}: {e}");
+        return Retry::Blocking;
+    }
+
+    tracing::warn!("Notification {id} rejected, moving to dead-letter queue");
+    move_to_dead_letter(store, id, &notification)
+}
+
+fn move_to_dead_letter(store: &NotificationStore, id: &str, notification: &Notification) -> Retry {
+    match store.move_to_dead_letter(id, notification) {
+        Ok(()) => Retry::DeadLetter,
+        Err(e) => {
+            tracing::error!("Impossible to move notification {id} to dead-letter queue: {e}");
+            Retry::Blocking
+        }
+    }
+}
+
+/// Send the due notifications of the `instance` of `target` to all the `destinations`, in creation order.
+///
+/// `destinations` are `(label, destination)` pairs: the label is logged instead of the destination,
+/// and recorded in the notification once delivered, so that retries skip it.
+/// Delivered notifications are deleted; on failure, a new attempt is scheduled and the following
+/// notifications wait for it. Notifications rejected by all the failed destinations are moved to
+/// the dead-letter queue right away.
+async fn process_notifications<'a, D, F, Fut>(
+    config: &Config,
+    store: &NotificationStore,
+    target: Target,
+    instance: &str,
+    retry_delay: u64,
+    destinations: &'a [(String, D)],
+    send: F,
+) where
+    F: Fn(&'a D, String, Notification) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    tracing::debug!("Process pending notifications");
+
+    let notifications = match store.get_due_notifications(
+        target,
+        instance,
+        config.delivery.max_blocking_attempts,
+    ) {
+        Ok(result) => result,
+        Err(error) => {
+            tracing::error!("Impossible to get {instance} notifications from db: {error:?}");
+            time::sleep(Duration::from_secs(60)).await;
+            return;
+        }
+    };
+
+    for (id, mut notification) in notifications.into_iter() {
+        let mut errors: Vec<String> = Vec::new();
+        let mut rejected: bool = true;
+
+        for (label, destination) in destinations.iter() {
+            if notification.delivered.contains(label) {
+                continue;
+            }
+
+            match send(destination, id.clone(), notification.clone()).await {
+                Ok(()) => {
+                    notification.delivered.insert(label.clone());
+                }
+                Err(e) => {
+                    if let Some(RateLimited(delay)) = e.downcast_ref::<RateLimited>() {
+                        // Don't let the target stall the queue longer than a failed attempt would
+                        let delay: Duration =
+                            (*delay).min(Duration::from_secs(config.delivery.max_backoff));
+                        tracing::warn!("{instance} rate limit hit, retrying in {delay:?}");
+
+                        if let Err(e) = store.update_notification(&id, &notification) {
+                            tracing::error!("Impossible to update notification {id}: {e}");
+                        }
+
+                        time::sleep(delay).await;
+                        return;
+                    }
+
+                    tracing::error!("Impossible to send notification {id} to {label}: {e}");
+                    rejected &= e.is::<Rejected>();
+                    errors.push(format!("{label}: {e}"));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            let error: String = errors.join(", ");
+            let retry: Retry = if rejected {
+                delivery_rejected(store, &id, notification, error)
+            } else {
+                delivery_failed(config, store, &id, notification, error, retry_delay)
+            };
+
+            // Keep the creation order: don't send the next ones before this one
+            if retry == Retry::Blocking {
+                break;
+            }
+
+            continue;
+        }
+
+        tracing::info!("Sent notification: {}", notification.plain_text);
+
+        match store.delete_notification(&id) {
+            Ok(_) => tracing::debug!("Notification {id} deleted"),
+            Err(error) => tracing::error!("Impossible to delete notification {id}: {error:#?}"),
+        };
+    }
+}
 
-pub async fn run(config: Config, store: &NotificationStore) {
+pub async fn run(
+    config: Config,
+    store: &NotificationStore,
+    subscriptions: &SubscriptionStore,
+    chain_state: watch::Receiver<Option<ChainState>>,
+) -> Result<()> {
     tokio::select! {
-        _ = ntfy::run(&config, store) => {
+        result = ntfy::run(&config, store) => {
             println!("ntfy exited.");
+            result
         }
-        _ = nostr::run(&config, store) => {
+        result = nostr::run(&config, store, subscriptions, chain_state.clone()) => {
             println!("nostr exited.");
+            result
         }
+        result = matrix::run(&config, store) => {
+            println!("matrix exited.");
+            result
+        }
+        result = discord::run(&config, store) => {
+            println!("discord exited.");
+            result
+        }
+        result = slack::run(&config, store) => {
+            println!("slack exited.");
+            result
+        }
+        result = email::run(&config, store) => {
+            println!("email exited.");
+            result
+        }
+        result = mqtt::run(&config, store, chain_state) => {
+            println!("mqtt exited.");
+            result
+        }
+        result = telegram::run(&config, store) => {
+            println!("telegram exited.");
+            result
+        }
+        result = webhook::run(&config, store) => {
+            println!("webhook exited.");
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            base: 30,
+            max: 3600,
+        };
+        assert_eq!(backoff.delay(1), 30);
+        assert_eq!(backoff.delay(2), 60);
+        assert_eq!(backoff.delay(3), 120);
+        assert_eq!(backoff.delay(7), 1920);
+        assert_eq!(backoff.delay(8), 3600);
+        assert_eq!(backoff.delay(100), 3600);
+    }
+
+    #[test]
+    fn test_http_error() {
+        let error = http_error(StatusCode::BAD_REQUEST, "can't parse entities");
+        assert!(error.is::<Rejected>());
+        assert_eq!(
+            error.to_string(),
+            "status 400 Bad Request: can't parse entities"
+        );
+        assert!(http_error(StatusCode::FORBIDDEN, "").is::<Rejected>());
+        assert!(!http_error(StatusCode::UNAUTHORIZED, "").is::<Rejected>());
+        assert!(!http_error(StatusCode::TOO_MANY_REQUESTS, "").is::<Rejected>());
+        assert!(!http_error(StatusCode::BAD_GATEWAY, "").is::<Rejected>());
+    }
+
+    #[test]
+    fn test_url_label() {
+        let url = Url::parse("https://discord.com/api/webhooks/1/token").unwrap();
+        let label: String = url_label("discord", &url);
+        assert!(label.starts_with("discord#"));
+        assert!(!label.contains("token"));
+        assert_ne!(
+            label,
+            url_label(
+                "discord",
+                &Url::parse("https://discord.com/api/webhooks/2/token").unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+
+        headers.insert(RETRY_AFTER, "12".parse().unwrap());
+        assert_eq!(retry_after(&headers), Duration::from_secs(12));
+
+        headers.insert(RETRY_AFTER, "0.5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Duration::from_millis(500));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+
+        headers.insert(RETRY_AFTER, "-1".parse().unwrap());
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
+
+        // Too large for a `Duration`
+        headers.insert(RETRY_AFTER, "1e30".parse().unwrap());
+        assert_eq!(retry_after(&headers), DEFAULT_RETRY_AFTER);
     }
 }
@@ -0,0 +1,468 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use nostr_sdk::Result;
+use rumqttc::{AsyncClient, ClientError, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, watch};
+use tokio::time;
+
+use super::{alert_payload, process_notifications};
+use crate::config::model::Mqtt;
+use crate::config::Config;
+use crate::db::NotificationStore;
+use crate::primitives::{AlertKind, ChainState, Target};
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+/// Max wait for the broker to ack a notification
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Sensor {
+    id: &'static str,
+    name: &'static str,
+    unit: Option<&'static str>,
+    icon: &'static str,
+}
+
+/// Chain state sensors, published as retained messages
+const SENSORS: &[Sensor] = &[
+    Sensor {
+        id: "block_height",
+        name: "Block height",
+        unit: None,
+        icon: "mdi:cube-outline",
+    },
+    Sensor {
+        id: "difficulty",
+        name: "Difficulty",
+        unit: None,
+        icon: "mdi:pickaxe",
+    },
+    Sensor {
+        id: "hashrate",
+        name: "Hashrate",
+        unit: Some("EH/s"),
+        icon: "mdi:speedometer",
+    },
+    Sensor {
+        id: "blocks_to_halving",
+        name: "Blocks to halving",
+        unit: Some("blocks"),
+        icon: "mdi:timer-sand",
+    },
+];
+
+/// Publish packets waiting for the broker to ack them.
+///
+/// The client doesn't return the packet id of a publish: it's known when the packet is sent, and
+/// packets are sent in request order.
+#[derive(Default)]
+struct Acks {
+    /// Publish requests not sent yet, in request order
+    requested: VecDeque<oneshot::Sender<()>>,
+    /// Sent publish packets, by packet id
+    sent: HashMap<u16, oneshot::Sender<()>>,
+    /// Requests not sent yet when the connection was lost, dropped by the client if the broker
+    /// doesn't resume the session
+    unsent: usize,
+}
+
+impl Acks {
+    fn sent(&mut self, pkid: u16) {
+        // Publish packets not acked in the previous session are sent again with the same id
+        if self.sent.contains_key(&pkid) {
+            return;
+        }
+
+        if let Some(ack) = self.requested.pop_front() {
+            self.sent.insert(pkid, ack);
+        }
+    }
+
+    fn acked(&mut self, pkid: u16) {
+        if let Some(ack) = self.sent.remove(&pkid) {
+            let _ = ack.send(());
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.unsent = self.requested.len();
+    }
+
+    fn connected(&mut self, session_present: bool) {
+        if !session_present {
+            // Dropping the senders fails the notifications waiting for them
+            self.requested
+                .drain(..self.unsent.min(self.requested.len()));
+            self.sent.clear();
+        }
+        self.unsent = 0;
+    }
+}
+
+fn lock(acks: &Mutex<Acks>) -> MutexGuard<'_, Acks> {
+    acks.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Publish with QoS 1, returning a receiver completed when the broker acks the message
+fn publish<V>(
+    client: &AsyncClient,
+    acks: &Mutex<Acks>,
+    topic: String,
+    retain: bool,
+    payload: V,
+) -> Result<oneshot::Receiver<()>, ClientError>
+where
+    V: Into<Vec<u8>>,
+{
+    let (ack, acked) = oneshot::channel();
+
+    // Keep the order of the requests and of the senders the same
+    let mut acks = lock(acks);
+    client.try_publish(topic, QoS::AtLeastOnce, retain, payload)?;
+    acks.requested.push_back(ack);
+
+    Ok(acked)
+}
+
+fn alert_topic(config: &Mqtt, kind: Option<AlertKind>) -> String {
+    match kind {
+        Some(kind) => format!("{}/{kind}", config.topic_prefix),
+        None => format!("{}/alert", config.topic_prefix),
+    }
+}
+
+fn state_topic(config: &Mqtt, sensor: &str) -> String {
+    format!("{}/state/{sensor}", config.topic_prefix)
+}
+
+fn availability_topic(config: &Mqtt) -> String {
+    format!("{}/status", config.topic_prefix)
+}
+
+/// Home Assistant MQTT discovery message of a sensor
+fn discovery(config: &Mqtt, discovery_prefix: &str, sensor: &Sensor) -> (String, Value) {
+    let topic: String = format!(
+        "{discovery_prefix}/sensor/{}/{}/config",
+        config.client_id, sensor.id
+    );
+    let mut payload: Value = json!({
+        "name": sensor.name,
+        "unique_id": format!("{}_{}", config.client_id, sensor.id),
+        "state_topic": state_topic(config, sensor.id),
+        "availability_topic": availability_topic(config),
+        "icon": sensor.icon,
+        "state_class": "measurement",
+        "device": {
+            "identifiers": [config.client_id],
+            "name": "Bitcoin Alerts",
+        },
+    });
+
+    if let Some(unit) = sensor.unit {
+        payload["unit_of_measurement"] = json!(unit);
+    }
+
+    (topic, payload)
+}
+
+fn state_values(state: &ChainState) -> [(&'static str, String); 4] {
+    [
+        ("block_height", state.block_height.to_string()),
+        ("difficulty", state.difficulty.to_string()),
+        (
+            "hashrate",
+            format!("{:.2}", state.hashrate / 10f64.powi(18)),
+        ),
+        ("blocks_to_halving", state.blocks_to_halving.to_string()),
+    ]
+}
+
+fn publish_state(client: &AsyncClient, acks: &Mutex<Acks>, config: &Mqtt, state: &ChainState) {
+    for (sensor, value) in state_values(state).into_iter() {
+        if let Err(e) = publish(client, acks, state_topic(config, sensor), true, value) {
+            tracing::error!("Impossible to publish {sensor} state: {e}");
+        }
+    }
+}
+
+/// Announce the availability, the sensors and the last known state
+fn announce(
+    client: &AsyncClient,
+    acks: &Mutex<Acks>,
+    config: &Mqtt,
+    state: Option<ChainState>,
+) -> Result<()> {
+    publish(client, acks, availability_topic(config), true, "online")?;
+
+    if let Some(discovery_prefix) = &config.discovery_prefix {
+        for sensor in SENSORS.iter() {
+            let (topic, payload) = discovery(config, discovery_prefix, sensor);
+            publish(client, acks, topic, true, payload.to_string())?;
+        }
+    }
+
+    if let Some(state) = state {
+        publish_state(client, acks, config, &state);
+    }
+
+    Ok(())
+}
+
+pub async fn run(
+    config: &Config,
+    store: &NotificationStore,
+    mut chain_state: watch::Receiver<Option<ChainState>>,
+) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.mqtt.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let mut options = MqttOptions::new(&config.mqtt.client_id, &config.mqtt.host, config.mqtt.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_clean_session(false);
+    options.set_last_will(LastWill::new(
+        availability_topic(&config.mqtt),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    if let (Some(username), Some(password)) = (&config.mqtt.username, &config.mqtt.password) {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    let acks: Arc<Mutex<Acks>> = Arc::new(Mutex::new(Acks::default()));
+
+    // Drive the connection, announcing the sensors on every (re)connection
+    let announcer: AsyncClient = client.clone();
+    let announcer_acks: Arc<Mutex<Acks>> = acks.clone();
+    let mqtt: Mqtt = config.mqtt.clone();
+    let last_state = chain_state.clone();
+    tokio::spawn(async move {
+        let mut connected: bool = false;
+
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                    tracing::info!("Connected to MQTT broker {}:{}", mqtt.host, mqtt.port);
+                    connected = true;
+                    lock(&announcer_acks).connected(connack.session_present);
+
+                    let state: Option<ChainState> = *last_state.borrow();
+                    if let Err(e) = announce(&announcer, &announcer_acks, &mqtt, state) {
+                        tracing::error!("Impossible to announce MQTT sensors: {e}");
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => lock(&announcer_acks).sent(pkid),
+                Ok(Event::Incoming(Packet::PubAck(puback))) => {
+                    lock(&announcer_acks).acked(puback.pkid)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // The unsent requests are kept by the client only if the session is resumed
+                    if connected {
+                        lock(&announcer_acks).disconnected();
+                        connected = false;
+                    }
+
+                    tracing::error!("MQTT connection error: {e} - retrying in 10 sec");
+                    time::sleep(Duration::from_secs(10)).await;
+                }
+            }
+        }
+    });
+
+    tracing::info!("MQTT Dispatcher started");
+
+    let broker: [(String, ()); 1] = [(format!("{}:{}", config.mqtt.host, config.mqtt.port), ())];
+
+    loop {
+        if chain_state.has_changed().unwrap_or(false) {
+            let state: Option<ChainState> = *chain_state.borrow_and_update();
+            if let Some(state) = state {
+                publish_state(&client, &acks, &config.mqtt, &state);
+            }
+        }
+
+        process_notifications(
+            config,
+            store,
+            Target::Mqtt,
+            Target::Mqtt.as_str(),
+            RETRY_DELAY,
+            &broker,
+            |_, id, notification| {
+                let client: &AsyncClient = &client;
+                let acks: &Mutex<Acks> = &acks;
+                async move {
+                    let topic: String = alert_topic(&config.mqtt, notification.kind);
+                    let payload: String = alert_payload(&id, &notification).to_string();
+                    let acked = publish(client, acks, topic, false, payload)?;
+
+                    // Delivered once acked by the broker
+                    match time::timeout(ACK_TIMEOUT, acked).await {
+                        Ok(Ok(())) => Ok(()),
+                        Ok(Err(_)) => Err("dropped with the MQTT session".into()),
+                        Err(_) => Err(format!("not acked by the broker in {ACK_TIMEOUT:?}").into()),
+                    }
+                }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        tokio::select! {
+            _ = chain_state.changed() => {}
+            _ = time::sleep(Duration::from_secs(30)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::locale::Locale;
+
+    fn config() -> Mqtt {
+        Mqtt {
+            enabled: true,
+            locale: Locale::En,
+            host: String::from("localhost"),
+            port: 1883,
+            client_id: String::from("bitcoin_alerts"),
+            username: None,
+            password: None,
+            topic_prefix: String::from("bitcoin_alerts"),
+            discovery_prefix: Some(String::from("homeassistant")),
+        }
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut acks = Acks::default();
+        let (first, mut first_acked) = oneshot::channel();
+        let (second, mut second_acked) = oneshot::channel();
+        acks.requested.push_back(first);
+        acks.requested.push_back(second);
+
+        acks.sent(1);
+        acks.sent(2);
+        // Sent again after a reconnection
+        acks.sent(1);
+        acks.acked(2);
+        assert!(first_acked.try_recv().is_err());
+        assert!(second_acked.try_recv().is_ok());
+        acks.acked(1);
+        assert!(first_acked.try_recv().is_ok());
+
+        // The broker didn't resume the session: the requests sent before are dropped
+        let (third, mut third_acked) = oneshot::channel();
+        let (fourth, _fourth_acked) = oneshot::channel();
+        acks.requested.push_back(third);
+        acks.sent(3);
+        acks.disconnected();
+        acks.requested.push_back(fourth);
+        acks.connected(false);
+        assert!(matches!(
+            third_acked.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        ));
+        assert_eq!(acks.requested.len(), 1);
+        assert!(acks.sent.is_empty());
+    }
+
+    #[test]
+    fn test_acks_session_resumed() {
+        let mut acks = Acks::default();
+        let (first, mut first_acked) = oneshot::channel();
+        let (second, mut second_acked) = oneshot::channel();
+        let (third, mut third_acked) = oneshot::channel();
+        acks.requested.push_back(first);
+        acks.requested.push_back(second);
+        acks.sent(1);
+
+        // Lost with the second one still in the client queue, and a third requested meanwhile
+        acks.disconnected();
+        acks.requested.push_back(third);
+        acks.connected(true);
+        assert_eq!(acks.requested.len(), 2);
+        assert_eq!(acks.sent.len(), 1);
+
+        // QoS 1 redelivery of the first one, then the queued ones with new ids
+        acks.sent(1);
+        acks.sent(2);
+        acks.sent(3);
+        acks.acked(3);
+        assert!(third_acked.try_recv().is_ok());
+        assert!(first_acked.try_recv().is_err());
+        acks.acked(1);
+        assert!(first_acked.try_recv().is_ok());
+        acks.acked(2);
+        assert!(second_acked.try_recv().is_ok());
+        assert!(acks.requested.is_empty());
+        assert!(acks.sent.is_empty());
+    }
+
+    #[test]
+    fn test_acks_pkid_reuse() {
+        let mut acks = Acks::default();
+        let (first, mut first_acked) = oneshot::channel();
+        let (second, mut second_acked) = oneshot::channel();
+        acks.requested.push_back(first);
+        acks.sent(u16::MAX);
+        acks.acked(u16::MAX);
+        assert!(first_acked.try_recv().is_ok());
+
+        // Ids wrap around: an acked id belongs to the next request that gets it
+        acks.requested.push_back(second);
+        acks.sent(u16::MAX);
+        assert!(second_acked.try_recv().is_err());
+        acks.acked(u16::MAX);
+        assert!(second_acked.try_recv().is_ok());
+
+        // A duplicate ack doesn't complete anything else
+        let (third, mut third_acked) = oneshot::channel();
+        acks.requested.push_back(third);
+        acks.acked(u16::MAX);
+        acks.sent(1);
+        assert!(third_acked.try_recv().is_err());
+        acks.acked(1);
+        assert!(third_acked.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_topics() {
+        let config = config();
+        assert_eq!(
+            alert_topic(&config, Some(AlertKind::HalvingCountdown)),
+            "bitcoin_alerts/halving_countdown"
+        );
+        assert_eq!(alert_topic(&config, None), "bitcoin_alerts/alert");
+        assert_eq!(
+            state_topic(&config, "hashrate"),
+            "bitcoin_alerts/state/hashrate"
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let config = config();
+        let (topic, payload) = discovery(&config, "homeassistant", &SENSORS[2]);
+        assert_eq!(topic, "homeassistant/sensor/bitcoin_alerts/hashrate/config");
+        assert_eq!(payload["unique_id"], "bitcoin_alerts_hashrate");
+        assert_eq!(payload["state_topic"], "bitcoin_alerts/state/hashrate");
+        assert_eq!(payload["availability_topic"], "bitcoin_alerts/status");
+        assert_eq!(payload["unit_of_measurement"], "EH/s");
+    }
+}
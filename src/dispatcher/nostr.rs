@@ -1,41 +1,807 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
+use futures::future;
+use nostr_connect::client::NostrConnect;
 use nostr_sdk::nostr::nips::nip01::Metadata;
-use nostr_sdk::{Client, EventBuilder, Result};
+use nostr_sdk::nostr::nips::nip10::Marker;
+use nostr_sdk::nostr::nips::nip17;
+use nostr_sdk::nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::{
+    Client, Event, EventBuilder, EventId, Filter, Keys, Kind, Options, PublicKey, RelayOptions,
+    RelayPoolNotification, RelayServiceFlags, RelayStatus, RelayUrl, Result, Tag, TagStandard,
+    Timestamp,
+};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
 use tokio::time;
 
+use super::{delivery_failed, Backoff, Retry};
+use crate::bitcoin::HALVING_INTERVAL;
+use crate::config::model::{Nostr, Signer};
 use crate::config::Config;
-use crate::db::NotificationStore;
-use crate::primitives::Target;
+use crate::db::{Notification, NotificationStore, Subscription, SubscriptionStore};
+use crate::primitives::{AlertKind, ChainState, Target};
+use crate::util;
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 60;
+
+/// Timeout of the lookup of, and the connection to, the inbox relays of a recipient
+const INBOX_RELAYS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefix of the `d` tag of the state events
+const STATE_PREFIX: &str = "bitcoin_alerts";
+
+/// Attempts to send an event to all the relays, before giving up on the ones that missed it
+const MAX_BROADCAST_ATTEMPTS: u32 = 10;
+
+/// Prefix of the names of the relay metrics
+const METRICS_PREFIX: &str = "bitcoin_alerts_nostr";
+
+/// Timeout of the requests to a remote signer
+const BUNKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delays, in secs, between the attempts to reach the signer at startup
+const SIGNER_BACKOFF: Backoff = Backoff { base: 10, max: 600 };
+
+/// Gift wraps are backdated up to 2 days (NIP-59)
+const GIFT_WRAP_MAX_DELAY: u64 = 2 * 24 * 60 * 60;
+
+const HELP: &str = "Commands:
+- subscribe <kind>: alerts of a kind (e.g. halving, difficulty, reorg)
+- subscribe fees<N: when the next block fee estimate drops below N sat/vB
+- subscribe fees>N: when the median fee rate of a block rises above N sat/vB
+- unsubscribe <kind|fees|all>
+- list: your subscriptions
+- help";
+
+/// Hashtags of the notes of `kind`, besides #bitcoin
+fn hashtags(kind: AlertKind) -> &'static [&'static str] {
+    match kind {
+        AlertKind::Halving | AlertKind::BlockReward | AlertKind::HalvingCountdown => &["halving"],
+        AlertKind::Difficulty => &["difficulty", "mining"],
+        AlertKind::Hashrate => &["hashrate", "mining"],
+        AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => &["block"],
+        AlertKind::Supply => &["supply"],
+        AlertKind::Reorg => &["reorg"],
+        AlertKind::LowFee | AlertKind::HighFee | AlertKind::MempoolPurging => &["fees", "mempool"],
+        AlertKind::WatchReceived
+        | AlertKind::WatchSpent
+        | AlertKind::TxConfirmed
+        | AlertKind::TxReplaced
+        | AlertKind::TxDropped => &[],
+    }
+}
+
+/// Thread of an alert series: the halving countdown notes of the same epoch
+fn thread(notification: &Notification) -> Option<String> {
+    match notification.kind {
+        Some(AlertKind::HalvingCountdown) => Some(format!(
+            "halving_countdown:{}",
+            notification.block_height / HALVING_INTERVAL
+        )),
+        _ => None,
+    }
+}
+
+/// Tags of a note, replying to the `root` of its thread if there is one
+fn tags(
+    notification: &Notification,
+    expiration: &BTreeMap<AlertKind, u64>,
+    author: PublicKey,
+    root: Option<EventId>,
+    now: Timestamp,
+) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = vec![Tag::hashtag("bitcoin")];
+
+    if let Some(kind) = notification.kind {
+        tags.extend(hashtags(kind).iter().map(|hashtag| Tag::hashtag(*hashtag)));
+
+        if let Some(secs) = expiration.get(&kind) {
+            tags.push(Tag::expiration(now + *secs));
+        }
+    }
+
+    if let Some(root) = root {
+        tags.push(Tag::from_standardized_without_cell(TagStandard::Event {
+            event_id: root,
+            relay_url: None,
+            marker: Some(Marker::Root),
+            public_key: Some(author),
+            uppercase: false,
+        }));
+    }
+
+    tags
+}
+
+/// Content of the state events (NIP-78), by metric
+fn state_events(state: &ChainState, now: Timestamp) -> Vec<(&'static str, Value)> {
+    let mut events: Vec<(&'static str, Value)> = vec![
+        (
+            "block_height",
+            json!({ "block_height": state.block_height }),
+        ),
+        (
+            "difficulty",
+            json!({ "block_height": state.block_height, "difficulty": state.difficulty }),
+        ),
+        (
+            "hashrate",
+            json!({ "block_height": state.block_height, "hashrate": state.hashrate }),
+        ),
+        (
+            "halving",
+            json!({
+                "block_height": state.block_height,
+                "halving_height": state.block_height + state.blocks_to_halving,
+                "blocks_to_halving": state.blocks_to_halving,
+                // 10 minutes per block
+                "eta": (now + state.blocks_to_halving * 600).as_u64(),
+            }),
+        ),
+    ];
+
+    if state.next_block_fee_rate.is_some() || state.mempool_min_fee.is_some() {
+        events.push((
+            "fees",
+            json!({
+                "block_height": state.block_height,
+                "next_block_fee_rate": state.next_block_fee_rate,
+                "mempool_min_fee": state.mempool_min_fee,
+            }),
+        ));
+    }
+
+    events
+}
+
+/// Content of a state event, without the fields derived from the current time
+fn state_key(content: &Value) -> Value {
+    let mut key: Value = content.clone();
+    if let Some(fields) = key.as_object_mut() {
+        fields.remove("eta");
+    }
+    key
+}
+
+/// State events whose content changed since they were last published
+fn state_changes(
+    state: &ChainState,
+    now: Timestamp,
+    published: &HashMap<&'static str, Value>,
+) -> Vec<(&'static str, Value)> {
+    state_events(state, now)
+        .into_iter()
+        .filter(|(metric, content)| published.get(metric) != Some(&state_key(content)))
+        .collect()
+}
+
+/// Replace the state events of the instance whose content changed
+async fn publish_state(
+    client: &Client,
+    state: &ChainState,
+    published: &mut HashMap<&'static str, Value>,
+) {
+    for (metric, content) in state_changes(state, Timestamp::now(), published).into_iter() {
+        let builder: EventBuilder =
+            EventBuilder::new(Kind::ApplicationSpecificData, content.to_string())
+                .tag(Tag::identifier(format!("{STATE_PREFIX}/{metric}")));
+
+        match client.send_event_builder(builder).await {
+            Ok(output) if output.success.is_empty() => {
+                let errors: Vec<String> = output
+                    .failed
+                    .iter()
+                    .map(|(url, error)| format!("{url}: {error}"))
+                    .collect();
+                tracing::error!(
+                    "{metric} state not accepted by any relay: {}",
+                    errors.join(", ")
+                );
+            }
+            Ok(_) => {
+                published.insert(metric, state_key(&content));
+            }
+            Err(e) => tracing::error!("Impossible to publish {metric} state: {e}"),
+        }
+    }
+}
+
+/// Alerts a command refers to
+#[derive(Debug, Clone, PartialEq)]
+enum Topic {
+    Kind(AlertKind),
+    FeesBelow(f64),
+    FeesAbove(f64),
+    /// Both fee thresholds
+    Fees,
+}
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `f64` also parses `NaN`, `inf` and negative numbers
+        let fee_rate = |rate: &str| match rate.parse::<f64>() {
+            Ok(value) if value.is_finite() && value >= 0.0 => Ok(value),
+            _ => Err(format!("invalid fee rate: {rate}")),
+        };
+
+        if s == "fees" {
+            Ok(Self::Fees)
+        } else if let Some(rate) = s.strip_prefix("fees<") {
+            Ok(Self::FeesBelow(fee_rate(rate)?))
+        } else if let Some(rate) = s.strip_prefix("fees>") {
+            Ok(Self::FeesAbove(fee_rate(rate)?))
+        } else {
+            AlertKind::deserialize(s.into_deserializer())
+                .map(Self::Kind)
+                .map_err(|_: serde::de::value::Error| format!("unknown alert kind: {s}"))
+        }
+    }
+}
+
+/// Command received by direct message
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    UnsubscribeAll,
+    List,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.to_lowercase();
+        let mut words = s.split_whitespace();
+        let command: &str = words.next().unwrap_or_default();
+        // Allow spaces in the topic (i.e. `fees < 5`)
+        let topic: String = words.collect();
+
+        match (command, topic.as_str()) {
+            ("subscribe", "") | ("unsubscribe", "") => Err(String::from("missing alert kind")),
+            ("subscribe", topic) => Ok(Self::Subscribe(topic.parse()?)),
+            ("unsubscribe", "all") => Ok(Self::UnsubscribeAll),
+            ("unsubscribe", topic) => Ok(Self::Unsubscribe(topic.parse()?)),
+            ("list", "") => Ok(Self::List),
+            ("help", "") => Ok(Self::Help),
+            _ => Err(format!("unknown command: {s}")),
+        }
+    }
+}
+
+/// Apply `command` to `subscription`, returning the reply
+fn apply(command: Command, subscription: &mut Subscription) -> String {
+    match command {
+        Command::Subscribe(Topic::Kind(kind)) => {
+            subscription.kinds.insert(kind);
+            format!("Subscribed to {kind}")
+        }
+        Command::Subscribe(Topic::FeesBelow(rate)) => {
+            subscription.fees_below = Some(rate);
+            format!("Subscribed to fee rates below {rate} sat/vB")
+        }
+        Command::Subscribe(Topic::FeesAbove(rate)) => {
+            subscription.fees_above = Some(rate);
+            format!("Subscribed to fee rates above {rate} sat/vB")
+        }
+        Command::Subscribe(Topic::Fees) => String::from("Missing fee rate, e.g. subscribe fees<5"),
+        Command::Unsubscribe(Topic::Kind(kind)) => {
+            subscription.kinds.remove(&kind);
+            format!("Unsubscribed from {kind}")
+        }
+        Command::Unsubscribe(Topic::FeesBelow(_)) => {
+            subscription.fees_below = None;
+            String::from("Unsubscribed from low fee rates")
+        }
+        Command::Unsubscribe(Topic::FeesAbove(_)) => {
+            subscription.fees_above = None;
+            String::from("Unsubscribed from high fee rates")
+        }
+        Command::Unsubscribe(Topic::Fees) => {
+            subscription.fees_below = None;
+            subscription.fees_above = None;
+            String::from("Unsubscribed from fee rates")
+        }
+        Command::UnsubscribeAll => {
+            *subscription = Subscription {
+                nip04: subscription.nip04,
+                ..Default::default()
+            };
+            String::from("Unsubscribed from all the alerts")
+        }
+        Command::List => describe(subscription),
+        Command::Help => HELP.to_string(),
+    }
+}
+
+fn describe(subscription: &Subscription) -> String {
+    if subscription.is_empty() {
+        return String::from("No subscriptions");
+    }
+
+    let mut topics: Vec<String> = subscription
+        .kinds
+        .iter()
+        .map(|kind| kind.to_string())
+        .collect();
+
+    if let Some(rate) = subscription.fees_below {
+        topics.push(format!("fees<{rate}"));
+    }
+
+    if let Some(rate) = subscription.fees_above {
+        topics.push(format!("fees>{rate}"));
+    }
+
+    format!("Subscribed to: {}", topics.join(", "))
+}
+
+/// Decrypted direct message
+struct DirectMessage {
+    sender: PublicKey,
+    content: String,
+    created_at: Timestamp,
+    /// NIP-04 message, instead of NIP-17
+    nip04: bool,
+}
+
+impl DirectMessage {
+    async fn decrypt(client: &Client, event: &Event) -> Result<Option<Self>> {
+        match event.kind {
+            Kind::EncryptedDirectMessage => {
+                let signer = client.signer().await?;
+                Ok(Some(Self {
+                    sender: event.pubkey,
+                    content: signer.nip04_decrypt(&event.pubkey, &event.content).await?,
+                    created_at: event.created_at,
+                    nip04: true,
+                }))
+            }
+            Kind::GiftWrap => {
+                let UnwrappedGift { sender, rumor } = client.unwrap_gift_wrap(event).await?;
+
+                if rumor.kind != Kind::PrivateDirectMessage {
+                    return Ok(None);
+                }
+
+                Ok(Some(Self {
+                    sender,
+                    content: rumor.content,
+                    created_at: rumor.created_at,
+                    nip04: false,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Inbox relays (NIP-17) of `public_key`, from its kind 10050 list.
+///
+/// They are added to the pool with the gossip flag instead of the read and write ones, so that
+/// the notes and the subscriptions keep using only the configured relays.
+async fn inbox_relays(client: &Client, public_key: PublicKey) -> Vec<RelayUrl> {
+    let filter = Filter::new()
+        .author(public_key)
+        .kind(Kind::InboxRelays)
+        .limit(1);
+    let event: Event = match client.fetch_events(filter, INBOX_RELAYS_TIMEOUT).await {
+        Ok(events) => match events.first_owned() {
+            Some(event) => event,
+            None => return Vec::new(),
+        },
+        Err(e) => {
+            tracing::warn!("Impossible to get the inbox relays of {public_key}: {e}");
+            return Vec::new();
+        }
+    };
+
+    let mut relays: Vec<RelayUrl> = Vec::new();
+    for url in nip17::extract_owned_relay_list(event) {
+        let opts =
+            RelayOptions::default().flags(RelayServiceFlags::PING | RelayServiceFlags::GOSSIP);
+        if let Err(e) = client.pool().add_relay(&url, opts).await {
+            tracing::warn!("Impossible to add inbox relay {url}: {e}");
+            continue;
+        }
+        match client.try_connect_relay(&url, INBOX_RELAYS_TIMEOUT).await {
+            Ok(()) => relays.push(url),
+            Err(e) => tracing::warn!("Impossible to connect to inbox relay {url}: {e}"),
+        }
+    }
+    relays
+}
+
+async fn send_direct_message(
+    client: &Client,
+    receiver: PublicKey,
+    message: &str,
+    nip04: bool,
+) -> Result<()> {
+    let output = if nip04 {
+        let signer = client.signer().await?;
+        let content: String = signer.nip04_encrypt(&receiver, message).await?;
+        let builder: EventBuilder =
+            EventBuilder::new(Kind::EncryptedDirectMessage, content).tag(Tag::public_key(receiver));
+        client.send_event_builder(builder).await?
+    } else {
+        // Deliver to the inbox relays of the receiver, or to ours if it has none
+        let relays: Vec<RelayUrl> = inbox_relays(client, receiver).await;
+        if relays.is_empty() {
+            client.send_private_msg(receiver, message, []).await?
+        } else {
+            client
+                .send_private_msg_to(relays, receiver, message, [])
+                .await?
+        }
+    };
+
+    if output.success.is_empty() {
+        let errors: Vec<String> = output
+            .failed
+            .iter()
+            .map(|(url, error)| format!("{url}: {error}"))
+            .collect();
+        return Err(format!("not accepted by any relay: {}", errors.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+/// Event of a notification and the relays that acknowledged it, across attempts
+struct Broadcast {
+    event: Event,
+    acked: HashSet<RelayUrl>,
+    attempts: u32,
+}
+
+impl Broadcast {
+    fn new(event: Event) -> Self {
+        Self {
+            event,
+            acked: HashSet::new(),
+            attempts: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RelayHealth {
+    status: Option<RelayStatus>,
+    acked: u64,
+    failed: u64,
+    last_error: Option<String>,
+}
+
+/// Name, type, help and value of a relay metric
+type RelayMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&RelayHealth) -> u64,
+);
+
+/// Delivery of the events of an instance, tracked per relay
+#[derive(Default)]
+struct Deliveries {
+    /// Events not acknowledged by enough relays yet, by notification id
+    pending: HashMap<String, Broadcast>,
+    /// Events of delivered notifications, still missing some relays
+    rebroadcast: Vec<Broadcast>,
+    relays: BTreeMap<RelayUrl, RelayHealth>,
+}
+
+impl Deliveries {
+    /// Record the result of an attempt, returning the errors of the relays that failed
+    fn record(
+        &mut self,
+        broadcast: &mut Broadcast,
+        success: HashSet<RelayUrl>,
+        failed: HashMap<RelayUrl, String>,
+    ) -> Vec<String> {
+        broadcast.attempts += 1;
+
+        for url in success.into_iter() {
+            self.relays.entry(url.clone()).or_default().acked += 1;
+            broadcast.acked.insert(url);
+        }
+
+        failed
+            .into_iter()
+            .map(|(url, error)| {
+                let health: &mut RelayHealth = self.relays.entry(url.clone()).or_default();
+                health.failed += 1;
+                health.last_error = Some(error.clone());
+                format!("{url}: {error}")
+            })
+            .collect()
+    }
+
+    /// Send the event to the `relays` that didn't acknowledge it yet, returning their errors
+    async fn send(
+        &mut self,
+        client: &Client,
+        relays: &HashSet<RelayUrl>,
+        broadcast: &mut Broadcast,
+    ) -> Vec<String> {
+        let missing: HashSet<RelayUrl> = relays.difference(&broadcast.acked).cloned().collect();
+
+        if missing.is_empty() {
+            return Vec::new();
+        }
+
+        match client
+            .send_event_to(missing.clone(), &broadcast.event)
+            .await
+        {
+            Ok(output) => self.record(broadcast, output.success, output.failed),
+            Err(e) => {
+                let failed = missing
+                    .into_iter()
+                    .map(|url| (url, e.to_string()))
+                    .collect();
+                self.record(broadcast, HashSet::new(), failed)
+            }
+        }
+    }
+
+    /// Send the delivered events again to the relays that missed them
+    async fn rebroadcast(&mut self, client: &Client, relays: &HashSet<RelayUrl>) {
+        let mut rebroadcast: Vec<Broadcast> = Vec::new();
+
+        for mut broadcast in std::mem::take(&mut self.rebroadcast).into_iter() {
+            self.send(client, relays, &mut broadcast).await;
+
+            if broadcast.acked.len() < relays.len() {
+                if broadcast.attempts < MAX_BROADCAST_ATTEMPTS {
+                    rebroadcast.push(broadcast);
+                } else {
+                    tracing::warn!(
+                        "Event {} not acknowledged by {} relays after {} attempts",
+                        broadcast.event.id,
+                        relays.len() - broadcast.acked.len(),
+                        broadcast.attempts
+                    );
+                }
+            }
+        }
+
+        self.rebroadcast = rebroadcast;
+    }
+
+    /// Log the status of the relays when it changes, and their delivery stats
+    async fn log_relay_status(&mut self, client: &Client) {
+        for (url, relay) in client.relays().await.into_iter() {
+            let status: RelayStatus = relay.status();
+            let health: &mut RelayHealth = self.relays.entry(url.clone()).or_default();
+
+            if health.status != Some(status) {
+                tracing::info!("Relay {url} is {status}");
+                health.status = Some(status);
+            }
+
+            tracing::debug!(
+                "Relay {url}: {status}, {} acked, {} failed, last error: {}",
+                health.acked,
+                health.failed,
+                health.last_error.as_deref().unwrap_or("none")
+            );
+        }
+    }
+
+    /// Delivery stats of the relays, in the Prometheus text format
+    fn metrics(&self, instance: &str) -> String {
+        let instance: String = escape_label(instance);
+        let mut metrics = String::new();
+
+        let relay_metrics: [RelayMetric; 3] = [
+            (
+                "relay_connected",
+                "gauge",
+                "Whether the relay is connected",
+                |health| u64::from(health.status == Some(RelayStatus::Connected)),
+            ),
+            (
+                "relay_acked_total",
+                "counter",
+                "Events acknowledged by the relay",
+                |health| health.acked,
+            ),
+            (
+                "relay_failed_total",
+                "counter",
+                "Events rejected by the relay or not sent to it",
+                |health| health.failed,
+            ),
+        ];
+
+        for (name, kind, help, value) in relay_metrics.into_iter() {
+            let _ = writeln!(metrics, "# HELP {METRICS_PREFIX}_{name} {help}");
+            let _ = writeln!(metrics, "# TYPE {METRICS_PREFIX}_{name} {kind}");
+            for (url, health) in self.relays.iter() {
+                let _ = writeln!(
+                    metrics,
+                    "{METRICS_PREFIX}_{name}{{instance=\"{instance}\",relay=\"{}\"}} {}",
+                    escape_label(url.as_str()),
+                    value(health)
+                );
+            }
+        }
+
+        let counts: [(&str, &str, usize); 2] = [
+            (
+                "pending",
+                "Events not acknowledged by enough relays yet",
+                self.pending.len(),
+            ),
+            (
+                "rebroadcast",
+                "Delivered events still missing some relays",
+                self.rebroadcast.len(),
+            ),
+        ];
+
+        for (name, help, count) in counts.into_iter() {
+            let _ = writeln!(metrics, "# HELP {METRICS_PREFIX}_{name} {help}");
+            let _ = writeln!(metrics, "# TYPE {METRICS_PREFIX}_{name} gauge");
+            let _ = writeln!(
+                metrics,
+                "{METRICS_PREFIX}_{name}{{instance=\"{instance}\"}} {count}"
+            );
+        }
+
+        metrics
+    }
+
+    /// Write the delivery stats of the relays to `path`
+    fn write_metrics(&self, instance: &str, path: &Path) -> Result<()> {
+        // Written under a temporary name and renamed, so that a partial file is never scraped
+        let tmp: PathBuf = path.with_extension("tmp");
+        fs::write(&tmp, self.metrics(instance))?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Escape a label value of the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Keys of the client of a remote signer, generated on first use
+fn app_keys(path: &Path) -> Result<Keys> {
+    if path.exists() {
+        return Ok(Keys::new(util::read_secret_key(path)?));
+    }
+
+    let keys: Keys = Keys::generate();
+    util::write_secret_key(path, keys.secret_key())?;
+    tracing::info!("Nostr Connect client keys saved to {}", path.display());
+    Ok(keys)
+}
+
+fn client(signer: Signer, nip42_auth: bool) -> Result<Client> {
+    let builder = match signer {
+        Signer::Keys(keys) => Client::builder().signer(keys),
+        Signer::Bunker { uri, app_key_file } => {
+            let connect: NostrConnect =
+                NostrConnect::new(uri, app_keys(&app_key_file)?, BUNKER_TIMEOUT, None)?;
+            Client::builder().signer(connect)
+        }
+    };
+    // Authenticate to the relays requiring it, and resend the rejected events
+    let opts: Options = Options::new().automatic_authentication(nip42_auth);
+    Ok(builder.opts(opts).build())
+}
+
+pub async fn run(
+    config: &Config,
+    store: &NotificationStore,
+    subscriptions: &SubscriptionStore,
+    chain_state: watch::Receiver<Option<ChainState>>,
+) -> Result<()> {
+    let instances: Vec<_> = config
+        .nostr
+        .iter()
+        .filter(|nostr| nostr.enabled)
+        .map(|nostr| {
+            Box::pin(run_instance(
+                config,
+                nostr,
+                store,
+                subscriptions,
+                chain_state.clone(),
+            ))
+        })
+        .collect();
 
-pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
     // If not enabled, infinite loop
-    if !config.nostr.enabled {
+    if instances.is_empty() {
         loop {
             time::sleep(Duration::from_secs(60)).await;
         }
     }
 
-    let signer = config.nostr.keys.clone().unwrap();
-    let client: Client = Client::builder().signer(signer).build();
+    // Stop as soon as one of the instances stops
+    let (result, ..) = future::select_all(instances).await;
+    result
+}
+
+/// Public key of the signer, retrying until a remote signer is reachable
+async fn signer_public_key(client: &Client, nostr: &Nostr) -> PublicKey {
+    let mut attempts: u32 = 0;
+
+    loop {
+        let result: Result<PublicKey> = async {
+            let signer = client.signer().await?;
+            Ok(signer.get_public_key().await?)
+        }
+        .await;
+
+        match result {
+            Ok(public_key) => return public_key,
+            Err(e) => {
+                attempts += 1;
+                let delay: u64 = SIGNER_BACKOFF.delay(attempts);
+                tracing::error!(
+                    "Impossible to get the public key of the {} signer: {e} - retrying in {delay} secs",
+                    nostr.instance
+                );
+                time::sleep(Duration::from_secs(delay)).await;
+            }
+        }
+    }
+}
+
+async fn run_instance(
+    config: &Config,
+    nostr: &Nostr,
+    store: &NotificationStore,
+    subscriptions: &SubscriptionStore,
+    chain_state: watch::Receiver<Option<ChainState>>,
+) -> Result<()> {
+    let signer: Signer = nostr
+        .signer
+        .clone()
+        .ok_or_else(|| format!("missing signer of nostr instance {}", nostr.instance))?;
+    let client: Client = client(signer, nostr.nip42_auth)?;
 
-    for relay_url in config.nostr.relays.iter() {
+    for relay_url in nostr.relays.iter() {
         client.add_relay(relay_url).await?;
     }
 
     client.connect().await;
 
+    // With a remote signer, wait for the bunker to connect
+    let public_key: PublicKey = signer_public_key(&client, nostr).await;
+
     let mut metadata = Metadata::new()
-        .name(&config.nostr.name)
-        .display_name(&config.nostr.display_name)
-        .about(&config.nostr.description)
-        .picture(config.nostr.picture.clone())
-        .lud16(&config.nostr.lud16);
+        .name(&nostr.name)
+        .display_name(&nostr.display_name)
+        .about(&nostr.description)
+        .picture(nostr.picture.clone())
+        .lud16(&nostr.lud16);
 
-    if let Some(nip05) = &config.nostr.nip05 {
+    if let Some(nip05) = &nostr.nip05 {
         metadata = metadata.nip05(nip05);
     }
 
@@ -44,51 +810,781 @@ pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
     }
 
     // Set NIP65 list
-    let builder = EventBuilder::relay_list(config.nostr.relays.iter().cloned().map(|u| (u, None)));
+    let builder = EventBuilder::relay_list(nostr.relays.iter().cloned().map(|u| (u, None)));
     if let Err(err) = client.send_event_builder(builder).await {
         tracing::error!("Impossible to set relay list: {}", err);
     }
 
-    tracing::info!("Nostr Dispatcher started");
+    tracing::info!("Nostr Dispatcher started ({})", nostr.instance);
+
+    if nostr.dm_subscriptions {
+        tokio::select! {
+            result = listen_commands(&client, nostr, public_key, subscriptions) => result,
+            result = process_notifications(config, nostr, &client, public_key, store, chain_state) => result,
+        }
+    } else {
+        process_notifications(config, nostr, &client, public_key, store, chain_state).await
+    }
+}
+
+/// Handle the subscription commands received by direct message
+async fn listen_commands(
+    client: &Client,
+    nostr: &Nostr,
+    public_key: PublicKey,
+    subscriptions: &SubscriptionStore,
+) -> Result<()> {
+    let started_at: Timestamp = Timestamp::now();
+    let mut notifications = client.notifications();
+
+    let filter: Filter = Filter::new()
+        .pubkey(public_key)
+        .kinds([Kind::EncryptedDirectMessage, Kind::GiftWrap])
+        .since(started_at - GIFT_WRAP_MAX_DELAY);
+    client.subscribe(filter, None).await?;
+
+    tracing::info!("Listening for subscription commands ({})", nostr.instance);
 
     loop {
+        match notifications.recv().await {
+            Ok(RelayPoolNotification::Event { event, .. }) => {
+                if let Err(e) =
+                    handle_command(client, nostr, public_key, subscriptions, &event, started_at)
+                        .await
+                {
+                    tracing::error!("Impossible to handle direct message {}: {e}", event.id);
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Skipped {skipped} relay notifications")
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn handle_command(
+    client: &Client,
+    nostr: &Nostr,
+    public_key: PublicKey,
+    subscriptions: &SubscriptionStore,
+    event: &Event,
+    started_at: Timestamp,
+) -> Result<()> {
+    let message: DirectMessage = match DirectMessage::decrypt(client, event).await? {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+
+    // Commands sent while offline, or replayed by the relays after a restart, are ignored
+    if message.created_at < started_at || message.sender == public_key {
+        return Ok(());
+    }
+
+    let sender: String = message.sender.to_hex();
+    let mut subscription: Subscription = subscriptions.get_subscription(&nostr.instance, &sender);
+    subscription.nip04 = message.nip04;
+
+    let reply: String = match Command::from_str(&message.content) {
+        Ok(command) => apply(command, &mut subscription),
+        Err(e) => format!("{e}\n\n{HELP}"),
+    };
+
+    subscriptions.set_subscription(&nostr.instance, &sender, &subscription)?;
+    send_direct_message(client, message.sender, &reply, message.nip04).await
+}
+
+/// Send the direct messages concurrently: unlike the notes, they don't depend on each other
+async fn send_direct_messages(
+    config: &Config,
+    client: &Client,
+    store: &NotificationStore,
+    notifications: BTreeMap<String, Notification>,
+) {
+    let results = future::join_all(notifications.into_iter().map(
+        |(id, notification)| async move {
+            let result: Result<()> = match &notification.recipient {
+                Some(recipient) => match PublicKey::from_hex(&recipient.public_key) {
+                    Ok(receiver) => {
+                        send_direct_message(
+                            client,
+                            receiver,
+                            &notification.plain_text,
+                            recipient.nip04,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                None => Ok(()),
+            };
+            (id, notification, result)
+        },
+    ))
+    .await;
+
+    for (id, notification, result) in results.into_iter() {
+        match result {
+            Ok(()) => match store.delete_notification(&id) {
+                Ok(_) => tracing::debug!("Direct message {id} deleted"),
+                Err(e) => tracing::error!("Impossible to delete direct message {id}: {e}"),
+            },
+            Err(e) => {
+                tracing::error!("Impossible to send direct message {id}: {e}");
+                // The direct messages don't wait for each other: only the outcome is logged
+                match delivery_failed(config, store, &id, notification, e.to_string(), RETRY_DELAY)
+                {
+                    Retry::Blocking => tracing::warn!(
+                        "Direct message {id} will be sent again on the next iteration"
+                    ),
+                    Retry::NonBlocking => {}
+                    Retry::DeadLetter => {
+                        tracing::warn!("Direct message {id} dropped: moved to dead-letter queue")
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn process_notifications(
+    config: &Config,
+    nostr: &Nostr,
+    client: &Client,
+    public_key: PublicKey,
+    store: &NotificationStore,
+    mut chain_state: watch::Receiver<Option<ChainState>>,
+) -> Result<()> {
+    let mut deliveries = Deliveries::default();
+    // Content of the last published state events, by metric
+    let mut published: HashMap<&'static str, Value> = HashMap::new();
+
+    loop {
+        // Unchanged events are skipped, so this only retries the ones no relay accepted
+        if nostr.state_events {
+            let state: Option<ChainState> = *chain_state.borrow_and_update();
+            if let Some(state) = state {
+                publish_state(client, &state, &mut published).await;
+            }
+        }
+
+        deliveries.log_relay_status(client).await;
+        deliveries.rebroadcast(client, &nostr.relays).await;
+
+        if let Some(path) = &nostr.metrics_file {
+            if let Err(e) = deliveries.write_metrics(&nostr.instance, path) {
+                tracing::error!(
+                    "Impossible to write relay metrics to {}: {e}",
+                    path.display()
+                );
+            }
+        }
+
         tracing::debug!("Process pending notifications");
 
-        let notifications = match store.get_notifications_by_target(Target::Nostr) {
+        let notifications = match store.get_due_notifications(
+            Target::Nostr,
+            &nostr.instance,
+            config.delivery.max_blocking_attempts,
+        ) {
             Ok(result) => result,
             Err(error) => {
-                tracing::error!("Impossible to get nostr notifications from db: {:?}", error);
+                tracing::error!(
+                    "Impossible to get {} notifications from db: {:?}",
+                    nostr.instance,
+                    error
+                );
                 time::sleep(Duration::from_secs(60)).await;
                 continue;
             }
         };
 
+        let (direct_messages, notifications): (BTreeMap<_, _>, BTreeMap<_, _>) = notifications
+            .into_iter()
+            .partition(|(_, notification)| notification.recipient.is_some());
+
+        if !direct_messages.is_empty() {
+            send_direct_messages(config, client, store, direct_messages).await;
+        }
+
         if !notifications.is_empty() {
             for (id, notification) in notifications.into_iter() {
                 tracing::info!("Sending notification: {}", notification.plain_text);
 
-                // Create builder
-                let builder: EventBuilder = EventBuilder::text_note(notification.plain_text)
-                    .pow(config.nostr.pow_difficulty);
+                // Reply to the first note of the series, if already sent
+                let thread: Option<String> = thread(&notification);
+                let root: Option<EventId> = thread
+                    .as_ref()
+                    .and_then(|thread| store.get_thread_root(&nostr.instance, thread).ok())
+                    .and_then(|id| EventId::from_hex(&id).ok());
+
+                // Retry with the same event, so that the relays that already accepted it don't
+                // get a duplicate
+                let mut broadcast: Broadcast = match deliveries.pending.remove(&id) {
+                    Some(broadcast) => broadcast,
+                    None => {
+                        // Create builder
+                        let builder: EventBuilder =
+                            EventBuilder::text_note(&notification.plain_text)
+                                .tags(tags(
+                                    &notification,
+                                    &nostr.expiration,
+                                    public_key,
+                                    root,
+                                    Timestamp::now(),
+                                ))
+                                .pow(nostr.pow_difficulty);
+
+                        match client.sign_event_builder(builder).await {
+                            Ok(event) => Broadcast::new(event),
+                            Err(e) => {
+                                tracing::error!("Impossible to sign notification {id}: {e}");
+                                let retry: Retry = delivery_failed(
+                                    config,
+                                    store,
+                                    &id,
+                                    notification,
+                                    e.to_string(),
+                                    RETRY_DELAY,
+                                );
+
+                                // Keep the creation order: don't send the next ones before this one
+                                if retry == Retry::Blocking {
+                                    break;
+                                }
+
+                                continue;
+                            }
+                        }
+                    }
+                };
 
                 // Send event
-                match client.send_event_builder(builder).await {
-                    Ok(_) => match store.delete_notification(id.as_str()) {
+                let errors: Vec<String> =
+                    deliveries.send(client, &nostr.relays, &mut broadcast).await;
+
+                if broadcast.acked.len() >= nostr.min_relay_acks {
+                    if let (Some(thread), None) = (&thread, root) {
+                        if let Err(e) = store.set_thread_root(
+                            &nostr.instance,
+                            thread,
+                            &broadcast.event.id.to_hex(),
+                        ) {
+                            tracing::error!("Impossible to save root of thread {thread}: {e}");
+                        }
+                    }
+
+                    match store.delete_notification(id.as_str()) {
                         Ok(_) => tracing::debug!("Notification {} deleted", id),
                         Err(error) => tracing::error!(
                             "Impossible to delete notification {}: {:#?}",
                             id,
                             error
                         ),
-                    },
-                    Err(e) => {
-                        tracing::error!("Impossible to send notification {id}: {e}")
+                    }
+
+                    if broadcast.acked.len() < nostr.relays.len() {
+                        deliveries.rebroadcast.push(broadcast);
+                    }
+                } else {
+                    tracing::error!(
+                        "Notification {id} acknowledged by {} relays, {} required: {}",
+                        broadcast.acked.len(),
+                        nostr.min_relay_acks,
+                        errors.join(", ")
+                    );
+                    let retry: Retry = delivery_failed(
+                        config,
+                        store,
+                        &id,
+                        notification,
+                        errors.join(", "),
+                        RETRY_DELAY,
+                    );
+
+                    // A requeued dead letter gets a new event, with a fresh expiration
+                    if retry != Retry::DeadLetter {
+                        deliveries.pending.insert(id, broadcast);
+                    }
+
+                    // Keep the creation order: don't send the next ones before this one
+                    if retry == Retry::Blocking {
+                        break;
                     }
                 }
             }
         }
 
         tracing::debug!("Wait for new notifications");
-        time::sleep(Duration::from_secs(60)).await;
+        tokio::select! {
+            _ = chain_state.changed(), if nostr.state_events => {}
+            _ = time::sleep(Duration::from_secs(60)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nostr_connect::signer::{
+        NostrConnectKeys, NostrConnectRemoteSigner, NostrConnectSignerActions,
+    };
+    use nostr_relay_builder::prelude::{RelayBuilderNip42, RelayBuilderNip42Mode};
+    use nostr_relay_builder::{LocalRelay, MockRelay, RelayBuilder};
+    use nostr_sdk::nips::nip46::NostrConnectRequest;
+    use nostr_sdk::TagKind;
+
+    use super::*;
+    use crate::primitives::Alert;
+
+    struct ApproveAll;
+
+    impl NostrConnectSignerActions for ApproveAll {
+        fn approve(&self, _public_key: &PublicKey, _req: &NostrConnectRequest) -> bool {
+            true
+        }
+    }
+
+    fn notification(kind: AlertKind, block_height: u64) -> Notification {
+        Notification::new(Target::Nostr, None, &Alert::new(kind, block_height), "", "")
+    }
+
+    #[test]
+    fn test_thread() {
+        assert_eq!(
+            thread(&notification(AlertKind::HalvingCountdown, 1_046_004)),
+            Some(String::from("halving_countdown:4"))
+        );
+        assert_eq!(
+            thread(&notification(AlertKind::HalvingCountdown, 1_049_000)),
+            thread(&notification(AlertKind::HalvingCountdown, 1_046_004))
+        );
+        assert_eq!(thread(&notification(AlertKind::Halving, 1_050_000)), None);
+    }
+
+    #[test]
+    fn test_tags() {
+        let author: PublicKey = Keys::generate().public_key();
+        let expiration = BTreeMap::from([(AlertKind::HighFee, 3600)]);
+        let now = Timestamp::from(1_700_000_000);
+
+        let tags: Vec<Tag> = super::tags(
+            &notification(AlertKind::HighFee, 840_000),
+            &expiration,
+            author,
+            None,
+            now,
+        );
+        assert_eq!(
+            tags,
+            vec![
+                Tag::hashtag("bitcoin"),
+                Tag::hashtag("fees"),
+                Tag::hashtag("mempool"),
+                Tag::expiration(Timestamp::from(1_700_003_600)),
+            ]
+        );
+
+        let root = EventId::all_zeros();
+        let tags: Vec<Tag> = super::tags(
+            &notification(AlertKind::HalvingCountdown, 1_046_004),
+            &expiration,
+            author,
+            Some(root),
+            now,
+        );
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags[2].kind(), TagKind::e());
+        assert_eq!(tags[2].content(), Some(root.to_hex().as_str()));
+        assert_eq!(tags[2].as_slice()[3], "root");
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::from_str("subscribe halving"),
+            Ok(Command::Subscribe(Topic::Kind(AlertKind::Halving)))
+        );
+        assert_eq!(
+            Command::from_str(" Subscribe  fees < 5"),
+            Ok(Command::Subscribe(Topic::FeesBelow(5.0)))
+        );
+        assert_eq!(
+            Command::from_str("subscribe fees>50.5"),
+            Ok(Command::Subscribe(Topic::FeesAbove(50.5)))
+        );
+        assert_eq!(
+            Command::from_str("unsubscribe all"),
+            Ok(Command::UnsubscribeAll)
+        );
+        assert_eq!(
+            Command::from_str("unsubscribe tx_dropped"),
+            Ok(Command::Unsubscribe(Topic::Kind(AlertKind::TxDropped)))
+        );
+        assert_eq!(Command::from_str("list"), Ok(Command::List));
+        assert!(Command::from_str("subscribe").is_err());
+        assert!(Command::from_str("subscribe moon").is_err());
+        assert!(Command::from_str("subscribe fees<cheap").is_err());
+        assert!(Command::from_str("subscribe fees<NaN").is_err());
+        assert!(Command::from_str("subscribe fees>inf").is_err());
+        assert!(Command::from_str("subscribe fees<-5").is_err());
+        assert!(Command::from_str("list all").is_err());
+        assert!(Command::from_str("gm").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut subscription = Subscription {
+            nip04: true,
+            ..Default::default()
+        };
+
+        apply(
+            Command::Subscribe(Topic::Kind(AlertKind::Halving)),
+            &mut subscription,
+        );
+        apply(Command::Subscribe(Topic::FeesBelow(5.0)), &mut subscription);
+        assert_eq!(
+            apply(Command::List, &mut subscription),
+            "Subscribed to: halving, fees<5"
+        );
+
+        apply(Command::Unsubscribe(Topic::Fees), &mut subscription);
+        assert_eq!(subscription.fees_below, None);
+        assert!(!subscription.is_empty());
+
+        apply(Command::UnsubscribeAll, &mut subscription);
+        assert!(subscription.is_empty());
+        assert!(subscription.nip04);
+        assert_eq!(apply(Command::List, &mut subscription), "No subscriptions");
+    }
+
+    #[tokio::test]
+    async fn test_bunker_signer() {
+        let relay = MockRelay::run().await.unwrap();
+        let user = Keys::generate();
+        let remote = NostrConnectRemoteSigner::new(
+            NostrConnectKeys {
+                signer: Keys::generate(),
+                user: user.clone(),
+            },
+            [relay.url()],
+            Some(String::from("secret")),
+            None,
+        )
+        .unwrap();
+        let uri = remote.bunker_uri();
+        tokio::spawn(async move { remote.serve(ApproveAll).await });
+        time::sleep(Duration::from_millis(500)).await;
+
+        let app_key_file =
+            std::env::temp_dir().join(format!("nostr-connect-{}.key", std::process::id()));
+        let client: Client = client(
+            Signer::Bunker {
+                uri: uri.clone(),
+                app_key_file: app_key_file.clone(),
+            },
+            true,
+        )
+        .unwrap();
+
+        let event: Event = client
+            .sign_event_builder(EventBuilder::text_note("gm"))
+            .await
+            .unwrap();
+        assert_eq!(event.pubkey, user.public_key());
+        assert!(event.verify().is_ok());
+
+        // Same client keys after a restart
+        let app_key: String = std::fs::read_to_string(&app_key_file).unwrap();
+        let client: Client = super::client(
+            Signer::Bunker {
+                uri,
+                app_key_file: app_key_file.clone(),
+            },
+            true,
+        )
+        .unwrap();
+        let public_key: PublicKey = client
+            .signer()
+            .await
+            .unwrap()
+            .get_public_key()
+            .await
+            .unwrap();
+        assert_eq!(public_key, user.public_key());
+        assert_eq!(std::fs::read_to_string(&app_key_file).unwrap(), app_key);
+        std::fs::remove_file(&app_key_file).unwrap();
+    }
+
+    #[test]
+    fn test_record() {
+        let keys = Keys::generate();
+        let event: Event = EventBuilder::text_note("gm").sign_with_keys(&keys).unwrap();
+        let relay1 = RelayUrl::parse("wss://relay1.example").unwrap();
+        let relay2 = RelayUrl::parse("wss://relay2.example").unwrap();
+        let mut deliveries = Deliveries::default();
+        let mut broadcast = Broadcast::new(event);
+
+        let errors: Vec<String> = deliveries.record(
+            &mut broadcast,
+            HashSet::from([relay1.clone()]),
+            HashMap::from([(relay2.clone(), String::from("timeout"))]),
+        );
+        assert_eq!(errors, vec![String::from("wss://relay2.example: timeout")]);
+        assert_eq!(broadcast.acked, HashSet::from([relay1.clone()]));
+
+        deliveries.record(
+            &mut broadcast,
+            HashSet::from([relay2.clone()]),
+            HashMap::new(),
+        );
+        assert_eq!(broadcast.acked.len(), 2);
+        assert_eq!(broadcast.attempts, 2);
+        assert_eq!(deliveries.relays[&relay1].acked, 1);
+        assert_eq!(deliveries.relays[&relay2].acked, 1);
+        assert_eq!(deliveries.relays[&relay2].failed, 1);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let keys = Keys::generate();
+        let event: Event = EventBuilder::text_note("gm").sign_with_keys(&keys).unwrap();
+        let relay = RelayUrl::parse("wss://relay.example").unwrap();
+        let mut deliveries = Deliveries::default();
+        let mut broadcast = Broadcast::new(event);
+
+        deliveries.record(
+            &mut broadcast,
+            HashSet::new(),
+            HashMap::from([(relay.clone(), String::from("timeout"))]),
+        );
+        deliveries.relays.get_mut(&relay).unwrap().status = Some(RelayStatus::Connected);
+        deliveries.pending.insert(String::from("id"), broadcast);
+
+        let metrics: String = deliveries.metrics("nostr\"1");
+        assert!(metrics.contains(
+            "bitcoin_alerts_nostr_relay_connected{instance=\"nostr\\\"1\",relay=\"wss://relay.example\"} 1\n"
+        ));
+        assert!(metrics.contains("# TYPE bitcoin_alerts_nostr_relay_acked_total counter\n"));
+        assert!(metrics.contains(
+            "bitcoin_alerts_nostr_relay_failed_total{instance=\"nostr\\\"1\",relay=\"wss://relay.example\"} 1\n"
+        ));
+        assert!(metrics.contains("bitcoin_alerts_nostr_pending{instance=\"nostr\\\"1\"} 1\n"));
+        assert!(metrics.contains("bitcoin_alerts_nostr_rebroadcast{instance=\"nostr\\\"1\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send_nip42() {
+        let relay = LocalRelay::run(RelayBuilder::default().nip42(RelayBuilderNip42 {
+            mode: RelayBuilderNip42Mode::Write,
+        }))
+        .await
+        .unwrap();
+        let url = RelayUrl::parse(&relay.url()).unwrap();
+        let relays: HashSet<RelayUrl> = HashSet::from([url.clone()]);
+
+        let client: Client = client(Signer::Keys(Keys::generate()), true).unwrap();
+        client.add_relay(&url).await.unwrap();
+        client.connect().await;
+
+        let event: Event = client
+            .sign_event_builder(EventBuilder::text_note("gm"))
+            .await
+            .unwrap();
+        let mut deliveries = Deliveries::default();
+        let mut broadcast = Broadcast::new(event);
+
+        let errors: Vec<String> = deliveries.send(&client, &relays, &mut broadcast).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(broadcast.acked, relays);
+
+        // Already acknowledged by all the relays
+        deliveries.send(&client, &relays, &mut broadcast).await;
+        assert_eq!(broadcast.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_round_trip() {
+        let relay = LocalRelay::run(RelayBuilder::default()).await.unwrap();
+        let bot_keys = Keys::generate();
+        let user_keys = Keys::generate();
+        let bot: Client = client(Signer::Keys(bot_keys.clone()), true).unwrap();
+        let user: Client = client(Signer::Keys(user_keys.clone()), true).unwrap();
+        for client in [&bot, &user] {
+            client.add_relay(relay.url()).await.unwrap();
+            client.connect().await;
+        }
+
+        for (nip04, kind) in [
+            (false, Kind::GiftWrap),
+            (true, Kind::EncryptedDirectMessage),
+        ] {
+            let text: String = format!("Halving! (nip04: {nip04})");
+            send_direct_message(&bot, user_keys.public_key(), &text, nip04)
+                .await
+                .unwrap();
+
+            let filter = Filter::new().pubkey(user_keys.public_key()).kind(kind);
+            let events = user
+                .fetch_events(filter, Duration::from_secs(5))
+                .await
+                .unwrap();
+            let message: DirectMessage = DirectMessage::decrypt(&user, events.first().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.sender, bot_keys.public_key());
+            assert_eq!(message.content, text);
+            assert_eq!(message.nip04, nip04);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_inbox_relays() {
+        let relay = LocalRelay::run(RelayBuilder::default()).await.unwrap();
+        let inbox = LocalRelay::run(RelayBuilder::default()).await.unwrap();
+        let inbox_url = RelayUrl::parse(&inbox.url()).unwrap();
+        let user_keys = Keys::generate();
+        let bot: Client = client(Signer::Keys(Keys::generate()), true).unwrap();
+        let user: Client = client(Signer::Keys(user_keys.clone()), true).unwrap();
+        for client in [&bot, &user] {
+            client.add_relay(relay.url()).await.unwrap();
+            client.connect().await;
+        }
+
+        let relay_list = EventBuilder::new(Kind::InboxRelays, "").tag(Tag::from_standardized(
+            TagStandard::Relay(inbox_url.clone()),
+        ));
+        user.send_event_builder(relay_list).await.unwrap();
+
+        send_direct_message(&bot, user_keys.public_key(), "Halving!", false)
+            .await
+            .unwrap();
+
+        // Only on the inbox relay of the user
+        let filter = Filter::new()
+            .pubkey(user_keys.public_key())
+            .kind(Kind::GiftWrap);
+        let events = user
+            .fetch_events(filter.clone(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+        user.add_relay(&inbox_url).await.unwrap();
+        user.connect_relay(&inbox_url).await.unwrap();
+        let events = user
+            .fetch_events_from([inbox_url], filter, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+
+        // Not used for the notes
+        assert_eq!(bot.relays().await.len(), 1);
+    }
+
+    fn chain_state() -> ChainState {
+        ChainState {
+            block_height: 840_000,
+            difficulty: 86_388_558_925_171.02,
+            hashrate: 6.2e20,
+            blocks_to_halving: 210_000,
+            next_block_fee_rate: None,
+            mempool_min_fee: None,
+        }
+    }
+
+    #[test]
+    fn test_state_events() {
+        let now = Timestamp::from(1_713_571_767);
+        let events = state_events(&chain_state(), now);
+        let metrics: Vec<&str> = events.iter().map(|(metric, _)| *metric).collect();
+        assert_eq!(
+            metrics,
+            vec!["block_height", "difficulty", "hashrate", "halving"]
+        );
+        assert_eq!(events[3].1["halving_height"], 1_050_000);
+        assert_eq!(events[3].1["eta"], 1_713_571_767 + 210_000 * 600);
+
+        let state = ChainState {
+            next_block_fee_rate: Some(12.5),
+            ..chain_state()
+        };
+        let events = state_events(&state, now);
+        assert_eq!(events[4].0, "fees");
+        assert_eq!(events[4].1["next_block_fee_rate"], 12.5);
+        assert_eq!(events[4].1["mempool_min_fee"], Value::Null);
+    }
+
+    #[test]
+    fn test_state_changes() {
+        let now = Timestamp::from(1_713_571_767);
+        let published: HashMap<&'static str, Value> = state_events(&chain_state(), now)
+            .iter()
+            .map(|(metric, content)| (*metric, state_key(content)))
+            .collect();
+
+        // The halving eta moves with the current time, but isn't a change of the state
+        assert!(state_changes(&chain_state(), now + 60, &published).is_empty());
+
+        let state = ChainState {
+            next_block_fee_rate: Some(12.5),
+            ..chain_state()
+        };
+        let metrics: Vec<&str> = state_changes(&state, now, &published)
+            .iter()
+            .map(|(metric, _)| *metric)
+            .collect();
+        assert_eq!(metrics, vec!["fees"]);
+
+        let state = ChainState {
+            block_height: 840_001,
+            blocks_to_halving: 209_999,
+            ..chain_state()
+        };
+        assert_eq!(state_changes(&state, now, &published).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_publish_state() {
+        let relay = MockRelay::run().await.unwrap();
+        let keys = Keys::generate();
+        let client: Client = client(Signer::Keys(keys.clone()), true).unwrap();
+        client.add_relay(relay.url()).await.unwrap();
+        client.connect().await;
+
+        let mut published = HashMap::new();
+        publish_state(&client, &chain_state(), &mut published).await;
+        assert_eq!(published.len(), 4);
+
+        let filter = Filter::new()
+            .author(keys.public_key())
+            .kind(Kind::ApplicationSpecificData)
+            .identifier("bitcoin_alerts/block_height");
+        let events = client
+            .fetch_events(filter, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+
+        let content: Value = serde_json::from_str(&events.first().unwrap().content).unwrap();
+        assert_eq!(content["block_height"], 840_000);
+    }
+
+    #[tokio::test]
+    async fn test_publish_state_rejected() {
+        let relay = LocalRelay::run(RelayBuilder::default().nip42(RelayBuilderNip42 {
+            mode: RelayBuilderNip42Mode::Write,
+        }))
+        .await
+        .unwrap();
+        let client: Client = client(Signer::Keys(Keys::generate()), false).unwrap();
+        client.add_relay(relay.url()).await.unwrap();
+        client.connect().await;
+
+        // Not authenticated: every event is rejected, and must be published again
+        let mut published = HashMap::new();
+        publish_state(&client, &chain_state(), &mut published).await;
+        assert!(published.is_empty());
     }
 }
@@ -3,74 +3,267 @@
 
 use std::time::Duration;
 
-use nostr_sdk::Result;
+use futures::future;
+use nostr_sdk::{Result, Url};
 use ntfy::dispatcher::Async;
+use ntfy::payload::{Action, ActionType};
 use ntfy::{Dispatcher, DispatcherBuilder, Payload};
+use serde_json::Value;
 use tokio::time;
 
+use super::process_notifications;
+use crate::config::model::Ntfy;
 use crate::config::Config;
-use crate::db::NotificationStore;
-use crate::primitives::Target;
+use crate::db::{Notification, NotificationStore};
+use crate::primitives::{AlertKind, Priority, Severity, Target};
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+/// Emoji tag of the alert kind
+fn default_tag(kind: AlertKind) -> &'static str {
+    match kind {
+        AlertKind::Halving => "tada",
+        AlertKind::BlockReward => "pick",
+        AlertKind::HalvingCountdown => "hourglass_flowing_sand",
+        AlertKind::Difficulty => "gear",
+        AlertKind::Hashrate => "zap",
+        AlertKind::Block => "bricks",
+        AlertKind::RoundBlock => "100",
+        AlertKind::PalindromeBlock => "repeat",
+        AlertKind::Supply => "coin",
+        AlertKind::Reorg => "rotating_light",
+        AlertKind::LowFee => "green_circle",
+        AlertKind::HighFee => "red_circle",
+        AlertKind::MempoolPurging => "broom",
+        AlertKind::WatchReceived => "inbox_tray",
+        AlertKind::WatchSpent => "outbox_tray",
+        AlertKind::TxConfirmed => "white_check_mark",
+        AlertKind::TxReplaced => "arrows_counterclockwise",
+        AlertKind::TxDropped => "x",
+    }
+}
+
+/// Priority of the matching route, or of the alert kind
+fn priority(ntfy: &Ntfy, notification: &Notification) -> Option<Priority> {
+    notification.priority.or_else(|| {
+        let kind: AlertKind = notification.kind?;
+        ntfy.priorities
+            .get(&kind)
+            .copied()
+            .or((kind.severity() == Severity::Critical).then_some(Priority::High))
+    })
+}
+
+/// Plain text followed by the alert values
+fn markdown(notification: &Notification) -> String {
+    let mut message: String = notification.plain_text.clone();
+
+    if !notification.values.is_empty() {
+        message.push('\n');
+    }
+
+    for (name, value) in notification.values.iter() {
+        let value: String = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        message.push_str(&format!("\n- **{}**: {value}", name.replace('_', " ")));
+    }
+
+    message
+}
+
+fn payload(ntfy: &Ntfy, notification: &Notification) -> Result<Payload> {
+    let mut payload = Payload::new(&ntfy.topic).title("Bitcoin Alerts");
+
+    payload = if ntfy.markdown {
+        payload.message(markdown(notification)).markdown(true)
+    } else {
+        payload.message(&notification.plain_text)
+    };
+
+    if let Some(priority) = priority(ntfy, notification) {
+        payload = payload.priority(match priority {
+            Priority::Min => ntfy::Priority::Min,
+            Priority::Low => ntfy::Priority::Low,
+            Priority::Default => ntfy::Priority::Default,
+            Priority::High => ntfy::Priority::High,
+            Priority::Max => ntfy::Priority::Max,
+        });
+    }
+
+    if let Some(kind) = notification.kind {
+        let tags: Vec<String> = match ntfy.tags.get(&kind) {
+            Some(tags) => tags.clone(),
+            None => vec![default_tag(kind).to_string(), kind.to_string()],
+        };
+        if !tags.is_empty() {
+            payload = payload.tags(tags);
+        }
+    }
+
+    if let Some(explorer_url) = &ntfy.explorer_url {
+        let explorer_url: &str = explorer_url.as_str().trim_end_matches('/');
+        let mut actions: Vec<Action> = Vec::new();
+
+        if notification.block_height > 0 {
+            let url: Url = Url::parse(&format!(
+                "{explorer_url}/block/{}",
+                notification.block_height
+            ))?;
+            actions.push(Action::new(ActionType::View, "View block", url));
+        }
+
+        if let Some(Value::String(txid)) = notification.values.get("txid") {
+            let url: Url = Url::parse(&format!("{explorer_url}/tx/{txid}"))?;
+            actions.push(Action::new(ActionType::View, "View transaction", url));
+        }
+
+        // Open the transaction, if any, otherwise the block
+        if let Some(action) = actions.last() {
+            payload = payload.click(action.url.clone());
+        }
+
+        if !actions.is_empty() {
+            payload = payload.actions(actions);
+        }
+    }
+
+    Ok(payload)
+}
 
 pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    let instances: Vec<_> = config
+        .ntfy
+        .iter()
+        .filter(|ntfy| ntfy.enabled)
+        .map(|ntfy| Box::pin(run_instance(config, ntfy, store)))
+        .collect();
+
     // If not enabled, infinite loop
-    if !config.ntfy.enabled {
+    if instances.is_empty() {
         loop {
             time::sleep(Duration::from_secs(60)).await;
         }
     }
 
-    let mut dispatcher = DispatcherBuilder::new(&config.ntfy.url);
+    // Stop as soon as one of the instances stops
+    let (result, ..) = future::select_all(instances).await;
+    result
+}
+
+async fn run_instance(config: &Config, ntfy: &Ntfy, store: &NotificationStore) -> Result<()> {
+    let mut dispatcher = DispatcherBuilder::new(&ntfy.url);
 
-    if let Some(credentials) = &config.ntfy.auth {
+    if let Some(credentials) = &ntfy.auth {
         dispatcher = dispatcher.credentials(credentials.clone());
     }
 
-    if let Some(proxy) = &config.ntfy.proxy {
+    if let Some(proxy) = &ntfy.proxy {
         dispatcher = dispatcher.proxy(proxy);
     }
 
     let dispatcher: Dispatcher<Async> = dispatcher.build_async()?;
 
-    tracing::info!("Ntfy Dispatcher started");
+    tracing::info!("Ntfy Dispatcher started ({})", ntfy.instance);
 
-    loop {
-        tracing::debug!("Process pending notifications");
-
-        let notifications = match store.get_notifications_by_target(Target::Ntfy) {
-            Ok(result) => result,
-            Err(error) => {
-                tracing::error!("Impossible to get ntfy notifications from db: {:?}", error);
-                time::sleep(Duration::from_secs(60)).await;
-                continue;
-            }
-        };
+    // The topic is the password of public servers: log the instance instead
+    let topic: [(String, ()); 1] = [(ntfy.instance.clone(), ())];
 
-        for (id, notification) in notifications.into_iter() {
-            let payload = Payload::new(&config.ntfy.topic)
-                .message(&notification.plain_text)
-                .title("Bitcoin Alerts");
-
-            match dispatcher.send(&payload).await {
-                Ok(_) => {
-                    tracing::info!("Sent notification: {}", notification.plain_text);
-
-                    match store.delete_notification(id.as_str()) {
-                        Ok(_) => tracing::debug!("Notification {} deleted", id),
-                        Err(error) => tracing::error!(
-                            "Impossible to delete notification {}: {:#?}",
-                            id,
-                            error
-                        ),
-                    };
-                }
-                Err(err) => {
-                    tracing::error!("Impossible to send notification {}: {:?}", id, err)
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Ntfy,
+            &ntfy.instance,
+            RETRY_DELAY,
+            &topic,
+            |_, _, notification| {
+                let dispatcher: &Dispatcher<Async> = &dispatcher;
+                async move {
+                    dispatcher.send(&payload(ntfy, &notification)?).await?;
+                    Ok(())
                 }
-            };
-        }
+            },
+        )
+        .await;
 
         tracing::debug!("Wait for new notifications");
         time::sleep(Duration::from_secs(30)).await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::locale::Locale;
+    use crate::primitives::Alert;
+
+    fn ntfy() -> Ntfy {
+        Ntfy {
+            instance: String::from("ntfy"),
+            enabled: true,
+            locale: Locale::En,
+            url: String::from("https://ntfy.sh"),
+            topic: String::from("bitcoin_alerts"),
+            auth: None,
+            proxy: None,
+            priorities: BTreeMap::from([(AlertKind::Halving, Priority::Max)]),
+            tags: BTreeMap::from([(AlertKind::Block, Vec::new())]),
+            explorer_url: Some(Url::parse("https://mempool.space/testnet/").unwrap()),
+            markdown: true,
+        }
+    }
+
+    fn notification(kind: AlertKind) -> Notification {
+        let alert = Alert::new(kind, 840_000).value("txid", "abcd");
+        Notification::new(Target::Ntfy, None, &alert, "Alert", "Alert")
+    }
+
+    #[test]
+    fn test_priority() {
+        let ntfy = ntfy();
+        let mut halving = notification(AlertKind::Halving);
+        assert_eq!(priority(&ntfy, &halving), Some(Priority::Max));
+        assert_eq!(
+            priority(&ntfy, &notification(AlertKind::Reorg)),
+            Some(Priority::High)
+        );
+        assert_eq!(priority(&ntfy, &notification(AlertKind::Block)), None);
+
+        // The route wins
+        halving.priority = Some(Priority::Low);
+        assert_eq!(priority(&ntfy, &halving), Some(Priority::Low));
+    }
+
+    #[test]
+    fn test_payload() {
+        let ntfy = ntfy();
+
+        let payload = payload(&ntfy, &notification(AlertKind::TxConfirmed)).unwrap();
+        assert_eq!(payload.message, "Alert\n\n- **txid**: abcd");
+        assert_eq!(
+            payload.tags,
+            Some(vec![
+                String::from("white_check_mark"),
+                String::from("tx_confirmed")
+            ])
+        );
+        assert_eq!(
+            payload.click.unwrap().as_str(),
+            "https://mempool.space/testnet/tx/abcd"
+        );
+        let actions = payload.actions.unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(
+            actions[0].url.as_str(),
+            "https://mempool.space/testnet/block/840000"
+        );
+
+        let payload = super::payload(&ntfy, &notification(AlertKind::Block)).unwrap();
+        assert_eq!(payload.tags, None);
+    }
+}
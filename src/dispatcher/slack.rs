@@ -0,0 +1,203 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use nostr_sdk::{Result, Url};
+use reqwest::{Client, Proxy, StatusCode};
+use serde_json::{json, Value};
+use tokio::time;
+
+use super::{alert_color, http_error, process_notifications, retry_after, url_label, RateLimited};
+use crate::config::model::Slack;
+use crate::config::Config;
+use crate::db::{Notification, NotificationStore};
+use crate::locale::Locale;
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+/// Escape the control characters of Slack `mrkdwn`
+fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn message(notification: &Notification, locale: Locale) -> Value {
+    json!({
+        // Fallback for notifications
+        "text": notification.plain_text,
+        "attachments": [{
+            "color": format!("#{:06X}", alert_color(notification.kind)),
+            "blocks": [
+                {
+                    "type": "section",
+                    "text": {
+                        "type": "mrkdwn",
+                        "text": escape_mrkdwn(&notification.plain_text),
+                    },
+                },
+                {
+                    "type": "context",
+                    "elements": [{
+                        "type": "mrkdwn",
+                        "text": locale.block(notification.block_height),
+                    }],
+                },
+            ],
+        }],
+    })
+}
+
+struct SlackClient {
+    client: Client,
+    locale: Locale,
+}
+
+impl SlackClient {
+    fn new(config: &Slack) -> Result<Self> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            locale: config.locale,
+        })
+    }
+
+    async fn post_message(&self, url: &Url, notification: &Notification) -> Result<()> {
+        let res = self
+            .client
+            .post(url.clone())
+            .json(&message(notification, self.locale))
+            .send()
+            .await
+            // The URL contains the webhook token: keep it out of the errors
+            .map_err(|e| e.without_url())?;
+        let status: StatusCode = res.status();
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Box::new(RateLimited(retry_after(res.headers()))));
+        }
+
+        if status.is_success() {
+            Ok(())
+        } else {
+            let res: String = res.text().await.unwrap_or_default();
+            Err(http_error(status, &res))
+        }
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.slack.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: SlackClient = SlackClient::new(&config.slack)?;
+
+    tracing::info!("Slack Dispatcher started");
+
+    let webhook_urls: Vec<(String, Url)> = config
+        .slack
+        .webhook_urls
+        .iter()
+        .map(|url| (url_label(Target::Slack.as_str(), url), url.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Slack,
+            Target::Slack.as_str(),
+            RETRY_DELAY,
+            &webhook_urls,
+            |url, _, notification| {
+                let client: &SlackClient = &client;
+                async move { client.post_message(url, &notification).await }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispatcher::mock::{MockServer, Request, Response};
+    use crate::primitives::{Alert, AlertKind};
+
+    fn notification() -> Notification {
+        Notification::new(
+            Target::Slack,
+            None,
+            &Alert::new(AlertKind::Halving, 840_000),
+            "Halving <4> & done",
+            "",
+        )
+    }
+
+    #[test]
+    fn test_message() {
+        let message: Value = message(&notification(), Locale::It);
+        assert_eq!(message["text"], "Halving <4> & done");
+
+        let attachment: &Value = &message["attachments"][0];
+        assert_eq!(attachment["color"], "#F7931A");
+        assert_eq!(
+            attachment["blocks"][0]["text"]["text"],
+            "Halving &lt;4&gt; &amp; done"
+        );
+        assert_eq!(
+            attachment["blocks"][1]["elements"][0]["text"],
+            "Blocco 840.000"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_message() {
+        let server = MockServer::new();
+        let url: Url = server.url().join("/services/T0/B0/token").unwrap();
+        let client = SlackClient::new(&Slack {
+            enabled: true,
+            locale: Locale::En,
+            webhook_urls: vec![url.clone()],
+            proxy: None,
+        })
+        .unwrap();
+        let server = server.serve(vec![
+            Response::new("200 OK", "ok"),
+            Response::new("429 Too Many Requests", "rate_limited").header("Retry-After", "30"),
+        ]);
+
+        client.post_message(&url, &notification()).await.unwrap();
+
+        let err = client
+            .post_message(&url, &notification())
+            .await
+            .unwrap_err();
+        let RateLimited(delay) = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(*delay, Duration::from_secs(30));
+
+        let requests: Vec<Request> = server.join().unwrap();
+        assert_eq!(
+            requests[0].request_line,
+            "POST /services/T0/B0/token HTTP/1.1"
+        );
+        assert_eq!(requests[0].json(), message(&notification(), Locale::En));
+    }
+}
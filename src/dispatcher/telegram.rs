@@ -0,0 +1,272 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use nostr_sdk::{Result, Url};
+use reqwest::{Client, Proxy, StatusCode};
+use serde_json::{json, Value};
+use tokio::time;
+
+use super::{http_error, process_notifications, RateLimited, DEFAULT_RETRY_AFTER};
+use crate::config::model::Telegram;
+use crate::config::Config;
+use crate::db::NotificationStore;
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+struct TelegramClient {
+    client: Client,
+    api_url: Url,
+    bot_token: String,
+    message_thread_id: Option<i64>,
+}
+
+impl TelegramClient {
+    fn new(config: &Telegram) -> Result<Self> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            api_url: config.api_url.clone(),
+            bot_token: config
+                .bot_token
+                .clone()
+                .ok_or("missing telegram bot token")?,
+            message_thread_id: config.message_thread_id,
+        })
+    }
+
+    fn endpoint(&self, method: &str) -> Result<Url> {
+        let mut url: Url = self.api_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| "Invalid telegram api url")?
+            .pop_if_empty()
+            .push(&format!("bot{}", self.bot_token))
+            .push(method);
+        Ok(url)
+    }
+
+    /// Send an HTML message with `sendMessage`
+    async fn send_message(&self, chat_id: &str, html: &str) -> Result<()> {
+        let url: Url = self.endpoint("sendMessage")?;
+        let mut body: Value = json!({
+            "chat_id": chat_id,
+            "text": html,
+            "parse_mode": "HTML",
+        });
+
+        if let Some(message_thread_id) = self.message_thread_id {
+            body["message_thread_id"] = json!(message_thread_id);
+        }
+
+        // The URL contains the bot token: keep it out of the errors
+        let res = self
+            .client
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        let status: StatusCode = res.status();
+        let res: Value = res.json().await.unwrap_or_default();
+
+        if status.is_success() && res.get("ok").and_then(|ok| ok.as_bool()) == Some(true) {
+            return Ok(());
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after: Duration = res
+                .pointer("/parameters/retry_after")
+                .and_then(|secs| secs.as_u64())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            return Err(Box::new(RateLimited(retry_after)));
+        }
+
+        // i.e. "can't parse entities" or "message is too long", with status 400
+        let description: &str = res
+            .get("description")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default();
+        Err(http_error(status, description))
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.telegram.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: TelegramClient = TelegramClient::new(&config.telegram)?;
+
+    tracing::info!("Telegram Dispatcher started");
+
+    let chat_ids: Vec<(String, String)> = config
+        .telegram
+        .chat_ids
+        .iter()
+        .map(|chat_id| (chat_id.clone(), chat_id.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Telegram,
+            Target::Telegram.as_str(),
+            RETRY_DELAY,
+            &chat_ids,
+            |chat_id, _, notification| {
+                let client: &TelegramClient = &client;
+                async move { client.send_message(chat_id, &notification.html).await }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::dispatcher::Rejected;
+    use crate::locale::Locale;
+
+    /// Serve a single Bot API request, returning the request line and body
+    fn mock_bot_api(
+        listener: TcpListener,
+        status: &'static str,
+        response: &'static str,
+    ) -> thread::JoinHandle<(String, Value)> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut content_length: usize = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+
+            (request_line, serde_json::from_slice(&body).unwrap())
+        })
+    }
+
+    fn client(listener: &TcpListener) -> TelegramClient {
+        let config = Telegram {
+            enabled: true,
+            locale: Locale::En,
+            api_url: Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap(),
+            bot_token: Some(String::from("123:abc")),
+            chat_ids: vec![String::from("-100")],
+            message_thread_id: Some(7),
+            proxy: None,
+        };
+        TelegramClient::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(&listener);
+        let server = mock_bot_api(
+            listener,
+            "200 OK",
+            r#"{"ok":true,"result":{"message_id":1}}"#,
+        );
+
+        client
+            .send_message("-100", "&lt;b&gt; 1 block")
+            .await
+            .unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /bot123:abc/sendMessage "));
+        assert_eq!(body["chat_id"], "-100");
+        assert_eq!(body["text"], "&lt;b&gt; 1 block");
+        assert_eq!(body["parse_mode"], "HTML");
+        assert_eq!(body["message_thread_id"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(&listener);
+        let server = mock_bot_api(
+            listener,
+            "400 Bad Request",
+            r#"{"ok":false,"error_code":400,"description":"Bad Request: chat not found"}"#,
+        );
+
+        let err = client.send_message("-100", "text").await.unwrap_err();
+        assert!(err.to_string().contains("chat not found"));
+        assert!(err.is::<Rejected>());
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_message_rate_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(&listener);
+        let server = mock_bot_api(
+            listener,
+            "429 Too Many Requests",
+            r#"{"ok":false,"error_code":429,"description":"Too Many Requests: retry after 7","parameters":{"retry_after":7}}"#,
+        );
+
+        let err = client.send_message("-100", "text").await.unwrap_err();
+        let RateLimited(delay) = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(*delay, Duration::from_secs(7));
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_message_error_without_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(&listener);
+        drop(listener);
+
+        let err = client.send_message("-100", "text").await.unwrap_err();
+        assert!(!err.to_string().contains("123:abc"));
+    }
+}
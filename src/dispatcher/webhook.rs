@@ -0,0 +1,252 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use nostr_sdk::{Result, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Proxy, StatusCode};
+use tokio::time;
+
+use super::{alert_payload, http_error, process_notifications, url_label};
+use crate::config::model::Webhook;
+use crate::config::Config;
+use crate::db::{Notification, NotificationStore};
+use crate::primitives::Target;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay, in secs, before retrying a failed notification
+const RETRY_DELAY: u64 = 30;
+
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+/// HMAC-SHA256 of `body`, as `sha256=<hex>`
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    let hmac = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+    format!("sha256={hmac}")
+}
+
+struct WebhookClient {
+    client: Client,
+    headers: HeaderMap,
+    secret: Option<String>,
+}
+
+impl WebhookClient {
+    fn new(config: &Webhook) -> Result<Self> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in config.headers.iter() {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            headers,
+            secret: config.secret.clone(),
+        })
+    }
+
+    async fn post(&self, url: &Url, body: &[u8]) -> Result<()> {
+        let mut req = self
+            .client
+            .post(url.clone())
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/json");
+
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, signature(secret, body));
+        }
+
+        // The URL may contain a token: keep it out of the errors
+        let res = req
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        let status: StatusCode = res.status();
+
+        if status.is_success() {
+            Ok(())
+        } else {
+            let res: String = res.text().await.unwrap_or_default();
+            Err(http_error(status, &res))
+        }
+    }
+
+    async fn send(&self, url: &Url, id: &str, notification: &Notification) -> Result<()> {
+        let body: Vec<u8> = serde_json::to_vec(&alert_payload(id, notification))?;
+        self.post(url, &body).await
+    }
+}
+
+pub async fn run(config: &Config, store: &NotificationStore) -> Result<()> {
+    // If not enabled, infinite loop
+    if !config.webhook.enabled {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    let client: WebhookClient = WebhookClient::new(&config.webhook)?;
+
+    tracing::info!("Webhook Dispatcher started");
+
+    let urls: Vec<(String, Url)> = config
+        .webhook
+        .urls
+        .iter()
+        .map(|url| (url_label(Target::Webhook.as_str(), url), url.clone()))
+        .collect();
+
+    loop {
+        process_notifications(
+            config,
+            store,
+            Target::Webhook,
+            Target::Webhook.as_str(),
+            RETRY_DELAY,
+            &urls,
+            |url, id, notification| {
+                let client: &WebhookClient = &client;
+                async move { client.send(url, &id, &notification).await }
+            },
+        )
+        .await;
+
+        tracing::debug!("Wait for new notifications");
+        time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::config::Args;
+    use crate::dispatcher::mock::{MockServer, Request, Response};
+    use crate::primitives::{Alert, AlertKind};
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = MockServer::new();
+        let url: Url = server.url().join("/bitcoin-alerts").unwrap();
+        let root =
+            std::env::temp_dir().join(format!("bitcoin-alerts-webhook-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let config_file = root.join("config.toml");
+        fs::write(
+            &config_file,
+            format!(
+                r#"
+                [bitcoin]
+                rpc_username = "username"
+                rpc_password = "password"
+
+                [webhook]
+                enabled = true
+                urls = ["{url}"]
+                headers = {{ Authorization = "Bearer token" }}
+                secret = "secret"
+                "#
+            ),
+        )
+        .unwrap();
+        let config = Config::from_args(&Args::parse_from([
+            "bitcoin-alerts",
+            "--config-file",
+            config_file.to_str().unwrap(),
+        ]));
+        let store = NotificationStore::open(&root.join("notification")).unwrap();
+        let alert = Alert::new(AlertKind::Halving, 840_000).value("epoch", 5);
+        store
+            .create_notification(Target::Webhook, "webhook", &alert, "Halving!", "")
+            .unwrap();
+
+        let client = WebhookClient::new(&config.webhook).unwrap();
+        let urls: Vec<(String, Url)> = vec![(String::from("webhook"), url)];
+        let process = || {
+            process_notifications(
+                &config,
+                &store,
+                Target::Webhook,
+                "webhook",
+                RETRY_DELAY,
+                &urls,
+                |url, id, notification| {
+                    let client: &WebhookClient = &client;
+                    async move { client.send(url, &id, &notification).await }
+                },
+            )
+        };
+        let server = server.serve(vec![
+            Response::new("500 Internal Server Error", "{}"),
+            Response::new("200 OK", "{}"),
+        ]);
+
+        // Server error: kept for a new attempt
+        process().await;
+        let (id, mut notification) = store
+            .get_notifications_by_target(Target::Webhook, "webhook")
+            .unwrap()
+            .pop_first()
+            .unwrap();
+        assert_eq!(notification.attempts, 1);
+        assert!(notification.last_error.as_ref().unwrap().contains("500"));
+
+        // Backoff elapsed
+        notification.next_attempt_at = 0;
+        store.update_notification(&id, &notification).unwrap();
+        process().await;
+        assert!(store
+            .get_notifications_by_target(Target::Webhook, "webhook")
+            .unwrap()
+            .is_empty());
+
+        let requests: Vec<Request> = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert_eq!(request.request_line, "POST /bitcoin-alerts HTTP/1.1");
+            assert_eq!(request.headers["authorization"], "Bearer token");
+            assert_eq!(request.headers["content-type"], "application/json");
+            assert_eq!(
+                request.headers["x-signature-256"],
+                signature("secret", &request.body)
+            );
+
+            let body = request.json();
+            assert_eq!(body["id"], id);
+            assert_eq!(body["kind"], "halving");
+            assert_eq!(body["block_height"], 840_000);
+            assert_eq!(body["values"]["epoch"], 5);
+            assert_eq!(body["plain_text"], "Halving!");
+        }
+
+        drop(store);
+        let _ = fs::remove_dir_all(root);
+    }
+}
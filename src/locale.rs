@@ -0,0 +1,303 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Localization of the alert messages
+
+use std::fmt;
+
+use crate::primitives::{AlertKind, Phrase, Var};
+use crate::util;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+    It,
+    Ja,
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::En => write!(f, "en"),
+            Self::Es => write!(f, "es"),
+            Self::It => write!(f, "it"),
+            Self::Ja => write!(f, "ja"),
+        }
+    }
+}
+
+impl Locale {
+    fn group_separator(&self) -> char {
+        match self {
+            Self::En | Self::Ja => ',',
+            Self::Es | Self::It => '.',
+        }
+    }
+
+    fn decimal_separator(&self) -> char {
+        match self {
+            Self::En | Self::Ja => '.',
+            Self::Es | Self::It => ',',
+        }
+    }
+
+    pub fn format_number(&self, num: u64) -> String {
+        util::format_number(num as usize, self.group_separator())
+    }
+
+    /// Format `num` with `decimals` decimal places
+    pub fn format_decimal(&self, num: f64, decimals: usize) -> String {
+        let formatted: String = format!("{:.*}", decimals, num.abs());
+        let (integer, fraction) = match formatted.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (formatted.as_str(), None),
+        };
+
+        let mut result: String = String::new();
+        if num < 0.0 && formatted.chars().any(|c| c.is_ascii_digit() && c != '0') {
+            result.push('-');
+        }
+        result.push_str(&util::format_number(
+            integer.parse().unwrap_or_default(),
+            self.group_separator(),
+        ));
+        if let Some(fraction) = fraction {
+            result.push(self.decimal_separator());
+            result.push_str(fraction);
+        }
+        result
+    }
+
+    /// Ordinal number, for the epoch (feminine in Spanish and Italian)
+    pub fn ordinal(&self, num: u64) -> String {
+        match self {
+            Self::En => {
+                let suffix: &str = match (num % 10, num % 100) {
+                    (_, 11..=13) => "th",
+                    (1, _) => "st",
+                    (2, _) => "nd",
+                    (3, _) => "rd",
+                    _ => "th",
+                };
+                format!("{num}{suffix}")
+            }
+            Self::Es => format!("{num}.ª"),
+            Self::It => format!("{num}ª"),
+            Self::Ja => format!("第{num}"),
+        }
+    }
+
+    fn confirmations(&self, num: u64) -> String {
+        match (self, num) {
+            (Self::En, 1) => String::from("1 confirmation"),
+            (Self::En, num) => format!("{num} confirmations"),
+            (Self::Es, 1) => String::from("1 confirmación"),
+            (Self::Es, num) => format!("{num} confirmaciones"),
+            (Self::It, 1) => String::from("1 conferma"),
+            (Self::It, num) => format!("{num} conferme"),
+            (Self::Ja, num) => format!("{num}承認"),
+        }
+    }
+
+    /// Block height, i.e. for the footer of the messages
+    pub fn block(&self, height: u64) -> String {
+        let height: String = self.format_number(height);
+        match self {
+            Self::En => format!("Block {height}"),
+            Self::Es => format!("Bloque {height}"),
+            Self::It => format!("Blocco {height}"),
+            Self::Ja => format!("ブロック{height}"),
+        }
+    }
+
+    /// Approximate duration
+    fn duration(&self, minutes: u64) -> String {
+        let (value, unit) = if minutes >= 2 * 24 * 60 {
+            (minutes / (24 * 60), 0)
+        } else if minutes >= 2 * 60 {
+            (minutes / 60, 1)
+        } else {
+            (minutes, 2)
+        };
+
+        match self {
+            Self::En => format!("~{value} {}", ["days", "hours", "minutes"][unit]),
+            Self::Es => format!("~{value} {}", ["días", "horas", "minutos"][unit]),
+            Self::It => format!("~{value} {}", ["giorni", "ore", "minuti"][unit]),
+            Self::Ja => format!("約{value}{}", ["日", "時間", "分"][unit]),
+        }
+    }
+
+    fn phrase(&self, phrase: Phrase) -> &'static str {
+        match phrase {
+            Phrase::ConflictingTx => match self {
+                Self::En => "a confirmed conflicting transaction",
+                Self::Es => "una transacción conflictiva confirmada",
+                Self::It => "una transazione in conflitto confermata",
+                Self::Ja => "承認済みの競合トランザクション",
+            },
+        }
+    }
+
+    pub fn format(&self, var: &Var) -> String {
+        match var {
+            Var::Text(text) => text.clone(),
+            Var::Integer(num) => self.format_number(*num),
+            Var::Decimal(num, decimals) => self.format_decimal(*num, *decimals),
+            Var::Btc(amount) => {
+                let formatted: String = self.format_decimal(amount.to_btc(), 8);
+                // Trim the trailing zeros of the fraction only
+                match formatted.split_once(self.decimal_separator()) {
+                    Some((integer, fraction)) => match fraction.trim_end_matches('0') {
+                        "" => integer.to_string(),
+                        fraction => format!("{integer}{}{fraction}", self.decimal_separator()),
+                    },
+                    None => formatted,
+                }
+            }
+            Var::Ordinal(num) => self.ordinal(*num),
+            Var::Confirmations(num) => self.confirmations(*num),
+            Var::Minutes(minutes) => self.duration(*minutes),
+            Var::Phrase(phrase) => self.phrase(*phrase).to_string(),
+        }
+    }
+
+    /// Default plain text template of `kind`
+    pub fn default_template(&self, kind: AlertKind) -> &'static str {
+        match self {
+            Self::En => match kind {
+                AlertKind::Halving => "⛏️ The Halving is here! Welcome to the {epoch_ordinal} epoch! ⛏️",
+                AlertKind::BlockReward => "⛏️ New block reward: {block_reward} BTC ⛏️",
+                AlertKind::HalvingCountdown => "🔥 {blocks_left} blocks to the next Halving 🔥",
+                AlertKind::Difficulty => "⛏️ Difficulty adj: {difficulty}T ({change_pct}%) ⛏️",
+                AlertKind::Hashrate => "🎉  New hashrate ATH: {hashrate} EH/s 🎉",
+                AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => "⛓️ Reached block {height} ⛓️",
+                AlertKind::Supply => "🎊 The supply has just reached {milestone} BTC 🎊",
+                AlertKind::Reorg => "⚠️ Reorg of depth {depth} detected! Old tip: {old_height} ({old_tip}) - New tip: {new_height} ({new_tip}) ⚠️",
+                AlertKind::LowFee => "💸 Low fees: next block fee estimate is {fee_rate} sat/vB 💸",
+                AlertKind::HighFee => "🔥 Fee spike: median fee rate of block {height} is {fee_rate} sat/vB 🔥",
+                AlertKind::MempoolPurging => "🧹 Mempool is full: purging transactions below {min_fee} sat/vB 🧹",
+                AlertKind::WatchReceived => "📥 {label}: received {amount} BTC in {txid} ({confirmations_text}) 📥",
+                AlertKind::WatchSpent => "📤 {label}: spent {amount} BTC in {txid} ({confirmations_text}) 📤",
+                AlertKind::TxConfirmed => "✅ Transaction {txid} has {confirmations_text} ✅",
+                AlertKind::TxReplaced => "🔁 Transaction {txid} replaced by {replacement} 🔁",
+                AlertKind::TxDropped => "❌ Transaction {txid} dropped out of the mempool ❌",
+            },
+            Self::Es => match kind {
+                AlertKind::Halving => "⛏️ ¡Llegó el Halving! ¡Bienvenidos a la {epoch_ordinal} época! ⛏️",
+                AlertKind::BlockReward => "⛏️ Nueva recompensa por bloque: {block_reward} BTC ⛏️",
+                AlertKind::HalvingCountdown => "🔥 {blocks_left} bloques para el próximo Halving 🔥",
+                AlertKind::Difficulty => "⛏️ Ajuste de dificultad: {difficulty}T ({change_pct}%) ⛏️",
+                AlertKind::Hashrate => "🎉  Nuevo máximo histórico de hashrate: {hashrate} EH/s 🎉",
+                AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => "⛓️ Alcanzado el bloque {height} ⛓️",
+                AlertKind::Supply => "🎊 La oferta acaba de alcanzar {milestone} BTC 🎊",
+                AlertKind::Reorg => "⚠️ ¡Reorganización de profundidad {depth} detectada! Punta anterior: {old_height} ({old_tip}) - Nueva punta: {new_height} ({new_tip}) ⚠️",
+                AlertKind::LowFee => "💸 Comisiones bajas: la estimación para el próximo bloque es {fee_rate} sat/vB 💸",
+                AlertKind::HighFee => "🔥 Subida de comisiones: la comisión mediana del bloque {height} es {fee_rate} sat/vB 🔥",
+                AlertKind::MempoolPurging => "🧹 Mempool llena: se purgan las transacciones por debajo de {min_fee} sat/vB 🧹",
+                AlertKind::WatchReceived => "📥 {label}: recibidos {amount} BTC en {txid} ({confirmations_text}) 📥",
+                AlertKind::WatchSpent => "📤 {label}: gastados {amount} BTC en {txid} ({confirmations_text}) 📤",
+                AlertKind::TxConfirmed => "✅ La transacción {txid} tiene {confirmations_text} ✅",
+                AlertKind::TxReplaced => "🔁 La transacción {txid} fue reemplazada por {replacement} 🔁",
+                AlertKind::TxDropped => "❌ La transacción {txid} salió de la mempool ❌",
+            },
+            Self::It => match kind {
+                AlertKind::Halving => "⛏️ L'Halving è arrivato! Benvenuti nella {epoch_ordinal} epoca! ⛏️",
+                AlertKind::BlockReward => "⛏️ Nuova ricompensa per blocco: {block_reward} BTC ⛏️",
+                AlertKind::HalvingCountdown => "🔥 {blocks_left} blocchi al prossimo Halving 🔥",
+                AlertKind::Difficulty => "⛏️ Aggiustamento della difficoltà: {difficulty}T ({change_pct}%) ⛏️",
+                AlertKind::Hashrate => "🎉  Nuovo massimo storico dell'hashrate: {hashrate} EH/s 🎉",
+                AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => "⛓️ Raggiunto il blocco {height} ⛓️",
+                AlertKind::Supply => "🎊 L'offerta ha appena raggiunto {milestone} BTC 🎊",
+                AlertKind::Reorg => "⚠️ Riorganizzazione di profondità {depth} rilevata! Punta precedente: {old_height} ({old_tip}) - Nuova punta: {new_height} ({new_tip}) ⚠️",
+                AlertKind::LowFee => "💸 Commissioni basse: la stima per il prossimo blocco è {fee_rate} sat/vB 💸",
+                AlertKind::HighFee => "🔥 Impennata delle commissioni: la commissione mediana del blocco {height} è {fee_rate} sat/vB 🔥",
+                AlertKind::MempoolPurging => "🧹 Mempool piena: eliminate le transazioni sotto {min_fee} sat/vB 🧹",
+                AlertKind::WatchReceived => "📥 {label}: ricevuti {amount} BTC in {txid} ({confirmations_text}) 📥",
+                AlertKind::WatchSpent => "📤 {label}: spesi {amount} BTC in {txid} ({confirmations_text}) 📤",
+                AlertKind::TxConfirmed => "✅ La transazione {txid} ha {confirmations_text} ✅",
+                AlertKind::TxReplaced => "🔁 La transazione {txid} è stata sostituita da {replacement} 🔁",
+                AlertKind::TxDropped => "❌ La transazione {txid} è uscita dalla mempool ❌",
+            },
+            Self::Ja => match kind {
+                AlertKind::Halving => "⛏️ 半減期が来ました！{epoch_ordinal}エポックへようこそ！ ⛏️",
+                AlertKind::BlockReward => "⛏️ 新しいブロック報酬: {block_reward} BTC ⛏️",
+                AlertKind::HalvingCountdown => "🔥 次の半減期まであと{blocks_left}ブロック 🔥",
+                AlertKind::Difficulty => "⛏️ 難易度調整: {difficulty}T ({change_pct}%) ⛏️",
+                AlertKind::Hashrate => "🎉  ハッシュレート史上最高値: {hashrate} EH/s 🎉",
+                AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => "⛓️ ブロック{height}に到達 ⛓️",
+                AlertKind::Supply => "🎊 供給量が{milestone} BTCに到達しました 🎊",
+                AlertKind::Reorg => "⚠️ 深さ{depth}のリオルグを検出！旧チップ: {old_height} ({old_tip}) - 新チップ: {new_height} ({new_tip}) ⚠️",
+                AlertKind::LowFee => "💸 手数料低下: 次のブロックの推定手数料は{fee_rate} sat/vB 💸",
+                AlertKind::HighFee => "🔥 手数料高騰: ブロック{height}の手数料率の中央値は{fee_rate} sat/vB 🔥",
+                AlertKind::MempoolPurging => "🧹 メモリプールが満杯: {min_fee} sat/vB未満のトランザクションを削除中 🧹",
+                AlertKind::WatchReceived => "📥 {label}: {txid}で{amount} BTCを受信 ({confirmations_text}) 📥",
+                AlertKind::WatchSpent => "📤 {label}: {txid}で{amount} BTCを送金 ({confirmations_text}) 📤",
+                AlertKind::TxConfirmed => "✅ トランザクション{txid}: {confirmations_text} ✅",
+                AlertKind::TxReplaced => "🔁 トランザクション{txid}は{replacement}に置き換えられました 🔁",
+                AlertKind::TxDropped => "❌ トランザクション{txid}がメモリプールから削除されました ❌",
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Amount;
+
+    use super::*;
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(Locale::En.format_decimal(86_388.123, 2), "86,388.12");
+        assert_eq!(Locale::It.format_decimal(86_388.123, 2), "86.388,12");
+        assert_eq!(Locale::Es.format_decimal(-3.921, 2), "-3,92");
+        assert_eq!(Locale::En.format_decimal(-0.001, 2), "0.00");
+        assert_eq!(Locale::Ja.format_decimal(3.125, 0), "3");
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Locale::Es.format(&Var::Integer(840_000)), "840.000");
+        assert_eq!(
+            Locale::En.format(&Var::Btc(Amount::from_sat(150_000_000))),
+            "1.5"
+        );
+        assert_eq!(
+            Locale::It.format(&Var::Btc(Amount::from_sat(100_000_000))),
+            "1"
+        );
+        assert_eq!(Locale::En.format(&Var::Btc(Amount::ZERO)), "0");
+        assert_eq!(
+            Locale::Es.format(&Var::Btc(Amount::from_sat(1_000_000_000))),
+            "10"
+        );
+        assert_eq!(
+            Locale::En.format(&Var::Btc(Amount::from_sat(10_000))),
+            "0.0001"
+        );
+        assert_eq!(Locale::En.format(&Var::Confirmations(1)), "1 confirmation");
+        assert_eq!(
+            Locale::Es.format(&Var::Confirmations(6)),
+            "6 confirmaciones"
+        );
+        assert_eq!(Locale::En.format(&Var::Minutes(30)), "~30 minutes");
+        assert_eq!(Locale::It.block(840_000), "Blocco 840.000");
+        assert_eq!(Locale::It.format(&Var::Minutes(180)), "~3 ore");
+        assert_eq!(Locale::Ja.format(&Var::Minutes(10_080)), "約7日");
+    }
+
+    #[test]
+    fn test_ordinal() {
+        assert_eq!(Locale::En.ordinal(1), "1st");
+        assert_eq!(Locale::En.ordinal(5), "5th");
+        assert_eq!(Locale::En.ordinal(12), "12th");
+        assert_eq!(Locale::En.ordinal(22), "22nd");
+        assert_eq!(Locale::Es.ordinal(5), "5.ª");
+        assert_eq!(Locale::It.ordinal(5), "5ª");
+        assert_eq!(Locale::Ja.ordinal(5), "第5");
+    }
+}
@@ -4,40 +4,123 @@
 #[macro_use]
 extern crate serde;
 
+use clap::Parser;
 use nostr_sdk::Result;
+use tokio::sync::watch;
 
 mod bitcoin;
+mod command;
 mod config;
 mod db;
 mod dispatcher;
+mod locale;
 mod logger;
 mod primitives;
+mod template;
 mod util;
 
 use self::bitcoin::RpcClient;
-use self::config::Config;
-use self::db::{BitcoinStore, NotificationStore};
+use self::command::QueuedCommand;
+use self::config::{Args, Command, Config, DeadLetterCommand};
+use self::db::{BitcoinStore, NotificationStore, SubscriptionStore};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Get config
-    let config = Config::from_args();
+    let args = Args::parse();
+    let config = Config::from_args(&args);
 
     // Init logger
     logger::init(&config);
 
+    if let Some(command) = args.command {
+        return run_command(&config, command);
+    }
+
     let rpc = RpcClient::new(&config);
-    let bitcoin_store = BitcoinStore::open(&config.bitcoin.db_path).unwrap();
-    let notification_store =
-        NotificationStore::open(&config.main_path.join("notification")).unwrap();
+    let bitcoin_store = BitcoinStore::open(&config.bitcoin.db_path)?;
+    let notification_store = NotificationStore::open(&config.main_path.join("notification"))?;
+    let subscription_store = SubscriptionStore::open(&config.main_path.join("subscription"))?;
+
+    let (chain_state, chain_state_rx) = watch::channel(None);
 
     tokio::select! {
-        _ = bitcoin::run(config.clone(), rpc, bitcoin_store, notification_store.clone()) => {
+        _ = bitcoin::run(config.clone(), rpc, bitcoin_store, notification_store.clone(), subscription_store.clone(), chain_state) => {
             println!("Bitcoin processor exited");
         }
-        _ = dispatcher::run(config, &notification_store) => {
+        _ = command::run(&config, &notification_store) => {
+            println!("Command queue exited");
+        }
+        result = dispatcher::run(config.clone(), &notification_store, &subscription_store, chain_state_rx) => {
             println!("Dispatcher exited");
+            result?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a CLI command
+///
+/// If the daemon is running, the database is locked: it's read without locking it, and the
+/// changes are queued for the daemon instead (see [`command`]).
+fn run_command(config: &Config, command: Command) -> Result<()> {
+    let path = config.main_path.join("notification");
+
+    let commands: Vec<QueuedCommand> = match command {
+        Command::Track { txid } => vec![QueuedCommand::Track { txid }],
+        Command::Untrack { txid } => vec![QueuedCommand::Untrack { txid }],
+        Command::DeadLetter {
+            command: DeadLetterCommand::List,
+        } => {
+            let notification_store = NotificationStore::open_read_only(&path)?;
+            for (id, notification) in notification_store.get_dead_letters()?.into_iter() {
+                println!(
+                    "{id} [{}] attempts: {}, last error: {}\n  {}",
+                    notification.instance(),
+                    notification.attempts,
+                    notification.last_error.unwrap_or_default(),
+                    notification.plain_text
+                );
+            }
+            return Ok(());
+        }
+        Command::DeadLetter {
+            command: DeadLetterCommand::Requeue { ids, all },
+        } => {
+            let ids: Vec<String> = if all {
+                NotificationStore::open_read_only(&path)?
+                    .get_dead_letters()?
+                    .into_keys()
+                    .collect()
+            } else {
+                ids
+            };
+            ids.into_iter()
+                .map(|id| QueuedCommand::Requeue { id })
+                .collect()
+        }
+    };
+
+    match NotificationStore::open(&path) {
+        Ok(notification_store) => {
+            for command in commands.iter() {
+                command.apply(&notification_store)?;
+                println!("{command}");
+            }
+        }
+        Err(e) if e.is_locked() => {
+            let path = command::path(config);
+            for command in commands.iter() {
+                command::queue(&path, command)?;
+            }
+            println!(
+                "The database is in use by the running instance: {} command(s) queued, applied within {} secs",
+                commands.len(),
+                command::POLL_INTERVAL.as_secs()
+            );
         }
+        Err(e) => return Err(e.into()),
     }
 
     Ok(())
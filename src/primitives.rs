@@ -1,21 +1,237 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::collections::BTreeMap;
 use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::Amount;
+use serde_json::Value;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Target {
+    Discord,
+    Email,
     Matrix,
+    Mqtt,
     Nostr,
     Ntfy,
+    Slack,
+    Telegram,
+    Webhook,
+}
+
+impl Target {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Discord => "discord",
+            Self::Email => "email",
+            Self::Matrix => "matrix",
+            Self::Mqtt => "mqtt",
+            Self::Nostr => "nostr",
+            Self::Ntfy => "ntfy",
+            Self::Slack => "slack",
+            Self::Telegram => "telegram",
+            Self::Webhook => "webhook",
+        }
+    }
 }
 
 impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "discord" => Ok(Self::Discord),
+            "email" => Ok(Self::Email),
+            "matrix" => Ok(Self::Matrix),
+            "mqtt" => Ok(Self::Mqtt),
+            "nostr" => Ok(Self::Nostr),
+            "ntfy" => Ok(Self::Ntfy),
+            "slack" => Ok(Self::Slack),
+            "telegram" => Ok(Self::Telegram),
+            "webhook" => Ok(Self::Webhook),
+            _ => Err(format!("unknown target: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Delivery priority, for the targets supporting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Min,
+    Low,
+    Default,
+    High,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Halving,
+    BlockReward,
+    HalvingCountdown,
+    Difficulty,
+    Hashrate,
+    Block,
+    RoundBlock,
+    PalindromeBlock,
+    Supply,
+    Reorg,
+    LowFee,
+    HighFee,
+    MempoolPurging,
+    WatchReceived,
+    WatchSpent,
+    TxConfirmed,
+    TxReplaced,
+    TxDropped,
+}
+
+impl fmt::Display for AlertKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Self::Matrix => write!(f, "matrix"),
-            Self::Nostr => write!(f, "nostr"),
-            Self::Ntfy => write!(f, "ntfy"),
+            Self::Halving => write!(f, "halving"),
+            Self::BlockReward => write!(f, "block_reward"),
+            Self::HalvingCountdown => write!(f, "halving_countdown"),
+            Self::Difficulty => write!(f, "difficulty"),
+            Self::Hashrate => write!(f, "hashrate"),
+            Self::Block => write!(f, "block"),
+            Self::RoundBlock => write!(f, "round_block"),
+            Self::PalindromeBlock => write!(f, "palindrome_block"),
+            Self::Supply => write!(f, "supply"),
+            Self::Reorg => write!(f, "reorg"),
+            Self::LowFee => write!(f, "low_fee"),
+            Self::HighFee => write!(f, "high_fee"),
+            Self::MempoolPurging => write!(f, "mempool_purging"),
+            Self::WatchReceived => write!(f, "watch_received"),
+            Self::WatchSpent => write!(f, "watch_spent"),
+            Self::TxConfirmed => write!(f, "tx_confirmed"),
+            Self::TxReplaced => write!(f, "tx_replaced"),
+            Self::TxDropped => write!(f, "tx_dropped"),
+        }
+    }
+}
+
+impl AlertKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::Reorg | Self::TxReplaced | Self::TxDropped => Severity::Critical,
+            Self::Halving | Self::HighFee | Self::MempoolPurging | Self::WatchSpent => {
+                Severity::Warning
+            }
+            _ => Severity::Info,
         }
     }
 }
+
+/// Localized phrase, used as template variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phrase {
+    ConflictingTx,
+}
+
+/// Template variable, formatted for the locale of the target
+#[derive(Debug, Clone, PartialEq)]
+pub enum Var {
+    Text(String),
+    Integer(u64),
+    /// Value and decimal places
+    Decimal(f64, usize),
+    Btc(Amount),
+    Ordinal(u64),
+    Confirmations(u64),
+    Minutes(u64),
+    Phrase(Phrase),
+}
+
+impl From<String> for Var {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Var {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<u64> for Var {
+    fn from(num: u64) -> Self {
+        Self::Integer(num)
+    }
+}
+
+/// Alert data, before being formatted for a target
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub block_height: u64,
+    /// Values the alert is about (i.e. difficulty, fee rate, txid)
+    pub values: BTreeMap<String, Value>,
+    /// Template variables
+    pub vars: BTreeMap<&'static str, Var>,
+    /// Set by the matching route, if any
+    pub priority: Option<Priority>,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, block_height: u64) -> Self {
+        Self {
+            kind,
+            block_height,
+            values: BTreeMap::new(),
+            vars: BTreeMap::new(),
+            priority: None,
+        }
+        .var("height", block_height)
+    }
+
+    pub fn value<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        self.values.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn var<V>(mut self, name: &'static str, value: V) -> Self
+    where
+        V: Into<Var>,
+    {
+        self.vars.insert(name, value.into());
+        self
+    }
+}
+
+/// Chain state after the last processed block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainState {
+    pub block_height: u64,
+    pub difficulty: f64,
+    /// H/s
+    pub hashrate: f64,
+    pub blocks_to_halving: u64,
+    /// Next block fee estimate (sat/vB), updated between blocks
+    pub next_block_fee_rate: Option<f64>,
+    /// Minimum fee rate to enter the mempool (sat/vB), updated between blocks
+    pub mempool_min_fee: Option<f64>,
+}
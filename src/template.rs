@@ -0,0 +1,321 @@
+// Copyright (c) 2021-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Alert message templates
+//!
+//! Templates reference the variables of the alert as `{name}`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::locale::Locale;
+use crate::primitives::{Alert, AlertKind, Target};
+use crate::util;
+
+/// Variables available to the templates of `kind`, besides `height`
+pub fn variables(kind: AlertKind) -> &'static [&'static str] {
+    match kind {
+        AlertKind::Halving => &["halving", "epoch", "epoch_ordinal"],
+        AlertKind::BlockReward => &["block_reward"],
+        AlertKind::HalvingCountdown => &["blocks_left", "eta"],
+        AlertKind::Difficulty => &["difficulty", "change_pct"],
+        AlertKind::Hashrate => &["hashrate"],
+        AlertKind::Block | AlertKind::RoundBlock | AlertKind::PalindromeBlock => &[],
+        AlertKind::Supply => &["milestone"],
+        AlertKind::Reorg => &["depth", "old_height", "old_tip", "new_height", "new_tip"],
+        AlertKind::LowFee | AlertKind::HighFee => &["fee_rate", "threshold"],
+        AlertKind::MempoolPurging => &["min_fee"],
+        AlertKind::WatchReceived | AlertKind::WatchSpent => &[
+            "label",
+            "amount",
+            "txid",
+            "confirmations",
+            "confirmations_text",
+        ],
+        AlertKind::TxConfirmed => &["txid", "confirmations", "confirmations_text"],
+        AlertKind::TxReplaced => &["txid", "replacement"],
+        AlertKind::TxDropped => &["txid"],
+    }
+}
+
+/// Variables referenced by `template`
+fn referenced_variables(template: &str) -> BTreeSet<&str> {
+    let mut names: BTreeSet<&str> = BTreeSet::new();
+    let mut rest: &str = template;
+
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        if let Some(end) = rest.find('}') {
+            let name: &str = &rest[..end];
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                names.insert(name);
+                rest = &rest[end + 1..];
+            }
+        }
+    }
+
+    names
+}
+
+/// Check that `template` only references variables of `kind`
+fn validate(kind: AlertKind, template: &str) -> Result<(), String> {
+    let known: &[&str] = variables(kind);
+    let unknown: Vec<&str> = referenced_variables(template)
+        .into_iter()
+        .filter(|name| *name != "height" && !known.contains(name))
+        .collect();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{kind} template references unknown variables: {}",
+            unknown.join(", ")
+        ))
+    }
+}
+
+/// Replace the variables of `template`, escaping their values for HTML if `html` is set.
+///
+/// Single pass: placeholders in the values (i.e. a `{txid}` in a label) are left as they are.
+fn render(template: &str, locale: Locale, alert: &Alert, html: bool) -> String {
+    let mut text: String = String::with_capacity(template.len());
+    let mut rest: &str = template;
+
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let var = rest
+            .find('}')
+            .and_then(|end| Some((end, alert.vars.get(&rest[1..end])?)));
+
+        match var {
+            Some((end, var)) => {
+                let value: String = locale.format(var);
+                if html {
+                    text.push_str(&util::escape_html(&value));
+                } else {
+                    text.push_str(&value);
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    text.push_str(rest);
+    text
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Variant {
+    plain: Option<String>,
+    html: Option<String>,
+}
+
+/// Entry of an alert kind table: a template, or the variant of a target
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Template(String),
+    Target(Variant),
+}
+
+#[derive(Debug, Clone, Default)]
+struct KindTemplates {
+    default: Variant,
+    targets: BTreeMap<String, Variant>,
+}
+
+/// Templates overriding the default ones, by alert kind.
+///
+/// Overrides apply to every locale.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    kinds: BTreeMap<AlertKind, KindTemplates>,
+}
+
+impl Templates {
+    /// Parse and validate a templates file
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content: String = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let file: BTreeMap<AlertKind, BTreeMap<String, Entry>> =
+            toml::from_str(content).map_err(|e| e.to_string())?;
+        let mut kinds: BTreeMap<AlertKind, KindTemplates> = BTreeMap::new();
+
+        for (kind, entries) in file.into_iter() {
+            let mut templates = KindTemplates::default();
+
+            for (key, entry) in entries.into_iter() {
+                let variant: &Variant = match (key.as_str(), entry) {
+                    ("plain", Entry::Template(template)) => {
+                        templates.default.plain = Some(template);
+                        &templates.default
+                    }
+                    ("html", Entry::Template(template)) => {
+                        templates.default.html = Some(template);
+                        &templates.default
+                    }
+                    (target, Entry::Target(variant)) => {
+                        Target::from_str(target)?;
+                        templates.targets.entry(key.clone()).or_insert(variant)
+                    }
+                    (key, Entry::Template(_)) => {
+                        return Err(format!("unknown {kind} template: {key}"))
+                    }
+                };
+
+                for template in variant.plain.iter().chain(variant.html.iter()) {
+                    validate(kind, template)?;
+                }
+            }
+
+            kinds.insert(kind, templates);
+        }
+
+        Ok(Self { kinds })
+    }
+
+    /// The variant of `target` first, then the one of the alert kind
+    fn find<F>(&self, target: Target, kind: AlertKind, get: F) -> Option<&str>
+    where
+        F: Fn(&Variant) -> Option<&String>,
+    {
+        let templates: &KindTemplates = self.kinds.get(&kind)?;
+        templates
+            .targets
+            .get(target.as_str())
+            .and_then(&get)
+            .or_else(|| get(&templates.default))
+            .map(|template| template.as_str())
+    }
+
+    pub fn plain_text(&self, target: Target, locale: Locale, alert: &Alert) -> String {
+        let template: &str = self
+            .find(target, alert.kind, |variant| variant.plain.as_ref())
+            .unwrap_or_else(|| locale.default_template(alert.kind));
+        render(template, locale, alert, false)
+    }
+
+    /// HTML template, or the escaped plain text if there isn't one
+    pub fn html(&self, target: Target, locale: Locale, alert: &Alert) -> String {
+        match self.find(target, alert.kind, |variant| variant.html.as_ref()) {
+            Some(template) => render(template, locale, alert, true),
+            None => util::escape_html(&self.plain_text(target, locale, alert)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Var;
+
+    const ALL_KINDS: &[AlertKind] = &[
+        AlertKind::Halving,
+        AlertKind::BlockReward,
+        AlertKind::HalvingCountdown,
+        AlertKind::Difficulty,
+        AlertKind::Hashrate,
+        AlertKind::Block,
+        AlertKind::RoundBlock,
+        AlertKind::PalindromeBlock,
+        AlertKind::Supply,
+        AlertKind::Reorg,
+        AlertKind::LowFee,
+        AlertKind::HighFee,
+        AlertKind::MempoolPurging,
+        AlertKind::WatchReceived,
+        AlertKind::WatchSpent,
+        AlertKind::TxConfirmed,
+        AlertKind::TxReplaced,
+        AlertKind::TxDropped,
+    ];
+
+    #[test]
+    fn test_default_templates() {
+        for locale in [Locale::En, Locale::Es, Locale::It, Locale::Ja] {
+            for kind in ALL_KINDS.iter().copied() {
+                assert!(
+                    validate(kind, locale.default_template(kind)).is_ok(),
+                    "{locale} {kind}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let templates = Templates::parse(
+            r#"
+            [difficulty]
+            plain = "Difficulty {difficulty}T at {height}"
+
+            [difficulty.telegram]
+            html = "<b>{difficulty}T</b> ({change_pct}%)"
+            "#,
+        )
+        .unwrap();
+        let alert = Alert::new(AlertKind::Difficulty, 840_672)
+            .var("difficulty", Var::Decimal(86.388, 2))
+            .var("change_pct", "<3");
+
+        assert_eq!(
+            templates.plain_text(Target::Ntfy, Locale::En, &alert),
+            "Difficulty 86.39T at 840,672"
+        );
+        assert_eq!(
+            templates.plain_text(Target::Ntfy, Locale::It, &alert),
+            "Difficulty 86,39T at 840.672"
+        );
+        assert_eq!(
+            templates.html(Target::Telegram, Locale::En, &alert),
+            "<b>86.39T</b> (&lt;3%)"
+        );
+        assert_eq!(
+            templates.html(Target::Matrix, Locale::En, &alert),
+            "Difficulty 86.39T at 840,672"
+        );
+
+        let block = Alert::new(AlertKind::Block, 840_000);
+        assert_eq!(
+            templates.plain_text(Target::Ntfy, Locale::En, &block),
+            "⛓️ Reached block 840,000 ⛓️"
+        );
+        assert_eq!(
+            templates.plain_text(Target::Ntfy, Locale::Ja, &block),
+            "⛓️ ブロック840,000に到達 ⛓️"
+        );
+
+        let halving = Alert::new(AlertKind::Halving, 840_000).var("epoch_ordinal", Var::Ordinal(5));
+        assert_eq!(
+            templates.plain_text(Target::Nostr, Locale::Es, &halving),
+            "⛏️ ¡Llegó el Halving! ¡Bienvenidos a la 5.ª época! ⛏️"
+        );
+
+        // Values are not expanded
+        let tx = Alert::new(AlertKind::TxDropped, 840_000).var("txid", "{height} {txid}");
+        assert_eq!(
+            render("{txid} at {height} {unknown} {", Locale::En, &tx, false),
+            "{height} {txid} at 840,000 {unknown} {"
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(Templates::parse("[halving]\nplain = \"{epoch} {eta}\"").is_err());
+        assert!(Templates::parse("[halving.fax]\nplain = \"{epoch}\"").is_err());
+        assert!(Templates::parse("[halving]\nplain = \"{epoch} {}\"").is_ok());
+    }
+}
@@ -1,10 +1,18 @@
 // Copyright (c) 2021-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitcoin::hashes::sha512::Hash as Sha512Hash;
 use bitcoin::hashes::Hash;
+use nostr_sdk::nips::nip19::ToBech32;
+use nostr_sdk::SecretKey;
 
 pub fn bytes_to_number<T>(bytes: Vec<u8>) -> Option<T>
 where
@@ -26,7 +34,16 @@ where
     Sha512Hash::hash(value.as_ref()).to_string()
 }
 
-pub fn format_number(num: usize) -> String {
+/// Seconds since UNIX epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Group the digits of `num` by thousands
+pub fn format_number(num: usize, separator: char) -> String {
     let mut number: String = num.to_string();
 
     if number.len() > 3 {
@@ -34,7 +51,7 @@ pub fn format_number(num: usize) -> String {
         number.clear();
         for (index, char) in reversed.chars().enumerate() {
             if index != 0 && index % 3 == 0 {
-                number.push(',');
+                number.push(separator);
             }
             number.push(char);
         }
@@ -44,6 +61,22 @@ pub fn format_number(num: usize) -> String {
     number
 }
 
+/// Escape text to be embedded in an HTML body
+pub fn escape_html(text: &str) -> String {
+    let mut escaped: String = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// Check if a number is a palindrome using pure math
 pub fn is_palindrome(mut n: u64) -> bool {
     if n == 0 {
@@ -68,18 +101,82 @@ pub fn is_round_number(n: u64, magnitude: u32) -> bool {
     n % (10u64.pow(magnitude)) == 0
 }
 
+/// Read a secret key (bech32 or hex) from a file only accessible by its owner
+pub fn read_secret_key(path: &Path) -> Result<SecretKey, String> {
+    #[cfg(unix)]
+    {
+        let mode: u32 = fs::metadata(path)
+            .map_err(|e| e.to_string())?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            return Err(format!(
+                "permissions {:o} are too open, expected 600",
+                mode & 0o777
+            ));
+        }
+    }
+
+    let content: String = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    SecretKey::parse(content.trim()).map_err(|e| e.to_string())
+}
+
+/// Write a secret key to a new file, only accessible by its owner
+pub fn write_secret_key(path: &Path, secret_key: &SecretKey) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let bech32: String = secret_key
+        .to_bech32()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut file = options.open(path)?;
+    writeln!(file, "{bech32}")
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_secret_key_file() {
+        let path = std::env::temp_dir().join(format!("bitcoin-alerts-{}.key", std::process::id()));
+        let secret_key = SecretKey::generate();
+
+        write_secret_key(&path, &secret_key).unwrap();
+        assert_eq!(read_secret_key(&path), Ok(secret_key.clone()));
+        assert!(write_secret_key(&path, &secret_key).is_err());
+
+        #[cfg(unix)]
+        {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(read_secret_key(&path).is_err());
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_format_number() {
-        assert_eq!(format_number(100), "100".to_string());
-        assert_eq!(format_number(1000), "1,000".to_string());
-        assert_eq!(format_number(10000), "10,000".to_string());
-        assert_eq!(format_number(100000), "100,000".to_string());
-        assert_eq!(format_number(1000000), "1,000,000".to_string());
-        assert_eq!(format_number(1000000000), "1,000,000,000".to_string());
+        assert_eq!(format_number(100, ','), "100".to_string());
+        assert_eq!(format_number(1000, ','), "1,000".to_string());
+        assert_eq!(format_number(10000, ','), "10,000".to_string());
+        assert_eq!(format_number(100000, ','), "100,000".to_string());
+        assert_eq!(format_number(1000000, ','), "1,000,000".to_string());
+        assert_eq!(format_number(1000000000, '.'), "1.000.000.000".to_string());
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("⛓️ Reached block 800,000 ⛓️"),
+            "⛓️ Reached block 800,000 ⛓️"
+        );
+        assert_eq!(
+            escape_html("<b>\"fees\" & 'more'</b>"),
+            "&lt;b&gt;&quot;fees&quot; &amp; &#39;more&#39;&lt;/b&gt;"
+        );
     }
 
     #[test]